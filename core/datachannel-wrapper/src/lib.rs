@@ -1,7 +1,117 @@
 pub use datachannel::*;
 
+#[cfg(feature = "mock")]
+pub mod mock;
+
+#[derive(Debug, Clone)]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    pub username: Option<String>,
+    pub credential: Option<String>,
+}
+
+impl IceServer {
+    /// Converts this into the URL strings that RtcConfig expects, where credentials are embedded as `turn:username:credential@host:port`.
+    pub fn to_config_urls(&self) -> Vec<String> {
+        self.urls
+            .iter()
+            .map(|url| {
+                let (scheme, rest) = match url.split_once(':') {
+                    Some(v) => v,
+                    None => {
+                        return url.clone();
+                    }
+                };
+                match (self.username.as_ref(), self.credential.as_ref()) {
+                    (Some(username), Some(credential)) => format!(
+                        "{}:{}:{}@{}",
+                        scheme,
+                        percent_encode(username),
+                        percent_encode(credential),
+                        rest
+                    ),
+                    (Some(username), None) => {
+                        format!("{}:{}@{}", scheme, percent_encode(username), rest)
+                    }
+                    _ => url.clone(),
+                }
+            })
+            .collect()
+    }
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CandidateType {
+    Host,
+    ServerReflexive,
+    PeerReflexive,
+    Relayed,
+}
+
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub candidate_type: CandidateType,
+    pub address: std::net::SocketAddr,
+}
+
+impl Candidate {
+    /// Parses the interesting parts out of an SDP candidate attribute, e.g. `a=candidate:1 1 UDP 2122317823 192.0.2.1 54321 typ host`.
+    pub fn parse(candidate: &str) -> Option<Self> {
+        let candidate = candidate.trim();
+        let candidate = candidate.strip_prefix("a=").unwrap_or(candidate);
+        let mut fields = candidate.split_ascii_whitespace();
+        let _foundation = fields.next()?;
+        let _component = fields.next()?;
+        let _transport = fields.next()?;
+        let _priority = fields.next()?;
+        let ip: std::net::IpAddr = fields.next()?.parse().ok()?;
+        let port: u16 = fields.next()?.parse().ok()?;
+        if fields.next()? != "typ" {
+            return None;
+        }
+        let candidate_type = match fields.next()? {
+            "host" => CandidateType::Host,
+            "srflx" => CandidateType::ServerReflexive,
+            "prflx" => CandidateType::PeerReflexive,
+            "relay" => CandidateType::Relayed,
+            _ => {
+                return None;
+            }
+        };
+        Some(Candidate {
+            candidate_type,
+            address: std::net::SocketAddr::new(ip, port),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PeerConnectionStats {
+    // Both of these are None until ICE has settled on a pair.
+    pub local_candidate: Option<Candidate>,
+    pub remote_candidate: Option<Candidate>,
+}
+
+enum Conn {
+    Rtc(Box<datachannel::RtcPeerConnection<PeerConnectionHandler>>),
+    #[cfg(feature = "mock")]
+    Mock(mock::Peer),
+}
+
 pub struct PeerConnection {
-    peer_conn: Box<datachannel::RtcPeerConnection<PeerConnectionHandler>>,
+    peer_conn: Conn,
     data_channel_rx: tokio::sync::mpsc::Receiver<DataChannel>,
 }
 
@@ -19,7 +129,7 @@ impl PeerConnection {
         let peer_conn = datachannel::RtcPeerConnection::new(&config, pch)?;
         Ok((
             PeerConnection {
-                peer_conn,
+                peer_conn: Conn::Rtc(peer_conn),
                 data_channel_rx,
             },
             event_rx,
@@ -31,25 +141,30 @@ impl PeerConnection {
         label: &str,
         dc_init: DataChannelInit,
     ) -> anyhow::Result<DataChannel> {
-        let (message_tx, message_rx) = tokio::sync::mpsc::channel(1);
-        let (open_tx, open_rx) = tokio::sync::oneshot::channel();
-        let state = std::sync::Arc::new(tokio::sync::Mutex::new(DataChannelState {
-            open_rx: Some(open_rx),
-            error: None,
-        }));
-        let dch = DataChannelHandler {
-            message_tx: Some(message_tx),
-            open_tx: Some(open_tx),
-            state: state.clone(),
-        };
-        let dc = self
-            .peer_conn
-            .create_data_channel_ex(label, dch, &dc_init)?;
-        Ok(DataChannel {
-            dc,
-            message_rx,
-            state,
-        })
+        match &mut self.peer_conn {
+            Conn::Rtc(peer_conn) => {
+                let (message_tx, message_rx) = tokio::sync::mpsc::channel(1);
+                let (open_tx, open_rx) = tokio::sync::oneshot::channel();
+                let state = std::sync::Arc::new(tokio::sync::Mutex::new(DataChannelState {
+                    open_rx: Some(open_rx),
+                    error: None,
+                }));
+                let dch = DataChannelHandler {
+                    message_tx: Some(message_tx),
+                    open_tx: Some(open_tx),
+                    state: state.clone(),
+                };
+                let dc = peer_conn.create_data_channel_ex(label, dch, &dc_init)?;
+                Ok(DataChannel {
+                    dc: Sink::Rtc(dc),
+                    message_rx,
+                    state,
+                    counters: std::sync::Arc::new(Counters::default()),
+                })
+            }
+            #[cfg(feature = "mock")]
+            Conn::Mock(peer) => Ok(peer.create_data_channel()),
+        }
     }
 
     pub async fn accept(&mut self) -> Option<DataChannel> {
@@ -57,27 +172,71 @@ impl PeerConnection {
     }
 
     pub fn set_local_description(&mut self, sdp_type: SdpType) -> anyhow::Result<()> {
-        self.peer_conn.set_local_description(sdp_type)?;
+        match &mut self.peer_conn {
+            Conn::Rtc(peer_conn) => {
+                peer_conn.set_local_description(sdp_type)?;
+            }
+            #[cfg(feature = "mock")]
+            Conn::Mock(peer) => {
+                peer.set_local_description(sdp_type);
+            }
+        }
         Ok(())
     }
 
     pub fn set_remote_description(&mut self, sess_desc: SessionDescription) -> anyhow::Result<()> {
-        self.peer_conn.set_remote_description(&sess_desc)?;
+        match &mut self.peer_conn {
+            Conn::Rtc(peer_conn) => {
+                peer_conn.set_remote_description(&sess_desc)?;
+            }
+            #[cfg(feature = "mock")]
+            Conn::Mock(peer) => {
+                peer.set_remote_description(sess_desc)?;
+            }
+        }
         Ok(())
     }
 
     pub fn local_description(&self) -> Option<SessionDescription> {
-        self.peer_conn.local_description()
+        match &self.peer_conn {
+            Conn::Rtc(peer_conn) => peer_conn.local_description(),
+            #[cfg(feature = "mock")]
+            Conn::Mock(peer) => peer.local_description(),
+        }
     }
 
     pub fn remote_description(&self) -> Option<SessionDescription> {
-        self.peer_conn.remote_description()
+        match &self.peer_conn {
+            Conn::Rtc(peer_conn) => peer_conn.remote_description(),
+            #[cfg(feature = "mock")]
+            Conn::Mock(peer) => peer.remote_description(),
+        }
     }
 
     pub fn add_remote_candidate(&mut self, cand: IceCandidate) -> anyhow::Result<()> {
-        self.peer_conn.add_remote_candidate(&cand)?;
+        match &mut self.peer_conn {
+            Conn::Rtc(peer_conn) => {
+                peer_conn.add_remote_candidate(&cand)?;
+            }
+            #[cfg(feature = "mock")]
+            Conn::Mock(_) => {}
+        }
         Ok(())
     }
+
+    pub fn stats(&self) -> PeerConnectionStats {
+        match &self.peer_conn {
+            Conn::Rtc(peer_conn) => match peer_conn.selected_candidate_pair() {
+                Some(pair) => PeerConnectionStats {
+                    local_candidate: Candidate::parse(&pair.local),
+                    remote_candidate: Candidate::parse(&pair.remote),
+                },
+                None => PeerConnectionStats::default(),
+            },
+            #[cfg(feature = "mock")]
+            Conn::Mock(_) => PeerConnectionStats::default(),
+        }
+    }
 }
 
 struct PeerConnectionHandler {
@@ -159,9 +318,10 @@ impl datachannel::PeerConnectionHandler for PeerConnectionHandler {
     fn on_data_channel(&mut self, dc: Box<datachannel::RtcDataChannel<Self::DCH>>) {
         let (message_rx, state) = self.pending_dc_receiver.take().unwrap();
         let _ = self.data_channel_tx.blocking_send(DataChannel {
-            dc,
+            dc: Sink::Rtc(dc),
             message_rx,
             state,
+            counters: std::sync::Arc::new(Counters::default()),
         });
     }
 }
@@ -170,15 +330,38 @@ struct DataChannelState {
     error: Option<Error>,
 }
 
+#[derive(Default)]
+struct Counters {
+    bytes_sent: std::sync::atomic::AtomicU64,
+    bytes_received: std::sync::atomic::AtomicU64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DataChannelStats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    // How many bytes have been sent but not yet handed off to the network.
+    pub buffered_amount: usize,
+    // Whether this data channel isn't backed by a peer connection, e.g. because it goes through a relay.
+    pub relayed: bool,
+}
+
+enum Sink {
+    Rtc(Box<datachannel::RtcDataChannel<DataChannelHandler>>),
+    Channel(tokio::sync::mpsc::Sender<Vec<u8>>),
+}
+
 pub struct DataChannel {
     state: std::sync::Arc<tokio::sync::Mutex<DataChannelState>>,
     message_rx: tokio::sync::mpsc::Receiver<Vec<u8>>,
-    dc: Box<datachannel::RtcDataChannel<DataChannelHandler>>,
+    dc: Sink,
+    counters: std::sync::Arc<Counters>,
 }
 
 async fn dc_send(
     state: &std::sync::Arc<tokio::sync::Mutex<DataChannelState>>,
-    dc: &mut Box<datachannel::RtcDataChannel<DataChannelHandler>>,
+    dc: &mut Sink,
+    counters: &Counters,
     msg: &[u8],
 ) -> Result<(), Error> {
     let mut state = state.lock().await;
@@ -190,22 +373,75 @@ async fn dc_send(
         open_rx.await.map_err(|_| Error::Closed)?;
     }
 
-    dc.send(msg)
-        .map_err(|e| Error::UnderlyingError(format!("{:?}", e)))?;
+    match dc {
+        Sink::Rtc(dc) => {
+            dc.send(msg)
+                .map_err(|e| Error::UnderlyingError(format!("{:?}", e)))?;
+        }
+        Sink::Channel(tx) => {
+            tx.send(msg.to_vec()).await.map_err(|_| Error::Closed)?;
+        }
+    }
+    counters
+        .bytes_sent
+        .fetch_add(msg.len() as u64, std::sync::atomic::Ordering::Relaxed);
     Ok(())
 }
 
-async fn dc_receive(message_rx: &mut tokio::sync::mpsc::Receiver<Vec<u8>>) -> Option<Vec<u8>> {
-    message_rx.recv().await
+async fn dc_receive(
+    message_rx: &mut tokio::sync::mpsc::Receiver<Vec<u8>>,
+    counters: &Counters,
+) -> Option<Vec<u8>> {
+    let msg = message_rx.recv().await?;
+    counters
+        .bytes_received
+        .fetch_add(msg.len() as u64, std::sync::atomic::Ordering::Relaxed);
+    Some(msg)
+}
+
+fn dc_stats(dc: &Sink, counters: &Counters) -> DataChannelStats {
+    DataChannelStats {
+        bytes_sent: counters
+            .bytes_sent
+            .load(std::sync::atomic::Ordering::Relaxed),
+        bytes_received: counters
+            .bytes_received
+            .load(std::sync::atomic::Ordering::Relaxed),
+        buffered_amount: match dc {
+            Sink::Rtc(dc) => dc.buffered_amount(),
+            Sink::Channel(_) => 0,
+        },
+        relayed: matches!(dc, Sink::Channel(_)),
+    }
 }
 
 impl DataChannel {
+    /// Creates a data channel that is not backed by a peer connection at all, but by whatever is on the other side of the given channels, e.g. a relay.
+    pub fn from_channels(
+        tx: tokio::sync::mpsc::Sender<Vec<u8>>,
+        rx: tokio::sync::mpsc::Receiver<Vec<u8>>,
+    ) -> Self {
+        DataChannel {
+            state: std::sync::Arc::new(tokio::sync::Mutex::new(DataChannelState {
+                open_rx: None,
+                error: None,
+            })),
+            message_rx: rx,
+            dc: Sink::Channel(tx),
+            counters: std::sync::Arc::new(Counters::default()),
+        }
+    }
+
     pub async fn send(&mut self, msg: &[u8]) -> Result<(), Error> {
-        dc_send(&self.state, &mut self.dc, msg).await
+        dc_send(&self.state, &mut self.dc, &self.counters, msg).await
     }
 
     pub async fn receive(&mut self) -> Option<Vec<u8>> {
-        dc_receive(&mut self.message_rx).await
+        dc_receive(&mut self.message_rx, &self.counters).await
+    }
+
+    pub fn stats(&self) -> DataChannelStats {
+        dc_stats(&self.dc, &self.counters)
     }
 
     pub fn split(self) -> (DataChannelSender, DataChannelReceiver) {
@@ -213,9 +449,11 @@ impl DataChannel {
             DataChannelSender {
                 state: self.state,
                 dc: self.dc,
+                counters: self.counters.clone(),
             },
             DataChannelReceiver {
                 message_rx: self.message_rx,
+                counters: self.counters,
             },
         )
     }
@@ -223,12 +461,18 @@ impl DataChannel {
 
 pub struct DataChannelSender {
     state: std::sync::Arc<tokio::sync::Mutex<DataChannelState>>,
-    dc: Box<datachannel::RtcDataChannel<DataChannelHandler>>,
+    dc: Sink,
+    counters: std::sync::Arc<Counters>,
 }
 
 impl DataChannelSender {
     pub async fn send(&mut self, msg: &[u8]) -> Result<(), Error> {
-        dc_send(&self.state, &mut self.dc, msg).await
+        dc_send(&self.state, &mut self.dc, &self.counters, msg).await
+    }
+
+    /// Reports the stats for both directions, as the receiving half shares its counters with us.
+    pub fn stats(&self) -> DataChannelStats {
+        dc_stats(&self.dc, &self.counters)
     }
 
     pub fn unsplit(self, rx: DataChannelReceiver) -> DataChannel {
@@ -236,17 +480,19 @@ impl DataChannelSender {
             state: self.state,
             message_rx: rx.message_rx,
             dc: self.dc,
+            counters: self.counters,
         }
     }
 }
 
 pub struct DataChannelReceiver {
     message_rx: tokio::sync::mpsc::Receiver<Vec<u8>>,
+    counters: std::sync::Arc<Counters>,
 }
 
 impl DataChannelReceiver {
     pub async fn receive(&mut self) -> Option<Vec<u8>> {
-        dc_receive(&mut self.message_rx).await
+        dc_receive(&mut self.message_rx, &self.counters).await
     }
 
     pub fn unsplit(self, tx: DataChannelSender) -> DataChannel {
@@ -0,0 +1,316 @@
+//! In-process stand-ins for data channels and peer connections, for exercising code that uses them without libdatachannel.
+
+use super::{
+    Conn, ConnectionState, DataChannel, DataChannelState, Error, GatheringState, PeerConnection,
+    PeerConnectionEvent, SdpType, SessionDescription, Sink,
+};
+
+const CHANNEL_CAPACITY: usize = 64;
+
+/// The far end of a mock data channel, which decides what the channel under test sees.
+pub struct Remote {
+    state: std::sync::Arc<tokio::sync::Mutex<DataChannelState>>,
+    open_tx: Option<tokio::sync::oneshot::Sender<()>>,
+    incoming_tx: Option<tokio::sync::mpsc::Sender<Vec<u8>>>,
+    outgoing_rx: tokio::sync::mpsc::Receiver<Vec<u8>>,
+}
+
+/// Creates a data channel that isn't open yet, along with the remote end that controls it.
+///
+/// As with a real data channel, sends wait until the channel is opened.
+pub fn new(capacity: usize) -> (DataChannel, Remote) {
+    let (incoming_tx, incoming_rx) = tokio::sync::mpsc::channel(capacity);
+    let (outgoing_tx, outgoing_rx) = tokio::sync::mpsc::channel(capacity);
+    let (open_tx, open_rx) = tokio::sync::oneshot::channel();
+    let state = std::sync::Arc::new(tokio::sync::Mutex::new(DataChannelState {
+        open_rx: Some(open_rx),
+        error: None,
+    }));
+    (
+        DataChannel {
+            state: state.clone(),
+            message_rx: incoming_rx,
+            dc: Sink::Channel(outgoing_tx),
+            counters: Default::default(),
+        },
+        Remote {
+            state,
+            open_tx: Some(open_tx),
+            incoming_tx: Some(incoming_tx),
+            outgoing_rx,
+        },
+    )
+}
+
+/// Creates two data channels that are already open and connected to each other.
+pub fn pair(capacity: usize) -> (DataChannel, DataChannel) {
+    let (a_tx, a_rx) = tokio::sync::mpsc::channel(capacity);
+    let (b_tx, b_rx) = tokio::sync::mpsc::channel(capacity);
+    (
+        DataChannel::from_channels(a_tx, b_rx),
+        DataChannel::from_channels(b_tx, a_rx),
+    )
+}
+
+impl Remote {
+    /// Opens the channel, letting any pending sends through.
+    pub fn open(&mut self) {
+        if let Some(open_tx) = self.open_tx.take() {
+            let _ = open_tx.send(());
+        }
+    }
+
+    /// Delivers a message to the channel, as if the peer had sent it.
+    pub async fn deliver(&self, msg: &[u8]) -> Result<(), Error> {
+        self.incoming_tx
+            .as_ref()
+            .ok_or(Error::Closed)?
+            .send(msg.to_vec())
+            .await
+            .map_err(|_| Error::Closed)
+    }
+
+    /// Waits for the next message the channel sends, returning None once it has been dropped.
+    pub async fn next_sent(&mut self) -> Option<Vec<u8>> {
+        self.outgoing_rx.recv().await
+    }
+
+    /// Closes the channel: receiving ends once everything already delivered has been read, and sending fails from then on.
+    pub async fn close(&mut self) {
+        self.incoming_tx = None;
+        self.open_tx = None;
+        self.state.lock().await.error = Some(Error::Closed);
+    }
+
+    /// Fails the channel with an error from the underlying transport, as libdatachannel would report it.
+    pub async fn fail(&mut self, err: &str) {
+        self.state.lock().await.error = Some(Error::UnderlyingError(err.to_owned()));
+    }
+}
+
+/// Stands in for the network between mock peer connections.
+///
+/// Mock peer connections only connect to others on the same network. As with a real one, they connect once each side has the other's description, at which point every data channel is paired up with the one created in the same order on the other side.
+#[derive(Clone, Default)]
+pub struct Network {
+    state: std::sync::Arc<std::sync::Mutex<NetworkState>>,
+}
+
+#[derive(Default)]
+struct NetworkState {
+    next_id: u64,
+    peers: std::collections::HashMap<u64, PeerState>,
+    unreachable: bool,
+}
+
+struct PeerState {
+    remote_id: Option<u64>,
+    event_tx: tokio::sync::mpsc::Sender<PeerConnectionEvent>,
+    channels: Vec<PendingChannel>,
+}
+
+struct PendingChannel {
+    incoming_tx: tokio::sync::mpsc::Sender<Vec<u8>>,
+    outgoing_rx: tokio::sync::mpsc::Receiver<Vec<u8>>,
+    open_tx: tokio::sync::oneshot::Sender<()>,
+}
+
+impl Network {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a network on which peer connections never connect, as if ICE couldn't find a way between them: they fail once each side has the other's description instead.
+    pub fn unreachable() -> Self {
+        let network = Self::default();
+        network.state.lock().unwrap().unreachable = true;
+        network
+    }
+
+    /// Creates a peer connection on this network.
+    ///
+    /// Candidates are gathered as soon as it is created, and there is only ever one description, which identifies the peer connection to whoever it is given to.
+    pub fn peer_connection(
+        &self,
+    ) -> (
+        PeerConnection,
+        tokio::sync::mpsc::Receiver<PeerConnectionEvent>,
+    ) {
+        let (event_tx, event_rx) = tokio::sync::mpsc::channel(16);
+        let _ = event_tx.try_send(PeerConnectionEvent::GatheringStateChange(
+            GatheringState::Complete,
+        ));
+
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.peers.insert(
+            id,
+            PeerState {
+                remote_id: None,
+                event_tx,
+                channels: vec![],
+            },
+        );
+
+        // Mock peer connections never have data channels opened on them by the other side: both sides must create them.
+        let (_, data_channel_rx) = tokio::sync::mpsc::channel(1);
+        (
+            PeerConnection {
+                peer_conn: Conn::Mock(Peer {
+                    id,
+                    network: self.clone(),
+                    answering: false,
+                    remote_description: None,
+                }),
+                data_channel_rx,
+            },
+            event_rx,
+        )
+    }
+}
+
+fn description(id: u64, sdp_type: SdpType) -> SessionDescription {
+    SessionDescription {
+        sdp_type,
+        sdp: super::sdp::parse_sdp(
+            &format!(
+                "v=0\r\n\
+                 o=- {} 0 IN IP4 127.0.0.1\r\n\
+                 s=-\r\n\
+                 t=0 0\r\n\
+                 m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n\
+                 c=IN IP4 0.0.0.0\r\n\
+                 a=mid:0\r\n\
+                 a=sctp-port:5000\r\n",
+                id
+            ),
+            false,
+        )
+        .expect("parse mock sdp"),
+    }
+}
+
+fn forward(mut rx: tokio::sync::mpsc::Receiver<Vec<u8>>, tx: tokio::sync::mpsc::Sender<Vec<u8>>) {
+    tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            if tx.send(msg).await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+pub(crate) struct Peer {
+    id: u64,
+    network: Network,
+    answering: bool,
+    remote_description: Option<SessionDescription>,
+}
+
+impl Peer {
+    pub(crate) fn create_data_channel(&mut self) -> DataChannel {
+        let (incoming_tx, incoming_rx) = tokio::sync::mpsc::channel(CHANNEL_CAPACITY);
+        let (outgoing_tx, outgoing_rx) = tokio::sync::mpsc::channel(CHANNEL_CAPACITY);
+        let (open_tx, open_rx) = tokio::sync::oneshot::channel();
+        self.network
+            .state
+            .lock()
+            .unwrap()
+            .peers
+            .get_mut(&self.id)
+            .unwrap()
+            .channels
+            .push(PendingChannel {
+                incoming_tx,
+                outgoing_rx,
+                open_tx,
+            });
+        DataChannel {
+            state: std::sync::Arc::new(tokio::sync::Mutex::new(DataChannelState {
+                open_rx: Some(open_rx),
+                error: None,
+            })),
+            message_rx: incoming_rx,
+            dc: Sink::Channel(outgoing_tx),
+            counters: Default::default(),
+        }
+    }
+
+    pub(crate) fn set_local_description(&mut self, _sdp_type: SdpType) {
+        // There is only ever one description, so there is nothing to roll back.
+    }
+
+    pub(crate) fn set_remote_description(
+        &mut self,
+        sess_desc: SessionDescription,
+    ) -> anyhow::Result<()> {
+        let remote_id = sess_desc
+            .sdp
+            .to_string()
+            .lines()
+            .find_map(|line| line.strip_prefix("o="))
+            .and_then(|origin| origin.split_whitespace().nth(1))
+            .and_then(|id| id.parse::<u64>().ok())
+            .ok_or_else(|| anyhow::anyhow!("not a mock peer connection description"))?;
+        if let SdpType::Offer = sess_desc.sdp_type {
+            self.answering = true;
+        }
+        self.remote_description = Some(sess_desc);
+
+        let mut state = self.network.state.lock().unwrap();
+        if !state.peers.contains_key(&remote_id) {
+            anyhow::bail!("no such mock peer connection: {}", remote_id);
+        }
+        state.peers.get_mut(&self.id).unwrap().remote_id = Some(remote_id);
+        if state.peers[&remote_id].remote_id != Some(self.id) {
+            return Ok(());
+        }
+
+        if state.unreachable {
+            for id in [self.id, remote_id] {
+                let _ =
+                    state.peers[&id]
+                        .event_tx
+                        .try_send(PeerConnectionEvent::ConnectionStateChange(
+                            ConnectionState::Failed,
+                        ));
+            }
+            return Ok(());
+        }
+
+        // Both sides have each other's description now, so we can connect them.
+        let local_channels = std::mem::take(&mut state.peers.get_mut(&self.id).unwrap().channels);
+        let remote_channels =
+            std::mem::take(&mut state.peers.get_mut(&remote_id).unwrap().channels);
+        for (local, remote) in local_channels.into_iter().zip(remote_channels) {
+            forward(local.outgoing_rx, remote.incoming_tx);
+            forward(remote.outgoing_rx, local.incoming_tx);
+            let _ = local.open_tx.send(());
+            let _ = remote.open_tx.send(());
+        }
+        for id in [self.id, remote_id] {
+            let _ = state.peers[&id]
+                .event_tx
+                .try_send(PeerConnectionEvent::ConnectionStateChange(
+                    ConnectionState::Connected,
+                ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn local_description(&self) -> Option<SessionDescription> {
+        Some(description(
+            self.id,
+            if self.answering {
+                SdpType::Answer
+            } else {
+                SdpType::Offer
+            },
+        ))
+    }
+
+    pub(crate) fn remote_description(&self) -> Option<SessionDescription> {
+        self.remote_description.clone()
+    }
+}
@@ -0,0 +1,586 @@
+use super::core;
+
+const GAMESHARK_SEEDS: [u32; 4] = [0x09F4FBBD, 0x9681884A, 0x352027E9, 0xF3DEE5A7];
+const ACTION_REPLAY_SEEDS: [u32; 4] = [0x7AA9648F, 0x7FAE6994, 0xC0EFAAD5, 0x42712C57];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// One `AAAAAAAA:VV`, `AAAAAAAA:VVVV` or `AAAAAAAA:VVVVVVVV` write per line, sized by the number of digits in the value.
+    Raw,
+    CodeBreaker,
+    /// GameShark v1/v2.
+    GameShark,
+    /// Action Replay v3, also sold as GameShark SP.
+    ActionReplay,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Width {
+    U8,
+    U16,
+    U32,
+}
+
+impl Width {
+    fn mask(self) -> u32 {
+        match self {
+            Width::U8 => 0xff,
+            Width::U16 => 0xffff,
+            Width::U32 => 0xffffffff,
+        }
+    }
+
+    fn sign_extend(self, v: u32) -> i32 {
+        match self {
+            Width::U8 => v as u8 as i8 as i32,
+            Width::U16 => v as u16 as i16 as i32,
+            Width::U32 => v as i32,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Modify {
+    Or,
+    And,
+    Add,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Cond {
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    ULt,
+    UGt,
+    And,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Op {
+    Write {
+        address: u32,
+        width: Width,
+        value: u32,
+    },
+    Modify {
+        address: u32,
+        width: Width,
+        modify: Modify,
+        value: u32,
+    },
+    /// If the condition fails, the next `skip` ops are skipped.
+    If {
+        address: u32,
+        width: Width,
+        cond: Cond,
+        value: u32,
+        skip: usize,
+    },
+}
+
+fn read(core: &mut core::CoreMutRef, address: u32, width: Width) -> u32 {
+    match width {
+        Width::U8 => core.raw_read_8(address, -1) as u32,
+        Width::U16 => core.raw_read_16(address, -1) as u32,
+        Width::U32 => core.raw_read_32(address, -1),
+    }
+}
+
+fn write(core: &mut core::CoreMutRef, address: u32, width: Width, value: u32) {
+    match width {
+        Width::U8 => core.raw_write_8(address, -1, value as u8),
+        Width::U16 => core.raw_write_16(address, -1, value as u16),
+        Width::U32 => core.raw_write_32(address, -1, value),
+    }
+}
+
+/// A single cheat, which may span many lines of code.
+#[derive(Clone, Debug)]
+pub struct Cheat {
+    ops: Vec<Op>,
+}
+
+impl Cheat {
+    /// Parses a cheat code, one operation per line. Blank lines are ignored.
+    pub fn parse(format: Format, code: &str) -> anyhow::Result<Self> {
+        let mut ops = vec![];
+        for (i, line) in code
+            .lines()
+            .map(|line| line.trim())
+            .enumerate()
+            .filter(|(_, line)| !line.is_empty())
+        {
+            let (op1, op2, op2_digits) = split_line(line)
+                .ok_or_else(|| anyhow::anyhow!("line {}: malformed code: {}", i + 1, line))?;
+            let op = match format {
+                Format::Raw => parse_raw(op1, op2, op2_digits),
+                Format::CodeBreaker => parse_codebreaker(op1, op2, op2_digits),
+                Format::GameShark => parse_gameshark(op1, op2, op2_digits),
+                Format::ActionReplay => parse_action_replay(op1, op2, op2_digits),
+            }
+            .map_err(|e| anyhow::anyhow!("line {}: {}", i + 1, e))?;
+            ops.extend(op);
+        }
+        Ok(Self { ops })
+    }
+
+    /// Applies the cheat to the core. This should be called once per frame.
+    pub fn apply(&self, core: &mut core::CoreMutRef) {
+        let mut i = 0;
+        while i < self.ops.len() {
+            match self.ops[i] {
+                Op::Write {
+                    address,
+                    width,
+                    value,
+                } => {
+                    write(core, address, width, value);
+                }
+                Op::Modify {
+                    address,
+                    width,
+                    modify,
+                    value,
+                } => {
+                    let current = read(core, address, width);
+                    write(
+                        core,
+                        address,
+                        width,
+                        match modify {
+                            Modify::Or => current | value,
+                            Modify::And => current & value,
+                            Modify::Add => current.wrapping_add(value),
+                        },
+                    );
+                }
+                Op::If {
+                    address,
+                    width,
+                    cond,
+                    value,
+                    skip,
+                } => {
+                    let current = read(core, address, width);
+                    let ok = match cond {
+                        Cond::Eq => current == value,
+                        Cond::Ne => current != value,
+                        Cond::Lt => width.sign_extend(current) < width.sign_extend(value),
+                        Cond::Gt => width.sign_extend(current) > width.sign_extend(value),
+                        Cond::ULt => current < value,
+                        Cond::UGt => current > value,
+                        Cond::And => current & value != 0,
+                    };
+                    if !ok {
+                        i += skip;
+                    }
+                }
+            }
+            i += 1;
+        }
+    }
+}
+
+/// Splits a line into its two operands, e.g. `02001234 0063` or `02001234:63`. The number of digits in the second operand is also returned, as some formats use it to determine the write width.
+fn split_line(line: &str) -> Option<(u32, u32, usize)> {
+    let (op1, op2) = line.split_once(|c: char| c == ':' || c.is_whitespace())?;
+    let op2 = op2.trim();
+    if op1.len() != 8 || op2.is_empty() || op2.len() > 8 {
+        return None;
+    }
+    Some((
+        u32::from_str_radix(op1, 16).ok()?,
+        u32::from_str_radix(op2, 16).ok()?,
+        op2.len(),
+    ))
+}
+
+fn parse_raw(address: u32, value: u32, value_digits: usize) -> anyhow::Result<Option<Op>> {
+    let width = match value_digits {
+        2 => Width::U8,
+        4 => Width::U16,
+        8 => Width::U32,
+        _ => anyhow::bail!("value must be 2, 4 or 8 digits long"),
+    };
+    Ok(Some(Op::Write {
+        address,
+        width,
+        value,
+    }))
+}
+
+fn parse_codebreaker(op1: u32, op2: u32, op2_digits: usize) -> anyhow::Result<Option<Op>> {
+    if op2_digits != 4 {
+        anyhow::bail!("expected code of the form XXXXXXXX YYYY");
+    }
+    let address = op1 & 0x0fffffff;
+    Ok(Some(match op1 >> 28 {
+        // Master and hook codes are only needed on real hardware.
+        0x0 | 0x1 => {
+            return Ok(None);
+        }
+        0x2 => Op::Modify {
+            address,
+            width: Width::U16,
+            modify: Modify::Or,
+            value: op2,
+        },
+        0x3 => Op::Write {
+            address,
+            width: Width::U8,
+            value: op2 & 0xff,
+        },
+        0x6 => Op::Modify {
+            address,
+            width: Width::U16,
+            modify: Modify::And,
+            value: op2,
+        },
+        0x7 => Op::If {
+            address,
+            width: Width::U16,
+            cond: Cond::Eq,
+            value: op2,
+            skip: 1,
+        },
+        0x8 => Op::Write {
+            address,
+            width: Width::U16,
+            value: op2,
+        },
+        0xa => Op::If {
+            address,
+            width: Width::U16,
+            cond: Cond::Ne,
+            value: op2,
+            skip: 1,
+        },
+        0xb => Op::If {
+            address,
+            width: Width::U16,
+            cond: Cond::Gt,
+            value: op2,
+            skip: 1,
+        },
+        0xc => Op::If {
+            address,
+            width: Width::U16,
+            cond: Cond::Lt,
+            value: op2,
+            skip: 1,
+        },
+        0xe => Op::Modify {
+            address,
+            width: Width::U16,
+            modify: Modify::Add,
+            value: op2,
+        },
+        0xf => Op::If {
+            address,
+            width: Width::U16,
+            cond: Cond::And,
+            value: op2,
+            skip: 1,
+        },
+        t => anyhow::bail!("unsupported codebreaker code type: {:x}", t),
+    }))
+}
+
+fn tea_decrypt(op1: u32, op2: u32, seeds: &[u32; 4]) -> (u32, u32) {
+    const DELTA: u32 = 0x9e3779b9;
+    let (mut op1, mut op2) = (op1, op2);
+    let mut sum = DELTA.wrapping_mul(32);
+    for _ in 0..32 {
+        op2 = op2.wrapping_sub(
+            (op1 << 4).wrapping_add(seeds[2])
+                ^ op1.wrapping_add(sum)
+                ^ (op1 >> 5).wrapping_add(seeds[3]),
+        );
+        op1 = op1.wrapping_sub(
+            (op2 << 4).wrapping_add(seeds[0])
+                ^ op2.wrapping_add(sum)
+                ^ (op2 >> 5).wrapping_add(seeds[1]),
+        );
+        sum = sum.wrapping_sub(DELTA);
+    }
+    (op1, op2)
+}
+
+fn parse_gameshark(op1: u32, op2: u32, op2_digits: usize) -> anyhow::Result<Option<Op>> {
+    if op2_digits != 8 {
+        anyhow::bail!("expected code of the form XXXXXXXX YYYYYYYY");
+    }
+    let (op1, op2) = tea_decrypt(op1, op2, &GAMESHARK_SEEDS);
+    if op1 == 0xdeadface {
+        anyhow::bail!("reseeding codes are not supported");
+    }
+    let address = op1 & 0x0fffffff;
+    Ok(Some(match op1 >> 28 {
+        0x0 => Op::Write {
+            address,
+            width: Width::U8,
+            value: op2 & 0xff,
+        },
+        0x1 => Op::Write {
+            address,
+            width: Width::U16,
+            value: op2 & 0xffff,
+        },
+        0x2 => Op::Write {
+            address,
+            width: Width::U32,
+            value: op2,
+        },
+        0xd => Op::If {
+            address,
+            width: Width::U16,
+            cond: Cond::Eq,
+            value: op2 & 0xffff,
+            skip: 1,
+        },
+        // Hook codes are only needed on real hardware.
+        0xf => {
+            return Ok(None);
+        }
+        t => anyhow::bail!("unsupported gameshark code type: {:x}", t),
+    }))
+}
+
+fn parse_action_replay(op1: u32, op2: u32, op2_digits: usize) -> anyhow::Result<Option<Op>> {
+    if op2_digits != 8 {
+        anyhow::bail!("expected code of the form XXXXXXXX YYYYYYYY");
+    }
+    let (op1, op2) = tea_decrypt(op1, op2, &ACTION_REPLAY_SEEDS);
+
+    // The end-of-list marker and hook codes are only needed on real hardware.
+    if (op1 == 0 && op2 == 0) || op1 >> 24 == 0xc4 {
+        return Ok(None);
+    }
+    if op1 == 0 {
+        anyhow::bail!("unsupported action replay special code: {:08x}", op2);
+    }
+
+    let address = ((op1 & 0x0ff00000) << 4) | (op1 & 0x000fffff);
+    let width = match op1 & 0x06000000 {
+        0x00000000 => Width::U8,
+        0x02000000 => Width::U16,
+        0x04000000 => Width::U32,
+        _ => anyhow::bail!("invalid action replay code width"),
+    };
+    let value = op2 & width.mask();
+
+    let cond = match op1 & 0x38000000 {
+        0x00000000 => None,
+        0x08000000 => Some(Cond::Eq),
+        0x10000000 => Some(Cond::Ne),
+        0x18000000 => Some(Cond::Lt),
+        0x20000000 => Some(Cond::Gt),
+        0x28000000 => Some(Cond::ULt),
+        0x30000000 => Some(Cond::UGt),
+        _ => Some(Cond::And),
+    };
+
+    Ok(Some(match cond {
+        None => match op1 & 0xc0000000 {
+            0x00000000 => Op::Write {
+                address,
+                width,
+                value,
+            },
+            0x80000000 => Op::Modify {
+                address,
+                width,
+                modify: Modify::Add,
+                value,
+            },
+            _ => anyhow::bail!("unsupported action replay code type: {:08x}", op1),
+        },
+        Some(cond) => Op::If {
+            address,
+            width,
+            cond,
+            value,
+            skip: match op1 & 0xc0000000 {
+                0x00000000 => 1,
+                0x40000000 => 2,
+                _ => anyhow::bail!("unsupported action replay condition action: {:08x}", op1),
+            },
+        },
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ops(format: Format, code: &str) -> Vec<Op> {
+        Cheat::parse(format, code).unwrap().ops
+    }
+
+    #[test]
+    fn tea_decrypt_matches_reference_vector() {
+        // TEA with an all-zero key encrypts an all-zero block to this.
+        assert_eq!(tea_decrypt(0x41ea3a0a, 0x94baa940, &[0; 4]), (0, 0));
+    }
+
+    #[test]
+    fn parses_raw_writes_sized_by_value() {
+        assert_eq!(
+            ops(
+                Format::Raw,
+                "02001234:63\n\n02001236:BEEF\n03000100 DEADBEEF\n"
+            ),
+            vec![
+                Op::Write {
+                    address: 0x02001234,
+                    width: Width::U8,
+                    value: 0x63,
+                },
+                Op::Write {
+                    address: 0x02001236,
+                    width: Width::U16,
+                    value: 0xbeef,
+                },
+                Op::Write {
+                    address: 0x03000100,
+                    width: Width::U32,
+                    value: 0xdeadbeef,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_codebreaker() {
+        assert_eq!(
+            ops(
+                Format::CodeBreaker,
+                "0000AB12 000A\n\
+                 1000B34E 0007\n\
+                 32001234 0063\n\
+                 82001236 BEEF\n\
+                 72001234 00FF\n\
+                 E2001236 FFFF\n"
+            ),
+            vec![
+                Op::Write {
+                    address: 0x02001234,
+                    width: Width::U8,
+                    value: 0x63,
+                },
+                Op::Write {
+                    address: 0x02001236,
+                    width: Width::U16,
+                    value: 0xbeef,
+                },
+                Op::If {
+                    address: 0x02001234,
+                    width: Width::U16,
+                    cond: Cond::Eq,
+                    value: 0xff,
+                    skip: 1,
+                },
+                Op::Modify {
+                    address: 0x02001236,
+                    width: Width::U16,
+                    modify: Modify::Add,
+                    value: 0xffff,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_gameshark() {
+        assert_eq!(
+            ops(
+                Format::GameShark,
+                "5ACE46E2 A69146F8\n\
+                 DA5E3BAE 74D7D382\n\
+                 CE87A78F A66914FD\n\
+                 38F27D69 2DEB32F6\n\
+                 49CECCB3 127BF08E\n"
+            ),
+            vec![
+                Op::Write {
+                    address: 0x02001234,
+                    width: Width::U8,
+                    value: 0x63,
+                },
+                Op::Write {
+                    address: 0x02001234,
+                    width: Width::U16,
+                    value: 0xbeef,
+                },
+                Op::Write {
+                    address: 0x03000100,
+                    width: Width::U32,
+                    value: 0xdeadbeef,
+                },
+                Op::If {
+                    address: 0x02001234,
+                    width: Width::U16,
+                    cond: Cond::Eq,
+                    value: 0x1,
+                    skip: 1,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_gameshark_reseeding() {
+        // Decrypts to DEADFACE 00000000.
+        assert!(Cheat::parse(Format::GameShark, "D0CD0E46 4AA27D60").is_err());
+    }
+
+    #[test]
+    fn parses_action_replay() {
+        assert_eq!(
+            ops(
+                Format::ActionReplay,
+                "4F9728E8 044F1EDB\n\
+                 05B7240D 05877161\n\
+                 22E7E964 558CB049\n\
+                 D0BBE6B6 FCD969FE\n\
+                 E65B48CE 071A0576\n\
+                 A05A144C 078B0074\n"
+            ),
+            vec![
+                Op::Write {
+                    address: 0x02001234,
+                    width: Width::U8,
+                    value: 0x63,
+                },
+                Op::Write {
+                    address: 0x03000100,
+                    width: Width::U16,
+                    value: 0x1234,
+                },
+                Op::Modify {
+                    address: 0x02001234,
+                    width: Width::U32,
+                    modify: Modify::Add,
+                    value: 0x5,
+                },
+                Op::If {
+                    address: 0x02001234,
+                    width: Width::U16,
+                    cond: Cond::Eq,
+                    value: 0xff,
+                    skip: 2,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_line_of_bad_code() {
+        let err = Cheat::parse(Format::CodeBreaker, "32001234 0063\n\n42001234 0000")
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("line 3:"), "{}", err);
+    }
+}
@@ -3,12 +3,14 @@ use super::gba;
 use super::state;
 use super::trapper;
 use super::vfile;
+use super::watcher;
 use std::ffi::CString;
 
 pub struct Core {
     pub(super) ptr: *mut mgba_sys::mCore,
     video_buffer: Option<Vec<u8>>,
     trapper: Option<trapper::Trapper>,
+    watcher: Option<watcher::Watcher>,
 }
 
 unsafe impl Send for Core {}
@@ -38,6 +40,7 @@ impl Core {
             ptr,
             video_buffer: None,
             trapper: None,
+            watcher: None,
         })
     }
 
@@ -75,6 +78,13 @@ impl Core {
     pub fn set_traps(&mut self, traps: Vec<(u32, Box<dyn FnMut(CoreMutRef)>)>) {
         self.trapper = Some(trapper::Trapper::new(self.as_mut(), traps));
     }
+
+    pub fn enable_watchpoints(&mut self) {
+        if self.watcher.is_some() {
+            return;
+        }
+        self.watcher = Some(watcher::Watcher::new(self.as_mut()));
+    }
 }
 
 impl Drop for Core {
@@ -305,7 +315,11 @@ impl<'a> CoreMutRef<'a> {
         unsafe { (*self.ptr).runLoop.unwrap()(self.ptr) }
     }
 
+    /// Runs a single instruction.
+    ///
+    /// From within a trap handler, the first step runs the trapped instruction, which the trapper then won't run again once the handler returns.
     pub fn step(&mut self) {
+        trapper::mark_stepped(*self);
         unsafe { (*self.ptr).step.unwrap()(self.ptr) }
     }
 
@@ -313,6 +327,31 @@ impl<'a> CoreMutRef<'a> {
         unsafe { (*self.ptr).reset.unwrap()(self.ptr) }
     }
 
+    pub fn install_trap(
+        &mut self,
+        addr: u32,
+        handler: Box<dyn FnMut(CoreMutRef)>,
+    ) -> anyhow::Result<()> {
+        trapper::install(*self, addr, handler)
+    }
+
+    pub fn uninstall_trap(&mut self, addr: u32) -> anyhow::Result<()> {
+        trapper::uninstall(*self, addr)
+    }
+
+    pub fn install_watchpoint(
+        &mut self,
+        range: std::ops::Range<u32>,
+        kind: watcher::Kind,
+        handler: watcher::Handler,
+    ) -> anyhow::Result<u32> {
+        watcher::install(*self, range, kind, handler)
+    }
+
+    pub fn uninstall_watchpoint(&mut self, id: u32) -> anyhow::Result<()> {
+        watcher::uninstall(*self, id)
+    }
+
     pub fn audio_buffer_size(&mut self) -> u64 {
         unsafe { (*self.ptr).getAudioBufferSize.unwrap()(self.ptr) }
     }
@@ -3,6 +3,7 @@ extern crate lazy_static;
 
 pub mod arm_core;
 pub mod blip;
+pub mod cheats;
 pub mod core;
 pub mod gba;
 pub mod input;
@@ -13,3 +14,4 @@ pub mod thread;
 pub mod timing;
 pub mod trapper;
 pub mod vfile;
+pub mod watcher;
@@ -12,13 +12,101 @@ struct TrapperCStruct {
 }
 
 struct Trap {
-    handler: Box<dyn FnMut(core::CoreMutRef)>,
+    handler: std::cell::RefCell<Box<dyn FnMut(core::CoreMutRef)>>,
     original: u16,
 }
 
 struct Impl {
-    traps: std::collections::HashMap<u32, Trap>,
+    // Traps are reference counted so a handler can uninstall its own trap while it is running.
+    traps: std::collections::HashMap<u32, std::rc::Rc<Trap>>,
     core_ptr: *mut mgba_sys::mCore,
+    // Set while a handler is running, to whether it has already stepped past the trapped instruction.
+    handling: Option<bool>,
+}
+
+impl Impl {
+    fn install(
+        &mut self,
+        addr: u32,
+        handler: Box<dyn FnMut(core::CoreMutRef)>,
+    ) -> anyhow::Result<()> {
+        match self.traps.entry(addr) {
+            std::collections::hash_map::Entry::Occupied(_) => {
+                anyhow::bail!("attempting to install a second trap at 0x{:08x}", addr);
+            }
+            std::collections::hash_map::Entry::Vacant(e) => {
+                let mut original = 0i16;
+                unsafe {
+                    mgba_sys::GBAPatch16(
+                        (*self.core_ptr).cpu as *mut mgba_sys::ARMCore,
+                        addr,
+                        (0xbe00 | TRAPPER_IMM) as i16,
+                        &mut original,
+                    )
+                };
+                e.insert(std::rc::Rc::new(Trap {
+                    original: original as u16,
+                    handler: std::cell::RefCell::new(handler),
+                }));
+            }
+        };
+        Ok(())
+    }
+
+    fn uninstall(&mut self, addr: u32) -> anyhow::Result<()> {
+        let trap = if let Some(trap) = self.traps.remove(&addr) {
+            trap
+        } else {
+            anyhow::bail!("no trap installed at 0x{:08x}", addr);
+        };
+        let mut patched = 0i16;
+        unsafe {
+            mgba_sys::GBAPatch16(
+                (*self.core_ptr).cpu as *mut mgba_sys::ARMCore,
+                addr,
+                trap.original as i16,
+                &mut patched,
+            )
+        };
+        Ok(())
+    }
+}
+
+unsafe fn get_impl<'a>(core: &mut core::CoreMutRef<'a>) -> Option<&'a mut Impl> {
+    let components = core.gba_mut().cpu_mut().components_mut();
+    let trapper = components[mgba_sys::mCPUComponentType_CPU_COMPONENT_MISC_1 as usize]
+        as *mut TrapperCStruct;
+    if trapper.is_null() {
+        return None;
+    }
+    Some(&mut (*trapper).r#impl)
+}
+
+/// Installs a trap on a core that is already running, e.g. for a debugger breakpoint. The core must have had traps set with [`core::Core::set_traps`], even if there were none.
+pub fn install(
+    mut core: core::CoreMutRef,
+    addr: u32,
+    handler: Box<dyn FnMut(core::CoreMutRef)>,
+) -> anyhow::Result<()> {
+    unsafe { get_impl(&mut core) }
+        .ok_or_else(|| anyhow::anyhow!("no trapper attached"))?
+        .install(addr, handler)
+}
+
+/// Uninstalls a trap, restoring the original instruction. This may be called from within the trap's own handler.
+pub fn uninstall(mut core: core::CoreMutRef, addr: u32) -> anyhow::Result<()> {
+    unsafe { get_impl(&mut core) }
+        .ok_or_else(|| anyhow::anyhow!("no trapper attached"))?
+        .uninstall(addr)
+}
+
+/// Records that the running trap handler, if any, has stepped past the trapped instruction itself.
+pub(crate) fn mark_stepped(mut core: core::CoreMutRef) {
+    if let Some(stepped) =
+        unsafe { get_impl(&mut core) }.and_then(|r#impl| r#impl.handling.as_mut())
+    {
+        *stepped = true;
+    }
 }
 
 unsafe impl Send for TrapperCStruct {}
@@ -43,19 +131,32 @@ unsafe extern "C" fn c_trapper_bkpt16(arm_core: *mut mgba_sys::ARMCore, imm: i32
     let components = arm_core.components_mut();
     let trapper = &mut *(components[mgba_sys::mCPUComponentType_CPU_COMPONENT_MISC_1 as usize]
         as *mut _ as *mut TrapperCStruct);
+    // The handler may install or uninstall traps, so don't hold onto the trapper while it runs.
+    let real_bkpt16 = trapper.real_bkpt16;
     if imm == TRAPPER_IMM {
-        let r#impl = &mut trapper.r#impl;
+        let r#impl = &mut trapper.r#impl as *mut Impl;
         let caller = arm_core.as_ref().gpr(15) as u32 - mgba_sys::WordSize_WORD_SIZE_THUMB * 2;
-        let trap = r#impl.traps.get_mut(&caller).unwrap();
-        mgba_sys::ARMRunFake(arm_core.ptr, trap.original as u32);
-        let mut core = core::CoreMutRef {
-            ptr: r#impl.core_ptr,
+        let trap = (*r#impl).traps.get(&caller).unwrap().clone();
+        let core_ptr = (*r#impl).core_ptr;
+        let core = core::CoreMutRef {
+            ptr: core_ptr,
             _lifetime: std::marker::PhantomData,
         };
-        (trap.handler)(core);
-        core.step();
+        mgba_sys::ARMRunFake(arm_core.ptr, trap.original as u32);
+
+        // The handler may step into its own trap again, e.g. a debugger single-stepping through a loop, in which case we just run the trapped instruction.
+        let outer_handling = std::mem::replace(&mut (*r#impl).handling, Some(false));
+        if let Ok(mut handler) = trap.handler.try_borrow_mut() {
+            (&mut *handler)(core);
+        }
+        let stepped = std::mem::replace(&mut (*r#impl).handling, outer_handling) == Some(true);
+
+        // Step without going through the core, so this doesn't count as a step by whichever handler we may be nested in.
+        if !stepped {
+            (*core_ptr).step.unwrap()(core_ptr);
+        }
     }
-    (*trapper).real_bkpt16.unwrap()(arm_core.ptr, imm);
+    real_bkpt16.unwrap()(arm_core.ptr, imm);
 }
 
 impl Trapper {
@@ -72,6 +173,7 @@ impl Trapper {
             r#impl: Impl {
                 traps: std::collections::HashMap::new(),
                 core_ptr: core.ptr,
+                handling: None,
             },
         });
 
@@ -96,26 +198,7 @@ impl Trapper {
                 // TODO: Delete me
                 continue;
             }
-            match trapper_c_struct.r#impl.traps.entry(addr) {
-                std::collections::hash_map::Entry::Occupied(_) => {
-                    panic!("attempting to install a second trap at 0x{:08x}", addr);
-                }
-                std::collections::hash_map::Entry::Vacant(e) => {
-                    let mut original = 0i16;
-                    unsafe {
-                        mgba_sys::GBAPatch16(
-                            core.gba_mut().cpu_mut().ptr,
-                            addr,
-                            (0xbe00 | TRAPPER_IMM) as i16,
-                            &mut original,
-                        )
-                    };
-                    e.insert(Trap {
-                        original: original as u16,
-                        handler,
-                    });
-                }
-            };
+            trapper_c_struct.r#impl.install(addr, handler).unwrap();
         }
         Trapper(trapper_c_struct)
     }
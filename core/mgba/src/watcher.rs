@@ -0,0 +1,474 @@
+use super::core;
+use super::gba;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Read,
+    Write,
+    /// Either a read or a write.
+    Access,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Access {
+    pub is_write: bool,
+    pub address: u32,
+    pub width: u32,
+    /// For reads, this is the same as `new_value`.
+    pub old_value: u32,
+    pub new_value: u32,
+    /// The address of the instruction that made the access.
+    ///
+    /// DMA transfers go through the same memory accessors as the CPU, so their accesses are reported too, but with whatever instruction the CPU was stopped at when the transfer ran.
+    pub pc: u32,
+}
+
+pub type Handler = Box<dyn FnMut(core::CoreMutRef, Access)>;
+
+#[repr(transparent)]
+pub struct Watcher(Box<WatcherCStruct>);
+
+#[repr(C)]
+struct WatcherCStruct {
+    cpu_component: mgba_sys::mCPUComponent,
+    original_memory: mgba_sys::ARMMemory,
+    r#impl: Impl,
+}
+
+struct Watchpoint {
+    range: std::ops::Range<u32>,
+    kind: Kind,
+    handler: std::cell::RefCell<Handler>,
+}
+
+struct Impl {
+    // Watchpoints are reference counted so a handler can uninstall its own watchpoint while it is running.
+    watchpoints: std::collections::BTreeMap<u32, std::rc::Rc<Watchpoint>>,
+    next_id: u32,
+    core_ptr: *mut mgba_sys::mCore,
+}
+
+unsafe impl Send for WatcherCStruct {}
+unsafe impl Send for Impl {}
+
+const EWRAM: std::ops::Range<u32> = 0x02000000..0x02040000;
+const IWRAM: std::ops::Range<u32> = 0x03000000..0x03008000;
+
+// EWRAM and IWRAM are mirrored throughout their regions, so accesses need to be folded back onto the canonical addresses.
+fn canonicalize(address: u32) -> u32 {
+    match address >> 24 {
+        0x02 => EWRAM.start | (address & (EWRAM.end - EWRAM.start - 1)),
+        0x03 => IWRAM.start | (address & (IWRAM.end - IWRAM.start - 1)),
+        _ => address,
+    }
+}
+
+impl Impl {
+    fn matching(&self, is_write: bool, address: u32, width: u32) -> Vec<std::rc::Rc<Watchpoint>> {
+        if self.watchpoints.is_empty() {
+            return vec![];
+        }
+        let start = canonicalize(address & !(width - 1));
+        let end = start + width;
+        self.watchpoints
+            .values()
+            .filter(|w| {
+                (match w.kind {
+                    Kind::Read => !is_write,
+                    Kind::Write => is_write,
+                    Kind::Access => true,
+                }) && w.range.start < end
+                    && start < w.range.end
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+unsafe fn get_watcher<'a>(cpu: *mut mgba_sys::ARMCore) -> &'a mut WatcherCStruct {
+    let components = std::slice::from_raw_parts_mut(
+        (*cpu).components,
+        mgba_sys::mCPUComponentType_CPU_COMPONENT_MAX as usize,
+    );
+    &mut *(components[mgba_sys::mCPUComponentType_CPU_COMPONENT_MISC_2 as usize] as *mut _
+        as *mut WatcherCStruct)
+}
+
+unsafe fn current_pc(cpu: *mut mgba_sys::ARMCore) -> u32 {
+    let gba = gba::GBAMutRef {
+        ptr: (*cpu).master as *mut mgba_sys::GBA,
+        _lifetime: std::marker::PhantomData,
+    };
+    let cpu = gba.cpu_mut();
+    let cpu = cpu.as_ref();
+    // By the time an instruction accesses memory, the PC is two instructions ahead of it.
+    match cpu.execution_mode() {
+        super::arm_core::ExecutionMode::ARM => {
+            cpu.gpr(15) as u32 - mgba_sys::WordSize_WORD_SIZE_ARM * 2
+        }
+        super::arm_core::ExecutionMode::Thumb => {
+            cpu.gpr(15) as u32 - mgba_sys::WordSize_WORD_SIZE_THUMB * 2
+        }
+    }
+}
+
+fn raw_read(core: &mut core::CoreMutRef, address: u32, width: u32) -> u32 {
+    match width {
+        1 => core.raw_read_8(address, -1) as u32,
+        2 => core.raw_read_16(address, -1) as u32,
+        _ => core.raw_read_32(address, -1),
+    }
+}
+
+unsafe fn notify(
+    cpu: *mut mgba_sys::ARMCore,
+    watchpoints: Vec<std::rc::Rc<Watchpoint>>,
+    access: Access,
+) {
+    if watchpoints.is_empty() {
+        return;
+    }
+    let core = core::CoreMutRef {
+        ptr: get_watcher(cpu).r#impl.core_ptr,
+        _lifetime: std::marker::PhantomData,
+    };
+    let access = Access {
+        pc: current_pc(cpu),
+        ..access
+    };
+    for watchpoint in watchpoints {
+        // The handler may access its own watched range again, e.g. a debugger reading memory while stopped, in which case we just let the access through.
+        if let Ok(mut handler) = watchpoint.handler.try_borrow_mut() {
+            (&mut *handler)(core, access);
+        }
+    }
+}
+
+unsafe fn load(
+    cpu: *mut mgba_sys::ARMCore,
+    address: u32,
+    width: u32,
+    load: impl FnOnce(&mgba_sys::ARMMemory) -> u32,
+) -> u32 {
+    let watcher = get_watcher(cpu);
+    let value = load(&watcher.original_memory);
+    let watchpoints = watcher.r#impl.matching(false, address, width);
+    notify(
+        cpu,
+        watchpoints,
+        Access {
+            is_write: false,
+            address,
+            width,
+            old_value: value,
+            new_value: value,
+            pc: 0,
+        },
+    );
+    value
+}
+
+unsafe fn store(
+    cpu: *mut mgba_sys::ARMCore,
+    address: u32,
+    width: u32,
+    store: impl FnOnce(&mgba_sys::ARMMemory),
+) {
+    let watcher = get_watcher(cpu);
+    let watchpoints = watcher.r#impl.matching(true, address, width);
+    if watchpoints.is_empty() {
+        store(&watcher.original_memory);
+        return;
+    }
+    let mut core = core::CoreMutRef {
+        ptr: watcher.r#impl.core_ptr,
+        _lifetime: std::marker::PhantomData,
+    };
+    let old_value = raw_read(&mut core, address, width);
+    store(&watcher.original_memory);
+    let new_value = raw_read(&mut core, address, width);
+    notify(
+        cpu,
+        watchpoints,
+        Access {
+            is_write: true,
+            address,
+            width,
+            old_value,
+            new_value,
+            pc: 0,
+        },
+    );
+}
+
+unsafe extern "C" fn c_load32(
+    cpu: *mut mgba_sys::ARMCore,
+    address: u32,
+    cycle_counter: *mut i32,
+) -> u32 {
+    load(cpu, address, 4, |m| {
+        m.load32.unwrap()(cpu, address, cycle_counter)
+    })
+}
+
+unsafe extern "C" fn c_load16(
+    cpu: *mut mgba_sys::ARMCore,
+    address: u32,
+    cycle_counter: *mut i32,
+) -> u32 {
+    load(cpu, address, 2, |m| {
+        m.load16.unwrap()(cpu, address, cycle_counter)
+    })
+}
+
+unsafe extern "C" fn c_load8(
+    cpu: *mut mgba_sys::ARMCore,
+    address: u32,
+    cycle_counter: *mut i32,
+) -> u32 {
+    load(cpu, address, 1, |m| {
+        m.load8.unwrap()(cpu, address, cycle_counter)
+    })
+}
+
+unsafe extern "C" fn c_store32(
+    cpu: *mut mgba_sys::ARMCore,
+    address: u32,
+    value: i32,
+    cycle_counter: *mut i32,
+) {
+    store(cpu, address, 4, |m| {
+        m.store32.unwrap()(cpu, address, value, cycle_counter)
+    })
+}
+
+unsafe extern "C" fn c_store16(
+    cpu: *mut mgba_sys::ARMCore,
+    address: u32,
+    value: i16,
+    cycle_counter: *mut i32,
+) {
+    store(cpu, address, 2, |m| {
+        m.store16.unwrap()(cpu, address, value, cycle_counter)
+    })
+}
+
+unsafe extern "C" fn c_store8(
+    cpu: *mut mgba_sys::ARMCore,
+    address: u32,
+    value: i8,
+    cycle_counter: *mut i32,
+) {
+    store(cpu, address, 1, |m| {
+        m.store8.unwrap()(cpu, address, value, cycle_counter)
+    })
+}
+
+// Returns the addresses of the words a LDM/STM touches, in the order they are accessed.
+fn multiple_addresses(
+    base_address: u32,
+    mask: i32,
+    direction: mgba_sys::LSMDirection,
+) -> impl Iterator<Item = u32> {
+    let n = (mask as u32 & 0xffff).count_ones();
+    let before = direction & mgba_sys::LSMDirection_LSM_B != 0;
+    let decrement = direction & mgba_sys::LSMDirection_LSM_D != 0;
+    let start = match (decrement, before) {
+        (false, false) => base_address,
+        (false, true) => base_address.wrapping_add(4),
+        (true, false) => base_address.wrapping_sub(4 * n).wrapping_add(4),
+        (true, true) => base_address.wrapping_sub(4 * n),
+    } & !3;
+    (0..n).map(move |i| start.wrapping_add(4 * i))
+}
+
+unsafe extern "C" fn c_load_multiple(
+    cpu: *mut mgba_sys::ARMCore,
+    base_address: u32,
+    mask: i32,
+    direction: mgba_sys::LSMDirection,
+    cycle_counter: *mut i32,
+) -> u32 {
+    let watcher = get_watcher(cpu);
+    let r = watcher.original_memory.loadMultiple.unwrap()(
+        cpu,
+        base_address,
+        mask,
+        direction,
+        cycle_counter,
+    );
+    let mut core = core::CoreMutRef {
+        ptr: watcher.r#impl.core_ptr,
+        _lifetime: std::marker::PhantomData,
+    };
+    for address in multiple_addresses(base_address, mask, direction) {
+        let watchpoints = get_watcher(cpu).r#impl.matching(false, address, 4);
+        if watchpoints.is_empty() {
+            continue;
+        }
+        let value = raw_read(&mut core, address, 4);
+        notify(
+            cpu,
+            watchpoints,
+            Access {
+                is_write: false,
+                address,
+                width: 4,
+                old_value: value,
+                new_value: value,
+                pc: 0,
+            },
+        );
+    }
+    r
+}
+
+unsafe extern "C" fn c_store_multiple(
+    cpu: *mut mgba_sys::ARMCore,
+    base_address: u32,
+    mask: i32,
+    direction: mgba_sys::LSMDirection,
+    cycle_counter: *mut i32,
+) -> u32 {
+    let watcher = get_watcher(cpu);
+    let mut core = core::CoreMutRef {
+        ptr: watcher.r#impl.core_ptr,
+        _lifetime: std::marker::PhantomData,
+    };
+    let watched = multiple_addresses(base_address, mask, direction)
+        .filter_map(|address| {
+            let watchpoints = watcher.r#impl.matching(true, address, 4);
+            if watchpoints.is_empty() {
+                return None;
+            }
+            Some((address, raw_read(&mut core, address, 4), watchpoints))
+        })
+        .collect::<Vec<_>>();
+    let r = watcher.original_memory.storeMultiple.unwrap()(
+        cpu,
+        base_address,
+        mask,
+        direction,
+        cycle_counter,
+    );
+    for (address, old_value, watchpoints) in watched {
+        let new_value = raw_read(&mut core, address, 4);
+        notify(
+            cpu,
+            watchpoints,
+            Access {
+                is_write: true,
+                address,
+                width: 4,
+                old_value,
+                new_value,
+                pc: 0,
+            },
+        );
+    }
+    r
+}
+
+unsafe extern "C" fn c_watcher_init(
+    _cpu: *mut std::os::raw::c_void,
+    _cpu_component: *mut mgba_sys::mCPUComponent,
+) {
+}
+
+unsafe extern "C" fn c_watcher_deinit(_cpu_component: *mut mgba_sys::mCPUComponent) {}
+
+impl Watcher {
+    pub fn new(mut core: core::CoreMutRef) -> Self {
+        let mut cpu_component = unsafe { std::mem::zeroed::<mgba_sys::mCPUComponent>() };
+        cpu_component.init = Some(c_watcher_init);
+        cpu_component.deinit = Some(c_watcher_deinit);
+
+        unsafe {
+            let arm_core = &mut *core.gba_mut().cpu_mut().ptr;
+            let mut watcher_c_struct = Box::new(WatcherCStruct {
+                cpu_component,
+                original_memory: arm_core.memory,
+                r#impl: Impl {
+                    watchpoints: std::collections::BTreeMap::new(),
+                    next_id: 0,
+                    core_ptr: core.ptr,
+                },
+            });
+
+            let components = std::slice::from_raw_parts_mut(
+                arm_core.components,
+                mgba_sys::mCPUComponentType_CPU_COMPONENT_MAX as usize,
+            );
+            components[mgba_sys::mCPUComponentType_CPU_COMPONENT_MISC_2 as usize] =
+                &mut *watcher_c_struct as *mut _ as *mut mgba_sys::mCPUComponent;
+            mgba_sys::ARMHotplugAttach(
+                arm_core,
+                mgba_sys::mCPUComponentType_CPU_COMPONENT_MISC_2 as mgba_sys::size_t,
+            );
+
+            arm_core.memory.load32 = Some(c_load32);
+            arm_core.memory.load16 = Some(c_load16);
+            arm_core.memory.load8 = Some(c_load8);
+            arm_core.memory.store32 = Some(c_store32);
+            arm_core.memory.store16 = Some(c_store16);
+            arm_core.memory.store8 = Some(c_store8);
+            arm_core.memory.loadMultiple = Some(c_load_multiple);
+            arm_core.memory.storeMultiple = Some(c_store_multiple);
+
+            Watcher(watcher_c_struct)
+        }
+    }
+}
+
+unsafe fn get_impl<'a>(core: &mut core::CoreMutRef<'a>) -> Option<&'a mut Impl> {
+    let components = core.gba_mut().cpu_mut().components_mut();
+    let watcher = components[mgba_sys::mCPUComponentType_CPU_COMPONENT_MISC_2 as usize]
+        as *mut WatcherCStruct;
+    if watcher.is_null() {
+        return None;
+    }
+    Some(&mut (*watcher).r#impl)
+}
+
+/// Installs a watchpoint over a range of EWRAM or IWRAM, returning an ID that can later be used to uninstall it. The core must have had watchpoints enabled with [`core::Core::enable_watchpoints`].
+pub fn install(
+    mut core: core::CoreMutRef,
+    range: std::ops::Range<u32>,
+    kind: Kind,
+    handler: Handler,
+) -> anyhow::Result<u32> {
+    if range.is_empty()
+        || !((EWRAM.contains(&range.start) && range.end <= EWRAM.end)
+            || (IWRAM.contains(&range.start) && range.end <= IWRAM.end))
+    {
+        anyhow::bail!(
+            "watchpoints must be within ewram or iwram: 0x{:08x}..0x{:08x}",
+            range.start,
+            range.end
+        );
+    }
+
+    let r#impl =
+        unsafe { get_impl(&mut core) }.ok_or_else(|| anyhow::anyhow!("watchpoints not enabled"))?;
+    let id = r#impl.next_id;
+    r#impl.next_id += 1;
+    r#impl.watchpoints.insert(
+        id,
+        std::rc::Rc::new(Watchpoint {
+            range,
+            kind,
+            handler: std::cell::RefCell::new(handler),
+        }),
+    );
+    Ok(id)
+}
+
+/// Uninstalls a watchpoint. This may be called from within the watchpoint's own handler.
+pub fn uninstall(mut core: core::CoreMutRef, id: u32) -> anyhow::Result<()> {
+    let r#impl =
+        unsafe { get_impl(&mut core) }.ok_or_else(|| anyhow::anyhow!("watchpoints not enabled"))?;
+    if r#impl.watchpoints.remove(&id).is_none() {
+        anyhow::bail!("no watchpoint with id {}", id);
+    }
+    Ok(())
+}
@@ -3,14 +3,19 @@ use rand::Rng;
 use crate::game;
 use crate::hooks;
 use crate::ipc;
+use crate::journal;
 use crate::lockstep;
+use crate::patch;
 use crate::protocol;
 use crate::replay;
 use crate::replayer;
 use crate::shadow;
+use crate::stall;
+use crate::telemetry;
 use crate::transport;
+use prost::Message;
 
-#[derive(PartialEq, Debug, Clone, Copy)]
+#[derive(PartialEq, Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
 pub enum BattleResult {
     Loss,
     Win,
@@ -25,6 +30,7 @@ pub struct CommittedState {
 
 pub struct MatchInit {
     pub dc: datachannel_wrapper::DataChannel,
+    pub unreliable_dc: Option<datachannel_wrapper::DataChannel>,
     pub peer_conn: datachannel_wrapper::PeerConnection,
     pub settings: Settings,
 }
@@ -33,19 +39,33 @@ pub struct Settings {
     pub replays_path: std::path::PathBuf,
     pub shadow_save_path: std::path::PathBuf,
     pub shadow_rom_path: std::path::PathBuf,
+    pub shadow_rom_patches: patch::Chain,
     pub replay_metadata: Vec<u8>,
     pub match_type: (u8, u8),
     pub input_delay: u32,
+    pub remote_input_delay: u32,
     pub rng_seed: Vec<u8>,
+    pub local_nickname: Option<String>,
     pub opponent_nickname: Option<String>,
     pub max_queue_length: usize,
+    pub resume: Option<journal::Resume>,
+    pub stall_timeout: std::time::Duration,
+    pub telemetry_path: Option<std::path::PathBuf>,
 }
 
 pub struct RoundState {
     pub number: u8,
     pub round: Option<Round>,
     pub last_result: Option<BattleResult>,
+    /// Whether the last round was a draw, in which case last_result only decides sides for the next round and isn't scored.
+    pub last_result_is_draw: bool,
+    pub wins: u32,
+    pub losses: u32,
+    pub draws: u32,
     ipc_sender: std::sync::Arc<parking_lot::Mutex<ipc::Sender>>,
+    journal: journal::Writer,
+    rng: std::sync::Arc<tokio::sync::Mutex<rand_pcg::Mcg128Xsl64>>,
+    stall_detector: std::sync::Arc<stall::Detector>,
 }
 
 impl RoundState {
@@ -53,6 +73,25 @@ impl RoundState {
         match self.round.take() {
             Some(round) => {
                 log::info!("round ended at {:x}", round.current_tick);
+                self.stall_detector.disarm();
+                match self.last_result {
+                    Some(_) if self.last_result_is_draw => {
+                        self.draws += 1;
+                    }
+                    Some(BattleResult::Win) => {
+                        self.wins += 1;
+                    }
+                    Some(BattleResult::Loss) => {
+                        self.losses += 1;
+                    }
+                    None => {}
+                }
+                self.journal.append(&journal::Entry::RoundEnded {
+                    number: self.number,
+                    result: self.last_result,
+                    draw: self.last_result_is_draw,
+                    rng: self.rng.lock().await.clone(),
+                })?;
                 self.ipc_sender
                     .lock()
                     .send(ipc::protos::FromCoreMessage {
@@ -76,8 +115,49 @@ impl RoundState {
         Ok(())
     }
 
+    /// Ends the round without a result, e.g. because the remote stopped sending us input.
+    ///
+    /// The replay is finished with whatever was committed so far, and nothing is written to the journal, so a resumed set plays this round again.
+    pub async fn abort_round(&mut self) -> anyhow::Result<()> {
+        let mut round = match self.round.take() {
+            Some(round) => round,
+            None => {
+                return Ok(());
+            }
+        };
+        log::info!("round aborted at {:x}", round.current_tick);
+        self.stall_detector.disarm();
+        if let Some(replay_writer) = round.replay_writer.take() {
+            replay_writer.finish()?;
+        }
+        self.ipc_sender
+            .lock()
+            .send(ipc::protos::FromCoreMessage {
+                which: Some(ipc::protos::from_core_message::Which::RoundEndedEv(
+                    ipc::protos::from_core_message::RoundEndedEvent {
+                        replay_filename: round
+                            .replay_filename
+                            .as_os_str()
+                            .to_str()
+                            .expect("replay filename")
+                            .to_owned(),
+                    },
+                )),
+            })
+            .await?;
+        Ok(())
+    }
+
     pub fn set_last_result(&mut self, last_result: BattleResult) {
         self.last_result = Some(last_result);
+        self.last_result_is_draw = false;
+    }
+
+    /// Records a draw for the current round: it counts towards neither side, but still decides sides for the next round as per [`Round::on_draw_result`].
+    pub fn set_draw_result(&mut self) {
+        let round = self.round.as_ref().expect("round");
+        self.last_result = Some(round.on_draw_result());
+        self.last_result_is_draw = true;
     }
 }
 
@@ -85,9 +165,10 @@ pub struct Match {
     shadow: std::sync::Arc<parking_lot::Mutex<shadow::Shadow>>,
     rom: Vec<u8>,
     hooks: &'static Box<dyn hooks::Hooks + Send + Sync>,
-    _peer_conn: datachannel_wrapper::PeerConnection,
+    peer_conn: datachannel_wrapper::PeerConnection,
     transport: std::sync::Arc<tokio::sync::Mutex<transport::Transport>>,
-    rng: tokio::sync::Mutex<rand_pcg::Mcg128Xsl64>,
+    redundancy: std::sync::Arc<transport::Redundancy>,
+    rng: std::sync::Arc<tokio::sync::Mutex<rand_pcg::Mcg128Xsl64>>,
     cancellation_token: tokio_util::sync::CancellationToken,
     settings: Settings,
     is_offerer: bool,
@@ -95,6 +176,10 @@ pub struct Match {
     primary_thread_handle: mgba::thread::Handle,
     round_started_tx: tokio::sync::mpsc::Sender<u8>,
     round_started_rx: tokio::sync::Mutex<tokio::sync::mpsc::Receiver<u8>>,
+    stall_detector: std::sync::Arc<stall::Detector>,
+    telemetry: std::sync::Arc<telemetry::Collector>,
+    // Sampled along with telemetry, so that showing them doesn't need to take the transport lock.
+    connection_stats: parking_lot::Mutex<Option<telemetry::ConnectionStats>>,
     transport_rendezvous_tx: tokio::sync::Mutex<Option<tokio::sync::oneshot::Sender<()>>>,
 }
 
@@ -171,60 +256,145 @@ impl Match {
         hooks: &'static Box<dyn hooks::Hooks + Send + Sync>,
         peer_conn: datachannel_wrapper::PeerConnection,
         dc: datachannel_wrapper::DataChannel,
+        unreliable_dc: Option<datachannel_wrapper::DataChannel>,
         mut rng: rand_pcg::Mcg128Xsl64,
         is_offerer: bool,
         primary_thread_handle: mgba::thread::Handle,
         ipc_sender: std::sync::Arc<parking_lot::Mutex<ipc::Sender>>,
-        settings: Settings,
+        mut settings: Settings,
     ) -> anyhow::Result<std::sync::Arc<Self>> {
-        let shadow_rom = std::fs::read(&settings.shadow_rom_path)?;
+        let shadow_rom = settings
+            .shadow_rom_patches
+            .apply(patch::normalize_revision(std::fs::read(
+                &settings.shadow_rom_path,
+            )?)?)?;
+
+        // Record what delay each side actually played with, as it is only known after the handshake.
+        let mut replay_metadata =
+            tango_protos::replay::ReplayMetadata::decode(&settings.replay_metadata[..])?;
+        if let Some(local_side) = replay_metadata.local_side.as_mut() {
+            local_side.input_delay = settings.input_delay;
+        }
+        if let Some(remote_side) = replay_metadata.remote_side.as_mut() {
+            remote_side.input_delay = settings.remote_input_delay;
+        }
+        settings.replay_metadata = replay_metadata.encode_to_vec();
+        log::info!(
+            "input delay: local = {}, remote = {}",
+            settings.input_delay,
+            settings.remote_input_delay
+        );
 
         let (dc_tx, dc_rx) = dc.split();
+        let (unreliable_dc_tx, unreliable_dc_rx) = match unreliable_dc {
+            Some(unreliable_dc) => {
+                let (tx, rx) = unreliable_dc.split();
+                (Some(tx), Some(rx))
+            }
+            None => (None, None),
+        };
+        let redundancy = std::sync::Arc::new(transport::Redundancy::default());
         let (round_started_tx, round_started_rx) = tokio::sync::mpsc::channel(1);
         let (transport_rendezvous_tx, transport_rendezvous_rx) = tokio::sync::oneshot::channel();
-        let did_polite_win_last_round = rng.gen::<bool>();
-        let last_result = if did_polite_win_last_round == is_offerer {
-            BattleResult::Win
+        let last_result = if let Some(resume) = settings.resume.as_ref() {
+            log::info!(
+                "resuming match after round {} ({} - {}, {} drawn)",
+                resume.round_number,
+                resume.wins,
+                resume.losses,
+                resume.draws
+            );
+            rng = resume.rng.clone();
+            resume.last_result
         } else {
-            BattleResult::Loss
+            let did_polite_win_last_round = rng.gen::<bool>();
+            if did_polite_win_last_round == is_offerer {
+                BattleResult::Win
+            } else {
+                BattleResult::Loss
+            }
         };
+
+        let telemetry_writer = match settings.telemetry_path.as_ref() {
+            Some(path) => Some(telemetry::Writer::create(path)?),
+            None => None,
+        };
+
+        let mut journal =
+            journal::Writer::open(&journal::path_for_replays_path(&settings.replays_path))?;
+        if settings.resume.is_none() {
+            journal.append(&journal::Entry::MatchStarted {
+                rng: rng.clone(),
+                last_result,
+            })?;
+        }
+
+        let (round_number, wins, losses, draws) = settings
+            .resume
+            .as_ref()
+            .map(|resume| {
+                (
+                    resume.round_number,
+                    resume.wins,
+                    resume.losses,
+                    resume.draws,
+                )
+            })
+            .unwrap_or((0, 0, 0, 0));
+        let shadow_rng = rng.clone();
+        let rng = std::sync::Arc::new(tokio::sync::Mutex::new(rng));
+        let stall_detector = std::sync::Arc::new(stall::Detector::new(settings.stall_timeout));
         let match_ = std::sync::Arc::new(Self {
             shadow: std::sync::Arc::new(parking_lot::Mutex::new(shadow::Shadow::new(
                 &shadow_rom,
                 &settings.shadow_save_path,
                 settings.match_type,
                 is_offerer,
+                round_number,
                 last_result,
-                rng.clone(),
+                shadow_rng,
             )?)),
             rom,
             hooks,
-            _peer_conn: peer_conn,
+            peer_conn,
             transport: std::sync::Arc::new(tokio::sync::Mutex::new(transport::Transport::new(
                 dc_tx,
+                unreliable_dc_tx,
+                redundancy.clone(),
                 transport_rendezvous_rx,
             ))),
+            redundancy,
             transport_rendezvous_tx: tokio::sync::Mutex::new(Some(transport_rendezvous_tx)),
-            rng: tokio::sync::Mutex::new(rng),
+            rng: rng.clone(),
             cancellation_token: tokio_util::sync::CancellationToken::new(),
             settings,
             round_state: tokio::sync::Mutex::new(RoundState {
-                number: 0,
+                number: round_number,
                 round: None,
                 last_result: Some(last_result),
-                ipc_sender,
+                last_result_is_draw: false,
+                wins,
+                losses,
+                draws,
+                ipc_sender: ipc_sender.clone(),
+                journal,
+                rng,
+                stall_detector: stall_detector.clone(),
             }),
             is_offerer,
             primary_thread_handle,
             round_started_tx,
             round_started_rx: tokio::sync::Mutex::new(round_started_rx),
+            stall_detector,
+            telemetry: std::sync::Arc::new(telemetry::Collector::default()),
+            connection_stats: parking_lot::Mutex::new(None),
         });
         {
             // TODO: Remove cyclical Arc reference.
             let match_ = match_.clone();
             tokio::task::spawn(async move {
                 tokio::select! {
-                    Err(e) = match_.run(dc_rx) => {
+                    Err(e) = match_.run(dc_rx, unreliable_dc_rx) => {
                         log::info!("match thread ending: {:?}", e);
                     }
                     _ = match_.cancelled() => {
@@ -233,6 +403,30 @@ impl Match {
                 log::info!("match thread ended");
             });
         }
+        {
+            let match_ = match_.clone();
+            tokio::task::spawn(async move {
+                tokio::select! {
+                    Err(e) = match_.watch_for_stalls(ipc_sender.clone()) => {
+                        log::info!("stall watcher ending: {:?}", e);
+                    }
+                    _ = match_.cancelled() => {
+                    }
+                }
+            });
+        }
+        {
+            let match_ = match_.clone();
+            tokio::task::spawn(async move {
+                tokio::select! {
+                    Err(e) = match_.report_telemetry(ipc_sender, telemetry_writer) => {
+                        log::info!("telemetry reporter ending: {:?}", e);
+                    }
+                    _ = match_.cancelled() => {
+                    }
+                }
+            });
+        }
         Ok(match_)
     }
 
@@ -260,95 +454,116 @@ impl Match {
 
     pub async fn run(
         &self,
-        mut dc_rx: datachannel_wrapper::DataChannelReceiver,
+        dc_rx: datachannel_wrapper::DataChannelReceiver,
+        unreliable_dc_rx: Option<datachannel_wrapper::DataChannelReceiver>,
     ) -> anyhow::Result<()> {
-        let mut last_round_number = 0;
+        receive_remote_inputs(
+            transport::Receiver::new(dc_rx, unreliable_dc_rx, self.redundancy.clone()),
+            &self.transport,
+            &self.telemetry,
+            &self.transport_rendezvous_tx,
+            &self.round_started_rx,
+            &self.round_state,
+            &self.stall_detector,
+        )
+        .await
+    }
+
+    async fn watch_for_stalls(
+        &self,
+        ipc_sender: std::sync::Arc<parking_lot::Mutex<ipc::Sender>>,
+    ) -> anyhow::Result<()> {
+        let mut interval = tokio::time::interval(std::time::Duration::from_millis(250));
         loop {
-            match protocol::Packet::deserialize(
-                match dc_rx.receive().await {
-                    None => {
-                        log::info!("data channel closed");
-                        break;
-                    }
-                    Some(buf) => buf,
+            interval.tick().await;
+            let (duration, timed_out) = match self.stall_detector.poll() {
+                stall::Status::Ok => {
+                    continue;
                 }
-                .as_slice(),
-            )? {
-                protocol::Packet::Input(input) => {
-                    // We need to sync on the first input so we don't end up wildly out of sync.
-                    if let Some(transport_rendezvous_tx) =
-                        self.transport_rendezvous_tx.lock().await.take()
-                    {
-                        transport_rendezvous_tx.send(()).unwrap();
-                    }
-
-                    // We need to wait for the next round to start to avoid dropping inputs on the floor.
-                    if input.round_number != last_round_number {
-                        let round_number =
-                            if let Some(number) = self.round_started_rx.lock().await.recv().await {
-                                number
-                            } else {
-                                break;
-                            };
-                        assert!(round_number == input.round_number);
-                        last_round_number = input.round_number;
-                    }
-
-                    // We need to wait for the first state to be committed before we can add remote input.
-                    //
-                    // This is because we don't know what tick to add the input at, and the input queue has not been filled up with delay frames yet.
-                    let first_state_committed_rx = {
-                        let mut round_state = self.round_state.lock().await;
-
-                        if input.round_number != round_state.number {
-                            log::error!(
-                                "round number mismatch, dropping input: this is probably bad!"
-                            );
-                            continue;
-                        }
-
-                        let round = match &mut round_state.round {
-                            None => {
-                                log::info!("no round in progress, dropping input");
-                                continue;
-                            }
-                            Some(b) => b,
-                        };
-                        round.first_state_committed_rx.take()
-                    };
-                    if let Some(first_state_committed_rx) = first_state_committed_rx {
-                        first_state_committed_rx.await.unwrap();
-                    }
-
-                    let mut round_state = self.round_state.lock().await;
-                    if input.round_number != round_state.number {
-                        log::error!("round number mismatch, dropping input: this is probably bad!");
-                        continue;
-                    }
-
-                    let round = match &mut round_state.round {
-                        None => {
-                            log::info!("no round in progress, dropping input");
-                            continue;
-                        }
-                        Some(b) => b,
-                    };
-
-                    if !round.iq.can_add_remote_input() {
-                        anyhow::bail!("remote overflowed our input buffer");
-                    }
-
-                    round.add_remote_input(lockstep::PartialInput {
-                        local_tick: input.local_tick,
-                        remote_tick: (input.local_tick as i64 + input.tick_diff as i64) as u32,
-                        joyflags: input.joyflags as u16,
-                    });
+                stall::Status::Warning(duration) => {
+                    log::warn!("no input from remote for {:?}", duration);
+                    (duration, false)
+                }
+                stall::Status::Resumed => {
+                    log::info!("remote input resumed");
+                    (std::time::Duration::ZERO, false)
+                }
+                stall::Status::TimedOut(duration) => {
+                    log::error!("no input from remote for {:?}, giving up", duration);
+                    (duration, true)
                 }
-                p => anyhow::bail!("unknown packet: {:?}", p),
+            };
+
+            ipc_sender
+                .lock()
+                .send(ipc::protos::FromCoreMessage {
+                    which: Some(ipc::protos::from_core_message::Which::StallEv(
+                        ipc::protos::from_core_message::StallEvent {
+                            duration: duration.as_nanos() as u64,
+                            timed_out,
+                        },
+                    )),
+                })
+                .await?;
+
+            if timed_out {
+                // Keep what was played so far: the set can still be resumed from the journal, which will replay this round from the start.
+                self.round_state.lock().await.abort_round().await?;
+                self.cancel();
+                anyhow::bail!("remote stalled for {:?}", duration);
             }
         }
+    }
 
-        Ok(())
+    async fn report_telemetry(
+        &self,
+        ipc_sender: std::sync::Arc<parking_lot::Mutex<ipc::Sender>>,
+        mut writer: Option<telemetry::Writer>,
+    ) -> anyhow::Result<()> {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+
+            let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+            self.transport
+                .lock()
+                .await
+                .send_ping(now.as_nanos() as u64)
+                .await?;
+
+            let sample = self.telemetry.take_sample();
+            if let Some(writer) = writer.as_mut() {
+                writer.write(&sample)?;
+            }
+            ipc_sender
+                .lock()
+                .send(ipc::protos::FromCoreMessage {
+                    which: Some(ipc::protos::from_core_message::Which::TelemetryEv(
+                        sample.to_ipc(),
+                    )),
+                })
+                .await?;
+
+            let connection_stats = telemetry::ConnectionStats {
+                peer_conn: self.peer_conn.stats(),
+                data_channel: self.transport.lock().await.stats(),
+                rtt: self.telemetry.rtt(),
+            };
+            *self.connection_stats.lock() = Some(connection_stats.clone());
+            ipc_sender
+                .lock()
+                .send(ipc::protos::FromCoreMessage {
+                    which: Some(ipc::protos::from_core_message::Which::ConnectionStatsEv(
+                        connection_stats.to_ipc(),
+                    )),
+                })
+                .await?;
+        }
+    }
+
+    /// Returns the connection stats as of the last telemetry sample, if there has been one yet.
+    pub fn connection_stats(&self) -> Option<telemetry::ConnectionStats> {
+        self.connection_stats.lock().clone()
     }
 
     pub async fn lock_round_state(&self) -> tokio::sync::MutexGuard<'_, RoundState> {
@@ -367,8 +582,25 @@ impl Match {
         self.is_offerer
     }
 
+    pub fn local_nickname(&self) -> Option<&str> {
+        self.settings.local_nickname.as_deref()
+    }
+
+    pub fn opponent_nickname(&self) -> Option<&str> {
+        self.settings.opponent_nickname.as_deref()
+    }
+
     pub async fn start_round(self: &std::sync::Arc<Self>) -> anyhow::Result<()> {
         let mut round_state = self.round_state.lock().await;
+        // The shadow has to be resumed from the same round we were, otherwise it will play the wrong side and desync.
+        let shadow_round_number = self.shadow.lock().round_number();
+        if shadow_round_number != round_state.number {
+            anyhow::bail!(
+                "shadow is at round {} but we are at round {}",
+                shadow_round_number,
+                round_state.number
+            );
+        }
         round_state.number += 1;
         let local_player_index = match round_state.last_result.take().unwrap() {
             BattleResult::Win => 0,
@@ -388,6 +620,12 @@ impl Match {
         let replay_file = std::fs::File::create(&replay_filename)?;
         log::info!("opened replay: {}", replay_filename.display());
 
+        let number = round_state.number;
+        round_state.journal.append(&journal::Entry::RoundStarted {
+            number,
+            local_player_index,
+        })?;
+
         log::info!("preparing round state");
 
         let (first_state_committed_local_packet, first_state_committed_rx) =
@@ -415,6 +653,7 @@ impl Match {
             local_player_index,
             current_tick: 0,
             dtick: 0,
+            remote_delay: self.settings.remote_input_delay,
             iq,
             last_committed_remote_input: lockstep::Input {
                 local_tick: 0,
@@ -436,19 +675,186 @@ impl Match {
             primary_thread_handle: self.primary_thread_handle.clone(),
             transport: self.transport.clone(),
             shadow: self.shadow.clone(),
+            telemetry: self.telemetry.clone(),
         });
+        self.stall_detector.arm();
         self.round_started_tx.send(round_state.number).await?;
         log::info!("round has started");
         Ok(())
     }
 }
 
+/// The part of a round that remote inputs go into.
+trait RemoteInputQueue {
+    fn first_state_committed_rx(&mut self) -> &mut Option<tokio::sync::oneshot::Receiver<()>>;
+    fn can_add_remote_input(&self) -> bool;
+    fn add_remote_input(&mut self, input: lockstep::PartialInput);
+}
+
+/// The rounds of a match as far as remote inputs are concerned, so they can be received without an emulator behind them.
+trait RemoteInputRounds {
+    type Round: RemoteInputQueue;
+
+    fn number(&self) -> u8;
+    fn round_mut(&mut self) -> Option<&mut Self::Round>;
+}
+
+impl RemoteInputQueue for Round {
+    fn first_state_committed_rx(&mut self) -> &mut Option<tokio::sync::oneshot::Receiver<()>> {
+        &mut self.first_state_committed_rx
+    }
+
+    fn can_add_remote_input(&self) -> bool {
+        self.iq.can_add_remote_input()
+    }
+
+    fn add_remote_input(&mut self, input: lockstep::PartialInput) {
+        Round::add_remote_input(self, input)
+    }
+}
+
+impl RemoteInputRounds for RoundState {
+    type Round = Round;
+
+    fn number(&self) -> u8 {
+        self.number
+    }
+
+    fn round_mut(&mut self) -> Option<&mut Round> {
+        self.round.as_mut()
+    }
+}
+
+/// Hands inputs from the remote to the rounds they're for, until the reliable channel closes.
+async fn receive_remote_inputs<S: RemoteInputRounds>(
+    mut receiver: transport::Receiver,
+    transport: &tokio::sync::Mutex<transport::Transport>,
+    telemetry: &telemetry::Collector,
+    transport_rendezvous_tx: &tokio::sync::Mutex<Option<tokio::sync::oneshot::Sender<()>>>,
+    round_started_rx: &tokio::sync::Mutex<tokio::sync::mpsc::Receiver<u8>>,
+    round_state: &tokio::sync::Mutex<S>,
+    stall_detector: &stall::Detector,
+) -> anyhow::Result<()> {
+    loop {
+        let (input, reliable) = match receiver.receive().await? {
+            None => break,
+            Some(transport::Received::Input(input, reliable)) => (input, reliable),
+            Some(transport::Received::Ping(ts)) => {
+                transport.lock().await.send_pong(ts).await?;
+                continue;
+            }
+            Some(transport::Received::Pong(ts)) => {
+                let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+                let then = std::time::Duration::from_nanos(ts);
+                telemetry.add_rtt(now.saturating_sub(then));
+                continue;
+            }
+        };
+
+        // We need to sync on the first input so we don't end up wildly out of sync.
+        if let Some(transport_rendezvous_tx) = transport_rendezvous_tx.lock().await.take() {
+            transport_rendezvous_tx.send(()).unwrap();
+        }
+
+        // We need to wait for the next round to start to avoid dropping inputs on the floor.
+        //
+        // Only inputs from the reliable channel get here for the next round, so this never holds up the reliable channel.
+        if input.round_number != receiver.round_number() {
+            let round_number = if let Some(number) = round_started_rx.lock().await.recv().await {
+                number
+            } else {
+                return Ok(());
+            };
+            assert!(round_number == input.round_number);
+            receiver.start_round(round_number);
+            if input.local_tick != 0 {
+                anyhow::bail!(
+                    "first input for round {} was for tick {}, not 0",
+                    input.round_number,
+                    input.local_tick
+                );
+            }
+        }
+
+        // We need to wait for the first state to be committed before we can add remote input.
+        //
+        // This is because we don't know what tick to add the input at, and the input queue has not been filled up with delay frames yet.
+        let first_state_committed_rx = {
+            let mut round_state = round_state.lock().await;
+
+            if input.round_number != round_state.number() {
+                log::error!("round number mismatch, dropping input: this is probably bad!");
+                continue;
+            }
+
+            let round = match round_state.round_mut() {
+                None => {
+                    log::info!("no round in progress, dropping input");
+                    continue;
+                }
+                Some(b) => b,
+            };
+            if reliable {
+                round.first_state_committed_rx().take()
+            } else {
+                // Inputs from the unreliable channel can't wait: the same input will turn up over the reliable channel anyway.
+                match round
+                    .first_state_committed_rx()
+                    .as_mut()
+                    .map(|rx| rx.try_recv())
+                {
+                    None | Some(Ok(())) => {
+                        *round.first_state_committed_rx() = None;
+                        None
+                    }
+                    Some(Err(_)) => {
+                        continue;
+                    }
+                }
+            }
+        };
+        if let Some(first_state_committed_rx) = first_state_committed_rx {
+            first_state_committed_rx.await.unwrap();
+        }
+
+        let mut round_state = round_state.lock().await;
+        if input.round_number != round_state.number() {
+            log::error!("round number mismatch, dropping input: this is probably bad!");
+            continue;
+        }
+
+        let round = match round_state.round_mut() {
+            None => {
+                log::info!("no round in progress, dropping input");
+                continue;
+            }
+            Some(b) => b,
+        };
+
+        if !round.can_add_remote_input() {
+            anyhow::bail!("remote overflowed our input buffer");
+        }
+
+        round.add_remote_input(lockstep::PartialInput {
+            local_tick: input.local_tick,
+            remote_tick: (input.local_tick as i64 + input.tick_diff as i64) as u32,
+            joyflags: input.joyflags as u16,
+        });
+
+        receiver.mark_received();
+        stall_detector.mark_remote_input();
+    }
+
+    Ok(())
+}
+
 pub struct Round {
     hooks: &'static Box<dyn hooks::Hooks + Send + Sync>,
     number: u8,
     local_player_index: u8,
     current_tick: u32,
     dtick: i32,
+    remote_delay: u32,
     iq: lockstep::PairQueue<lockstep::PartialInput, lockstep::PartialInput>,
     last_committed_remote_input: lockstep::Input,
     first_state_committed_local_packet: Option<tokio::sync::oneshot::Sender<()>>,
@@ -460,9 +866,14 @@ pub struct Round {
     primary_thread_handle: mgba::thread::Handle,
     transport: std::sync::Arc<tokio::sync::Mutex<transport::Transport>>,
     shadow: std::sync::Arc<parking_lot::Mutex<shadow::Shadow>>,
+    telemetry: std::sync::Arc<telemetry::Collector>,
 }
 
 impl Round {
+    pub fn number(&self) -> u8 {
+        self.number
+    }
+
     pub fn current_tick(&self) -> u32 {
         self.current_tick
     }
@@ -546,6 +957,7 @@ impl Round {
 
         let commit_tick = last_committed_state.tick + committable.len() as u32;
         let dirty_tick = commit_tick + predict_required.len() as u32 - 1;
+        let predicted_frames = predict_required.len() as u32;
 
         let input_pairs = committable
             .into_iter()
@@ -578,6 +990,7 @@ impl Round {
             }))
             .collect::<Vec<lockstep::Pair<lockstep::PartialInput, lockstep::PartialInput>>>();
         let last_local_input = input_pairs.last().unwrap().local.clone();
+        let rollback_depth = input_pairs.len() as u32;
 
         let ff_result = self.replayer.fastforward(
             &last_committed_state.state,
@@ -633,13 +1046,18 @@ impl Round {
             .expect("load dirty state");
         self.committed_state = Some(ff_result.committed_state);
 
-        self.dtick = last_local_input.lag() - self.last_committed_remote_input.lag();
+        // If the two sides are running with different delays, the lags will be offset from each other by the difference even when perfectly in sync.
+        self.dtick = last_local_input.lag() - self.last_committed_remote_input.lag()
+            + (self.local_delay() as i32 - self.remote_delay as i32);
 
         core.gba_mut()
             .sync_mut()
             .expect("set fps target")
             .set_fps_target(game::EXPECTED_FPS as f32 + self.tps_adjustment());
 
+        self.telemetry
+            .add_tick(self, predicted_frames, rollback_depth);
+
         let round_result = if let Some(round_result) = ff_result.round_result {
             round_result
         } else {
@@ -682,6 +1100,14 @@ impl Round {
         self.iq.local_delay()
     }
 
+    pub fn remote_delay(&self) -> u32 {
+        self.remote_delay
+    }
+
+    pub fn dtick(&self) -> i32 {
+        self.dtick
+    }
+
     pub fn local_queue_length(&self) -> usize {
         self.iq.local_queue_length()
     }
@@ -714,3 +1140,256 @@ impl Drop for Round {
             .set_fps_target(game::EXPECTED_FPS as f32);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestRound {
+        iq: lockstep::PairQueue<lockstep::PartialInput, lockstep::PartialInput>,
+        first_state_committed_rx: Option<tokio::sync::oneshot::Receiver<()>>,
+    }
+
+    impl RemoteInputQueue for TestRound {
+        fn first_state_committed_rx(&mut self) -> &mut Option<tokio::sync::oneshot::Receiver<()>> {
+            &mut self.first_state_committed_rx
+        }
+
+        fn can_add_remote_input(&self) -> bool {
+            self.iq.can_add_remote_input()
+        }
+
+        fn add_remote_input(&mut self, input: lockstep::PartialInput) {
+            self.iq.add_remote_input(input)
+        }
+    }
+
+    struct TestRoundState {
+        number: u8,
+        round: Option<TestRound>,
+    }
+
+    impl RemoteInputRounds for TestRoundState {
+        type Round = TestRound;
+
+        fn number(&self) -> u8 {
+            self.number
+        }
+
+        fn round_mut(&mut self) -> Option<&mut TestRound> {
+            self.round.as_mut()
+        }
+    }
+
+    fn round_state(
+        number: u8,
+        max_queue_length: usize,
+        first_state_committed_rx: Option<tokio::sync::oneshot::Receiver<()>>,
+    ) -> TestRoundState {
+        TestRoundState {
+            number,
+            round: Some(TestRound {
+                iq: lockstep::PairQueue::new(max_queue_length, 0),
+                first_state_committed_rx,
+            }),
+        }
+    }
+
+    fn input(round_number: u8, local_tick: u32) -> protocol::Input {
+        protocol::Input {
+            round_number,
+            local_tick,
+            tick_diff: 0,
+            joyflags: local_tick as u16,
+        }
+    }
+
+    /// Gives everything that's ready a chance to run.
+    async fn settle() {
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+
+    /// Receives remote inputs into the given rounds in the background, with the remote's ends of both data channels to send them over.
+    struct Harness {
+        dc: datachannel_wrapper::mock::Remote,
+        unreliable_dc: datachannel_wrapper::mock::Remote,
+        round_started_tx: tokio::sync::mpsc::Sender<u8>,
+        round_state: std::sync::Arc<tokio::sync::Mutex<TestRoundState>>,
+        task: tokio::task::JoinHandle<anyhow::Result<()>>,
+    }
+
+    impl Harness {
+        fn start(receiver_round_number: u8, round_state: TestRoundState) -> Harness {
+            let (dc, mut remote_dc) = datachannel_wrapper::mock::new(64);
+            let (unreliable_dc, mut remote_unreliable_dc) = datachannel_wrapper::mock::new(64);
+            remote_dc.open();
+            remote_unreliable_dc.open();
+            let (dc_tx, dc_rx) = dc.split();
+            let (unreliable_dc_tx, unreliable_dc_rx) = unreliable_dc.split();
+            let redundancy = std::sync::Arc::new(transport::Redundancy::default());
+            let (transport_rendezvous_tx, transport_rendezvous_rx) =
+                tokio::sync::oneshot::channel();
+            let (round_started_tx, round_started_rx) = tokio::sync::mpsc::channel(1);
+            let round_state = std::sync::Arc::new(tokio::sync::Mutex::new(round_state));
+
+            let mut receiver =
+                transport::Receiver::new(dc_rx, Some(unreliable_dc_rx), redundancy.clone());
+            receiver.start_round(receiver_round_number);
+            let task = tokio::task::spawn({
+                let round_state = round_state.clone();
+                async move {
+                    receive_remote_inputs(
+                        receiver,
+                        &tokio::sync::Mutex::new(transport::Transport::new(
+                            dc_tx,
+                            Some(unreliable_dc_tx),
+                            redundancy,
+                            transport_rendezvous_rx,
+                        )),
+                        &telemetry::Collector::default(),
+                        &tokio::sync::Mutex::new(Some(transport_rendezvous_tx)),
+                        &tokio::sync::Mutex::new(round_started_rx),
+                        &*round_state,
+                        &stall::Detector::new(std::time::Duration::from_secs(60)),
+                    )
+                    .await
+                }
+            });
+
+            Harness {
+                dc: remote_dc,
+                unreliable_dc: remote_unreliable_dc,
+                round_started_tx,
+                round_state,
+                task,
+            }
+        }
+
+        async fn send_reliable(&self, input: protocol::Input) {
+            self.dc
+                .deliver(&protocol::Packet::Input(input).serialize().unwrap())
+                .await
+                .unwrap();
+        }
+
+        async fn send_unreliable(&self, input: protocol::Input) {
+            self.unreliable_dc
+                .deliver(
+                    &protocol::Packet::RedundantInputs(protocol::RedundantInputs {
+                        ack_round_number: 0,
+                        ack_tick: 0,
+                        inputs: vec![input],
+                    })
+                    .serialize()
+                    .unwrap(),
+                )
+                .await
+                .unwrap();
+        }
+
+        async fn remote_queue_length(&self) -> usize {
+            self.round_state
+                .lock()
+                .await
+                .round
+                .as_ref()
+                .unwrap()
+                .iq
+                .remote_queue_length()
+        }
+
+        async fn wait_for_remote_queue_length(&self, n: usize) {
+            tokio::time::timeout(std::time::Duration::from_secs(5), async {
+                while self.remote_queue_length().await < n {
+                    tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+                }
+            })
+            .await
+            .expect("timed out");
+            assert_eq!(self.remote_queue_length().await, n);
+        }
+
+        async fn finish(self) -> anyhow::Result<()> {
+            tokio::time::timeout(std::time::Duration::from_secs(5), self.task)
+                .await
+                .expect("timed out")
+                .unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn inputs_for_next_round_wait_for_it_to_start() {
+        let harness = Harness::start(0, round_state(1, 8, None));
+
+        harness.send_reliable(input(1, 0)).await;
+        settle().await;
+        assert_eq!(harness.remote_queue_length().await, 0);
+
+        harness.round_started_tx.send(1).await.unwrap();
+        harness.wait_for_remote_queue_length(1).await;
+    }
+
+    #[tokio::test]
+    async fn next_round_must_start_from_first_tick() {
+        let harness = Harness::start(0, round_state(1, 8, None));
+        harness.round_started_tx.send(1).await.unwrap();
+
+        harness.send_reliable(input(1, 3)).await;
+        assert!(harness.finish().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn only_reliable_inputs_wait_for_first_committed_state() {
+        let (first_state_committed_tx, first_state_committed_rx) = tokio::sync::oneshot::channel();
+        let harness = Harness::start(1, round_state(1, 8, Some(first_state_committed_rx)));
+
+        // The unreliable copy can't hold things up, so it's dropped...
+        harness.send_unreliable(input(1, 0)).await;
+        settle().await;
+        assert_eq!(harness.remote_queue_length().await, 0);
+
+        // ...while the reliable copy waits.
+        harness.send_reliable(input(1, 0)).await;
+        settle().await;
+        assert_eq!(harness.remote_queue_length().await, 0);
+
+        first_state_committed_tx.send(()).unwrap();
+        harness.wait_for_remote_queue_length(1).await;
+
+        // From then on, unreliable inputs go straight in.
+        harness.send_unreliable(input(1, 1)).await;
+        harness.wait_for_remote_queue_length(2).await;
+    }
+
+    #[tokio::test]
+    async fn unreliable_input_takes_already_committed_first_state() {
+        let (first_state_committed_tx, first_state_committed_rx) = tokio::sync::oneshot::channel();
+        first_state_committed_tx.send(()).unwrap();
+        let harness = Harness::start(1, round_state(1, 8, Some(first_state_committed_rx)));
+
+        harness.send_unreliable(input(1, 0)).await;
+        harness.wait_for_remote_queue_length(1).await;
+        assert!(harness
+            .round_state
+            .lock()
+            .await
+            .round
+            .as_ref()
+            .unwrap()
+            .first_state_committed_rx
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn remote_overflowing_input_queue_fails() {
+        let harness = Harness::start(1, round_state(1, 2, None));
+
+        for tick in 0..3 {
+            harness.send_reliable(input(1, tick)).await;
+        }
+        assert_eq!(
+            harness.finish().await.unwrap_err().to_string(),
+            "remote overflowed our input buffer"
+        );
+    }
+}
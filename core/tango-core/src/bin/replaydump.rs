@@ -116,7 +116,7 @@ fn dump_video(args: VideoCli, replay: tango_core::replay::Replay) -> Result<(),
     let mut core = mgba::core::Core::new_gba("tango_core")?;
     core.enable_video_buffer();
 
-    let rom = std::fs::read(&args.rom_path)?;
+    let rom = tango_core::patch::normalize_revision(std::fs::read(&args.rom_path)?)?;
     let vf = mgba::vfile::VFile::open_memory(&rom);
     core.as_mut().load_rom(vf)?;
 
@@ -296,7 +296,7 @@ fn dump_wram(_args: WRAMCli, replay: tango_core::replay::Replay) -> Result<(), a
 
 fn dump_step(args: StepCli, replay: tango_core::replay::Replay) -> Result<(), anyhow::Error> {
     let mut core = mgba::core::Core::new_gba("tango_core")?;
-    let rom = std::fs::read(&args.rom_path)?;
+    let rom = tango_core::patch::normalize_revision(std::fs::read(&args.rom_path)?)?;
     let vf = mgba::vfile::VFile::open_memory(&rom);
     core.as_mut().load_rom(vf)?;
     core.as_mut().reset();
@@ -425,7 +425,7 @@ fn dump_input_info(
 
 fn dump_eval(args: EvalCli, replay: tango_core::replay::Replay) -> Result<(), anyhow::Error> {
     let mut core = mgba::core::Core::new_gba("tango_core")?;
-    let rom = std::fs::read(&args.rom_path)?;
+    let rom = tango_core::patch::normalize_revision(std::fs::read(&args.rom_path)?)?;
     let vf = mgba::vfile::VFile::open_memory(&rom);
     core.as_mut().load_rom(vf)?;
     core.as_mut().reset();
@@ -40,7 +40,7 @@ fn main() -> Result<(), anyhow::Error> {
 
     let mut core = mgba::core::Core::new_gba("tango_core")?;
 
-    let rom = std::fs::read(&args.rom_path)?;
+    let rom = tango_core::patch::normalize_revision(std::fs::read(&args.rom_path)?)?;
     let vf = mgba::vfile::VFile::open_memory(&rom);
     core.as_mut().load_rom(vf)?;
 
@@ -39,3 +39,42 @@ pub fn layout_paragraph<F, SF>(
         target.push(glyph);
     }
 }
+
+pub fn render_line<F, SF>(font: SF, text: &str) -> Option<(Vec<u8>, u32, u32)>
+where
+    F: Font,
+    SF: ScaleFont<F> + Copy,
+{
+    let mut glyphs = Vec::new();
+    layout_paragraph(font, ab_glyph::point(0.0, 0.0), 9999.0, text, &mut glyphs);
+
+    let height = font.height().ceil() as i32;
+    let width = {
+        let min_x = glyphs.first()?.position.x;
+        let last_glyph = glyphs.last()?;
+        let max_x = last_glyph.position.x + font.h_advance(last_glyph.id);
+        (max_x - min_x).ceil() as i32
+    };
+
+    let mut buf = vec![0u8; (width * height * 4) as usize];
+    for i in 0..(width * height) as usize {
+        buf[i * 4 + 3] = 0xff;
+    }
+    for glyph in glyphs {
+        if let Some(outlined) = font.outline_glyph(glyph) {
+            let bounds = outlined.px_bounds();
+            outlined.draw(|x, y, v| {
+                let x = x as i32 + bounds.min.x as i32;
+                let y = y as i32 + bounds.min.y as i32;
+                if x >= width || y >= height || x < 0 || y < 0 {
+                    return;
+                }
+                let gray = (v * 0xff as f32) as u8;
+                buf[((y * width + x) * 4) as usize + 0] = gray;
+                buf[((y * width + x) * 4) as usize + 1] = gray;
+                buf[((y * width + x) * 4) as usize + 2] = gray;
+            });
+        }
+    }
+    Some((buf, width as u32, height as u32))
+}
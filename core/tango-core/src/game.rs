@@ -1,4 +1,4 @@
-use crate::{audio, battle, font, input, ipc, session, stats, video};
+use crate::{audio, battle, font, gdbstub, input, ipc, patch, session, stats, video};
 use ab_glyph::{Font, ScaleFont};
 use parking_lot::Mutex;
 use std::sync::Arc;
@@ -8,12 +8,16 @@ pub const EXPECTED_FPS: f32 = 60.0;
 pub fn run(
     rt: tokio::runtime::Runtime,
     ipc_sender: Arc<Mutex<ipc::Sender>>,
+    mut ipc_receiver: ipc::Receiver,
     window_title: String,
     input_mapping: input::Mapping,
     rom_path: std::path::PathBuf,
+    rom_patches: patch::Chain,
     save_path: std::path::PathBuf,
     window_scale: u32,
     video_filter: Box<dyn video::Filter>,
+    show_scoreboard: bool,
+    gdb_stub: Option<Arc<gdbstub::Stub>>,
     match_init: Option<battle::MatchInit>,
 ) -> Result<(), anyhow::Error> {
     let handle = rt.handle().clone();
@@ -98,13 +102,38 @@ pub fn run(
             audio_cb.clone(),
             audio_device.spec().clone(),
             rom_path,
+            rom_patches,
             save_path,
             emu_tps_counter.clone(),
+            gdb_stub,
             match_init,
         )?);
 
         let mut event_loop = sdl.event_pump().unwrap();
 
+        let (set_cheats_tx, mut set_cheats_rx) = tokio::sync::mpsc::unbounded_channel();
+        handle.spawn(async move {
+            loop {
+                let msg = match ipc_receiver.receive().await {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        log::info!("ipc receiver closed: {}", e);
+                        return;
+                    }
+                };
+                match msg.which {
+                    Some(ipc::protos::to_core_message::Which::SetCheatsReq(set_cheats_req)) => {
+                        if set_cheats_tx.send(set_cheats_req).is_err() {
+                            return;
+                        }
+                    }
+                    p => {
+                        log::warn!("ignoring unexpected ipc request: {:?}", p);
+                    }
+                }
+            }
+        });
+
         rt.block_on(async {
             ipc_sender
                 .lock()
@@ -123,6 +152,9 @@ pub fn run(
         let mut show_debug_pressed = false;
         let mut show_debug = false;
 
+        let mut save_state_pressed = vec![false; input_mapping.save_state.len()];
+        let mut load_state_pressed = vec![false; input_mapping.load_state.len()];
+
         'toplevel: loop {
             // Handle events.
             for event in event_loop.poll_iter() {
@@ -208,8 +240,14 @@ pub fn run(
             }
 
             if let Some(session) = &current_session {
-                // If we're in single-player mode, allow speedup.
+                // If we're in single-player mode, allow speedup and rewinding.
                 if session.match_().is_none() {
+                    session.set_rewinding(
+                        input_mapping
+                            .rewind
+                            .iter()
+                            .any(|c| c.is_active(&input_state)),
+                    );
                     session.set_fps(
                         if input_mapping
                             .speed_up
@@ -223,6 +261,57 @@ pub fn run(
                     );
                 }
 
+                // Save states are saved and loaded on the press of a hotkey, not while it's held.
+                for (slot, (pressed, inputs)) in save_state_pressed
+                    .iter_mut()
+                    .zip(input_mapping.save_state.iter())
+                    .enumerate()
+                {
+                    let last_pressed = *pressed;
+                    *pressed = inputs.iter().any(|c| c.is_active(&input_state));
+                    if *pressed && !last_pressed {
+                        match session.save_state(slot) {
+                            Ok(()) => log::info!("saved state to slot {}", slot),
+                            Err(e) => log::error!("failed to save state to slot {}: {}", slot, e),
+                        }
+                    }
+                }
+                for (slot, (pressed, inputs)) in load_state_pressed
+                    .iter_mut()
+                    .zip(input_mapping.load_state.iter())
+                    .enumerate()
+                {
+                    let last_pressed = *pressed;
+                    *pressed = inputs.iter().any(|c| c.is_active(&input_state));
+                    if *pressed && !last_pressed {
+                        match session.load_state(slot) {
+                            Ok(()) => log::info!("loaded state from slot {}", slot),
+                            Err(e) => {
+                                log::error!("failed to load state from slot {}: {}", slot, e)
+                            }
+                        }
+                    }
+                }
+
+                while let Ok(set_cheats_req) = set_cheats_rx.try_recv() {
+                    let cheats = set_cheats_req
+                        .cheats
+                        .iter()
+                        .filter_map(|cheat| match parse_cheat(cheat) {
+                            Ok(cheat) => Some(cheat),
+                            Err(e) => {
+                                log::error!("failed to parse cheat: {}", e);
+                                None
+                            }
+                        })
+                        .collect::<Vec<_>>();
+                    let num_cheats = cheats.len();
+                    match session.set_cheats(cheats) {
+                        Ok(()) => log::info!("{} cheat(s) active", num_cheats),
+                        Err(e) => log::error!("failed to set cheats: {}", e),
+                    }
+                }
+
                 // If we've crashed, log the error and panic.
                 if let Some(thread_handle) = session.has_crashed() {
                     // HACK: No better way to lock the core.
@@ -286,18 +375,13 @@ pub fn run(
                     (mgba::gba::SCREEN_WIDTH * scaling_factor) as u32,
                     (mgba::gba::SCREEN_HEIGHT * scaling_factor) as u32,
                 );
-                canvas
-                    .copy(
-                        &texture,
-                        None,
-                        sdl2::rect::Rect::new(
-                            viewport.x() + (viewport.width() as i32 - new_width as i32) / 2,
-                            viewport.y() + (viewport.height() as i32 - new_height as i32) / 2,
-                            new_width,
-                            new_height,
-                        ),
-                    )
-                    .unwrap();
+                let game_rect = sdl2::rect::Rect::new(
+                    viewport.x() + (viewport.width() as i32 - new_width as i32) / 2,
+                    viewport.y() + (viewport.height() as i32 - new_height as i32) / 2,
+                    new_width,
+                    new_height,
+                );
+                canvas.copy(&texture, None, game_rect).unwrap();
 
                 // Update title to show P1/P2 state.
                 let mut title = title_prefix.to_string();
@@ -313,6 +397,107 @@ pub fn run(
                 }
                 canvas.window_mut().set_title(&title).unwrap();
 
+                if show_scoreboard {
+                    if let Some(match_) = session.match_().as_ref() {
+                        let (nameplates, scoreboard) = handle.block_on(async {
+                            let match_ = match_.lock().await;
+                            let match_ = match &*match_ {
+                                Some(match_) => match_,
+                                None => {
+                                    return (None, None);
+                                }
+                            };
+                            let local_nickname = match_.local_nickname().unwrap_or("???");
+                            let opponent_nickname = match_.opponent_nickname().unwrap_or("???");
+                            let round_state = match_.lock_round_state().await;
+                            if let Some(round) = round_state.round.as_ref() {
+                                let local = format!(
+                                    "P{} {}",
+                                    round.local_player_index() + 1,
+                                    local_nickname
+                                );
+                                let remote = format!(
+                                    "P{} {}",
+                                    round.remote_player_index() + 1,
+                                    opponent_nickname
+                                );
+                                (
+                                    Some(if round.local_player_index() == 0 {
+                                        (local, remote)
+                                    } else {
+                                        (remote, local)
+                                    }),
+                                    None,
+                                )
+                            } else if round_state.number > 0 {
+                                let mut scoreboard = format!(
+                                    "Round {}: {} {} - {} {}",
+                                    round_state.number,
+                                    local_nickname,
+                                    round_state.wins,
+                                    round_state.losses,
+                                    opponent_nickname
+                                );
+                                if round_state.draws > 0 {
+                                    scoreboard.push_str(&format!(" ({} drawn)", round_state.draws));
+                                }
+                                (None, Some(scoreboard))
+                            } else {
+                                (None, None)
+                            }
+                        });
+
+                        if let Some((p1, p2)) = nameplates {
+                            if let Some((buf, width, height)) = font::render_line(scaled_font, &p1)
+                            {
+                                draw_text(
+                                    &mut canvas,
+                                    &texture_creator,
+                                    &buf,
+                                    sdl2::rect::Rect::new(
+                                        game_rect.left(),
+                                        game_rect.bottom() - height as i32,
+                                        width,
+                                        height,
+                                    ),
+                                );
+                            }
+                            if let Some((buf, width, height)) = font::render_line(scaled_font, &p2)
+                            {
+                                draw_text(
+                                    &mut canvas,
+                                    &texture_creator,
+                                    &buf,
+                                    sdl2::rect::Rect::new(
+                                        game_rect.right() - width as i32,
+                                        game_rect.bottom() - height as i32,
+                                        width,
+                                        height,
+                                    ),
+                                );
+                            }
+                        }
+
+                        if let Some(scoreboard) = scoreboard {
+                            if let Some((buf, width, height)) =
+                                font::render_line(scaled_font, &scoreboard)
+                            {
+                                draw_text(
+                                    &mut canvas,
+                                    &texture_creator,
+                                    &buf,
+                                    sdl2::rect::Rect::new(
+                                        game_rect.center().x() - width as i32 / 2,
+                                        game_rect.center().y() - height as i32 / 2,
+                                        width,
+                                        height,
+                                    ),
+                                );
+                            }
+                        }
+                    }
+                }
+
                 // TODO: Figure out why moving this into its own function locks fps to tps.
                 if show_debug {
                     let mut lines = vec![format!(
@@ -324,6 +509,34 @@ pub fn run(
                         handle.block_on(async {
                             if let Some(match_) = &*match_.lock().await {
                                 lines.push("match active".to_string());
+                                if let Some(connection_stats) = match_.connection_stats() {
+                                    lines.push(format!(
+                                        "route: {} (rtt = {:3}ms)",
+                                        connection_stats.route(),
+                                        connection_stats
+                                            .rtt
+                                            .map(|rtt| rtt.as_millis())
+                                            .unwrap_or(0),
+                                    ));
+                                    if let (Some(local_candidate), Some(remote_candidate)) = (
+                                        connection_stats.peer_conn.local_candidate.as_ref(),
+                                        connection_stats.peer_conn.remote_candidate.as_ref(),
+                                    ) {
+                                        lines.push(format!(
+                                            "pair: {} ({:?}) <-> {} ({:?})",
+                                            local_candidate.address,
+                                            local_candidate.candidate_type,
+                                            remote_candidate.address,
+                                            remote_candidate.candidate_type,
+                                        ));
+                                    }
+                                    lines.push(format!(
+                                        "tx/rx: {} / {} bytes (buffered = {})",
+                                        connection_stats.data_channel.bytes_sent,
+                                        connection_stats.data_channel.bytes_received,
+                                        connection_stats.data_channel.buffered_amount,
+                                    ));
+                                }
                                 let round_state = match_.lock_round_state().await;
                                 if let Some(round) = round_state.round.as_ref() {
                                     lines.push(format!("current tick: {:4}", round.current_tick()));
@@ -332,10 +545,11 @@ pub fn run(
                                         round.local_player_index()
                                     ));
                                     lines.push(format!(
-                                        "qlen: {:2} vs {:2} (delay = {:1})",
+                                        "qlen: {:2} vs {:2} (delay = {:1} vs {:1})",
                                         round.local_queue_length(),
                                         round.remote_queue_length(),
                                         round.local_delay(),
+                                        round.remote_delay(),
                                     ));
                                     round.tps_adjustment()
                                 } else {
@@ -431,3 +645,44 @@ pub fn run(
     log::info!("goodbye");
     Ok(())
 }
+
+fn parse_cheat(
+    cheat: &ipc::protos::to_core_message::set_cheats_request::Cheat,
+) -> anyhow::Result<mgba::cheats::Cheat> {
+    mgba::cheats::Cheat::parse(
+        match cheat.format() {
+            ipc::protos::to_core_message::set_cheats_request::cheat::Format::Raw => {
+                mgba::cheats::Format::Raw
+            }
+            ipc::protos::to_core_message::set_cheats_request::cheat::Format::CodeBreaker => {
+                mgba::cheats::Format::CodeBreaker
+            }
+            ipc::protos::to_core_message::set_cheats_request::cheat::Format::GameShark => {
+                mgba::cheats::Format::GameShark
+            }
+            ipc::protos::to_core_message::set_cheats_request::cheat::Format::ActionReplay => {
+                mgba::cheats::Format::ActionReplay
+            }
+        },
+        &cheat.code,
+    )
+}
+
+fn draw_text(
+    canvas: &mut sdl2::render::Canvas<sdl2::video::Window>,
+    texture_creator: &sdl2::render::TextureCreator<sdl2::video::WindowContext>,
+    buf: &[u8],
+    rect: sdl2::rect::Rect,
+) {
+    let mut texture = texture_creator
+        .create_texture_streaming(
+            sdl2::pixels::PixelFormatEnum::ABGR8888,
+            rect.width(),
+            rect.height(),
+        )
+        .unwrap();
+    texture
+        .update(None, buf, (rect.width() * 4) as usize)
+        .unwrap();
+    canvas.copy(&texture, None, Some(rect)).unwrap();
+}
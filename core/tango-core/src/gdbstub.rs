@@ -0,0 +1,495 @@
+// Signals reported to GDB when we stop.
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+// GDB's default ARM register layout is r0-r15, then f0-f7 and fps from the FPA, then cpsr.
+const NUM_GPRS: usize = 16;
+const NUM_REGISTERS: usize = 26;
+
+enum Event {
+    Connected,
+    Packet(String),
+    Interrupt,
+    Disconnected,
+}
+
+struct Stopped {
+    breakpoints: std::collections::HashSet<u32>,
+    // Keyed by the Z packet type, address and length, as that's all z packets give us to remove them by.
+    watchpoints: std::collections::HashMap<(u8, u32, u32), u32>,
+}
+
+/// A GDB remote serial protocol server for the primary core.
+///
+/// The core is only ever touched from the emulator thread: when stopped, whether by a breakpoint, a watchpoint or GDB asking us to, the emulator thread blocks and serves GDB's requests itself until GDB tells it to continue.
+pub struct Stub {
+    events_rx: parking_lot::Mutex<std::sync::mpsc::Receiver<Event>>,
+    writer: parking_lot::Mutex<Option<std::net::TcpStream>>,
+    stop_requested: std::sync::atomic::AtomicBool,
+    // Set if GDB asked us to step while stopped at a watchpoint, where we can't step, so we stop at the next frame instead.
+    step_requested: std::sync::atomic::AtomicBool,
+    // Set if GDB has asked us to continue and so is waiting for us to tell it that we've stopped.
+    running: std::sync::atomic::AtomicBool,
+    stopped: parking_lot::Mutex<Stopped>,
+}
+
+impl Stub {
+    pub fn listen(addr: &str) -> anyhow::Result<std::sync::Arc<Self>> {
+        let listener = std::net::TcpListener::bind(addr)?;
+        log::info!("gdb stub listening on {}", listener.local_addr()?);
+
+        let (events_tx, events_rx) = std::sync::mpsc::channel();
+        let stub = std::sync::Arc::new(Stub {
+            events_rx: parking_lot::Mutex::new(events_rx),
+            writer: parking_lot::Mutex::new(None),
+            stop_requested: std::sync::atomic::AtomicBool::new(false),
+            step_requested: std::sync::atomic::AtomicBool::new(false),
+            running: std::sync::atomic::AtomicBool::new(false),
+            stopped: parking_lot::Mutex::new(Stopped {
+                breakpoints: std::collections::HashSet::new(),
+                watchpoints: std::collections::HashMap::new(),
+            }),
+        });
+
+        std::thread::spawn({
+            let stub = stub.clone();
+            move || {
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            log::error!("gdb stub failed to accept connection: {}", e);
+                            continue;
+                        }
+                    };
+                    log::info!("gdb connected from {:?}", stream.peer_addr());
+                    *stub.writer.lock() = match stream.try_clone() {
+                        Ok(writer) => Some(writer),
+                        Err(e) => {
+                            log::error!("gdb stub failed to clone stream: {}", e);
+                            continue;
+                        }
+                    };
+
+                    // GDB expects the target to be stopped as soon as it connects.
+                    stub.stop_requested
+                        .store(true, std::sync::atomic::Ordering::SeqCst);
+                    if events_tx.send(Event::Connected).is_err() {
+                        return;
+                    }
+                    if let Err(e) = read_packets(stream, &stub, &events_tx) {
+                        log::error!("gdb connection error: {}", e);
+                    }
+                    *stub.writer.lock() = None;
+                    if events_tx.send(Event::Disconnected).is_err() {
+                        return;
+                    }
+                    log::info!("gdb disconnected");
+                }
+            }
+        });
+
+        Ok(stub)
+    }
+
+    /// Stops if GDB has asked us to. This should be called once per frame from the emulator thread.
+    pub fn poll(self: &std::sync::Arc<Self>, core: mgba::core::CoreMutRef) {
+        if self
+            .step_requested
+            .swap(false, std::sync::atomic::Ordering::SeqCst)
+        {
+            self.stop_requested
+                .store(false, std::sync::atomic::Ordering::SeqCst);
+            self.stop(core, &format!("S{:02x}", SIGTRAP), true);
+        } else if self
+            .stop_requested
+            .swap(false, std::sync::atomic::Ordering::SeqCst)
+        {
+            self.stop(core, &format!("S{:02x}", SIGINT), true);
+        }
+    }
+
+    fn send(&self, data: &str) {
+        use std::io::Write;
+
+        let mut writer = self.writer.lock();
+        let stream = if let Some(stream) = writer.as_mut() {
+            stream
+        } else {
+            return;
+        };
+        let checksum = data.bytes().fold(0u8, |acc, b| acc.wrapping_add(b));
+        if let Err(e) = write!(stream, "${}#{:02x}", data, checksum) {
+            log::error!("gdb stub failed to send packet: {}", e);
+        }
+    }
+
+    /// Blocks the emulator thread, serving requests from GDB until it tells us to continue.
+    ///
+    /// `stop_reply` is what we tell GDB about why we stopped. If we stopped in the middle of an instruction, i.e. at a watchpoint, `can_step` must be false.
+    fn stop(
+        self: &std::sync::Arc<Self>,
+        mut core: mgba::core::CoreMutRef,
+        stop_reply: &str,
+        can_step: bool,
+    ) {
+        if self
+            .running
+            .swap(false, std::sync::atomic::Ordering::SeqCst)
+        {
+            self.send(stop_reply);
+        }
+
+        // If we're already stopped, we must have hit a breakpoint while stepping, which the step takes care of reporting.
+        let events_rx = if let Some(events_rx) = self.events_rx.try_lock() {
+            events_rx
+        } else {
+            return;
+        };
+        let mut stopped = self.stopped.lock();
+        loop {
+            let packet = match events_rx.recv() {
+                Ok(Event::Packet(packet)) => packet,
+                Ok(Event::Connected) | Ok(Event::Interrupt) => {
+                    // We're already stopped.
+                    self.stop_requested
+                        .store(false, std::sync::atomic::Ordering::SeqCst);
+                    continue;
+                }
+                Ok(Event::Disconnected) | Err(_) => {
+                    self.remove_all_breakpoints(core, &mut stopped);
+                    self.remove_all_watchpoints(core, &mut stopped);
+                    return;
+                }
+            };
+
+            let (command, args) = if packet.is_char_boundary(1) {
+                packet.split_at(1)
+            } else {
+                ("", packet.as_str())
+            };
+            let reply = match command {
+                "?" => stop_reply.to_string(),
+                "g" => read_registers(core),
+                "G" => match write_registers(core, args) {
+                    Some(()) => "OK".to_string(),
+                    None => "E01".to_string(),
+                },
+                "p" => match usize::from_str_radix(args, 16)
+                    .ok()
+                    .and_then(|r| read_register(core, r))
+                {
+                    Some(v) => v,
+                    None => "E01".to_string(),
+                },
+                "P" => match args.split_once('=').and_then(|(r, v)| {
+                    write_register(core, usize::from_str_radix(r, 16).ok()?, parse_hex_u32(v)?)
+                }) {
+                    Some(()) => "OK".to_string(),
+                    None => "E01".to_string(),
+                },
+                "m" => match parse_addr_len(args) {
+                    Some((addr, len)) => (0..len)
+                        .map(|i| format!("{:02x}", core.raw_read_8(addr.wrapping_add(i), -1)))
+                        .collect(),
+                    None => "E01".to_string(),
+                },
+                "M" => match args.split_once(':').and_then(|(addr_len, data)| {
+                    let (addr, len) = parse_addr_len(addr_len)?;
+                    let data = hex::decode(data).ok()?;
+                    if data.len() != len as usize {
+                        return None;
+                    }
+                    Some((addr, data))
+                }) {
+                    Some((addr, data)) => {
+                        core.raw_write_range(addr, -1, &data);
+                        "OK".to_string()
+                    }
+                    None => "E01".to_string(),
+                },
+                "Z" | "z" => match self.handle_breakpoint(core, &mut stopped, command == "Z", args)
+                {
+                    Some(Ok(())) => "OK".to_string(),
+                    Some(Err(e)) => {
+                        log::error!("gdb stub failed to update breakpoint: {}", e);
+                        "E01".to_string()
+                    }
+                    None => "".to_string(),
+                },
+                "s" if !can_step => {
+                    self.running
+                        .store(true, std::sync::atomic::Ordering::SeqCst);
+                    self.step_requested
+                        .store(true, std::sync::atomic::Ordering::SeqCst);
+                    return;
+                }
+                "s" => {
+                    core.step();
+                    format!("S{:02x}", SIGTRAP)
+                }
+                "c" => {
+                    self.running
+                        .store(true, std::sync::atomic::Ordering::SeqCst);
+                    return;
+                }
+                "D" | "k" => {
+                    self.remove_all_breakpoints(core, &mut stopped);
+                    self.remove_all_watchpoints(core, &mut stopped);
+                    self.send("OK");
+                    return;
+                }
+                "H" => "OK".to_string(),
+                "q" => match args.split(':').next().unwrap_or("") {
+                    "Supported" => "PacketSize=4000".to_string(),
+                    "Attached" => "1".to_string(),
+                    "C" => "QC1".to_string(),
+                    "fThreadInfo" => "m1".to_string(),
+                    "sThreadInfo" => "l".to_string(),
+                    _ => "".to_string(),
+                },
+                _ => "".to_string(),
+            };
+            self.send(&reply);
+        }
+    }
+
+    /// Returns None if the breakpoint type isn't supported.
+    fn handle_breakpoint(
+        self: &std::sync::Arc<Self>,
+        mut core: mgba::core::CoreMutRef,
+        stopped: &mut Stopped,
+        insert: bool,
+        args: &str,
+    ) -> Option<anyhow::Result<()>> {
+        let mut parts = args.split(',');
+        let typ = parts.next()?;
+        let addr = u32::from_str_radix(parts.next()?, 16).ok()?;
+        let kind = parts.next()?;
+
+        let watch_kind = match typ {
+            "0" | "1" => None,
+            "2" => Some(mgba::watcher::Kind::Write),
+            "3" => Some(mgba::watcher::Kind::Read),
+            "4" => Some(mgba::watcher::Kind::Access),
+            _ => {
+                return None;
+            }
+        };
+
+        if let Some(watch_kind) = watch_kind {
+            // For watchpoints, the kind is the length of the watched range.
+            let len = u32::from_str_radix(kind, 16).ok()?;
+            let key = (typ.as_bytes()[0] - b'0', addr, len);
+            return Some(if insert {
+                if stopped.watchpoints.contains_key(&key) {
+                    return Some(Ok(()));
+                }
+                let stub = self.clone();
+                core.install_watchpoint(
+                    addr..addr.wrapping_add(len),
+                    watch_kind,
+                    Box::new(move |core, access| {
+                        let name = match watch_kind {
+                            mgba::watcher::Kind::Write => "watch",
+                            mgba::watcher::Kind::Read => "rwatch",
+                            mgba::watcher::Kind::Access => "awatch",
+                        };
+                        stub.stop(
+                            core,
+                            &format!("T{:02x}{}:{:x};", SIGTRAP, name, access.address),
+                            false,
+                        );
+                    }),
+                )
+                .map(|id| {
+                    stopped.watchpoints.insert(key, id);
+                })
+            } else {
+                match stopped.watchpoints.remove(&key) {
+                    Some(id) => core.uninstall_watchpoint(id),
+                    None => Ok(()),
+                }
+            });
+        }
+
+        // Software and hardware breakpoints are both implemented with the trapper, which can only trap Thumb code.
+        if kind != "2" {
+            return Some(Err(anyhow::anyhow!(
+                "only thumb breakpoints are supported, got kind {}",
+                kind
+            )));
+        }
+
+        Some(if insert {
+            if stopped.breakpoints.contains(&addr) {
+                return Some(Ok(()));
+            }
+            let stub = self.clone();
+            core.install_trap(
+                addr,
+                Box::new(move |core| {
+                    stub.stop(core, &format!("S{:02x}", SIGTRAP), true);
+                }),
+            )
+            .map(|()| {
+                stopped.breakpoints.insert(addr);
+            })
+        } else {
+            if !stopped.breakpoints.remove(&addr) {
+                return Some(Ok(()));
+            }
+            core.uninstall_trap(addr)
+        })
+    }
+
+    fn remove_all_breakpoints(&self, mut core: mgba::core::CoreMutRef, stopped: &mut Stopped) {
+        for addr in stopped.breakpoints.drain() {
+            if let Err(e) = core.uninstall_trap(addr) {
+                log::error!("gdb stub failed to remove breakpoint: {}", e);
+            }
+        }
+    }
+
+    fn remove_all_watchpoints(&self, mut core: mgba::core::CoreMutRef, stopped: &mut Stopped) {
+        for (_, id) in stopped.watchpoints.drain() {
+            if let Err(e) = core.uninstall_watchpoint(id) {
+                log::error!("gdb stub failed to remove watchpoint: {}", e);
+            }
+        }
+    }
+}
+
+fn read_packets(
+    stream: std::net::TcpStream,
+    stub: &Stub,
+    events_tx: &std::sync::mpsc::Sender<Event>,
+) -> anyhow::Result<()> {
+    use std::io::{Read, Write};
+
+    let mut reader = std::io::BufReader::new(stream);
+    let mut byte = [0u8; 1];
+    loop {
+        if reader.read(&mut byte)? == 0 {
+            return Ok(());
+        }
+        match byte[0] {
+            b'$' => {}
+            0x03 => {
+                stub.stop_requested
+                    .store(true, std::sync::atomic::Ordering::SeqCst);
+                events_tx.send(Event::Interrupt)?;
+                continue;
+            }
+            // Acks, or garbage.
+            _ => {
+                continue;
+            }
+        }
+
+        let mut packet = vec![];
+        loop {
+            reader.read_exact(&mut byte)?;
+            if byte[0] == b'#' {
+                break;
+            }
+            packet.push(byte[0]);
+        }
+        let mut checksum = [0u8; 2];
+        reader.read_exact(&mut checksum)?;
+
+        let expected_checksum = u8::from_str_radix(std::str::from_utf8(&checksum)?, 16)?;
+        let actual_checksum = packet.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+        if expected_checksum != actual_checksum {
+            reader.get_mut().write_all(b"-")?;
+            continue;
+        }
+        reader.get_mut().write_all(b"+")?;
+
+        events_tx.send(Event::Packet(String::from_utf8(packet)?))?;
+    }
+}
+
+fn hex_u32(v: u32) -> String {
+    hex::encode(v.to_le_bytes())
+}
+
+fn parse_hex_u32(s: &str) -> Option<u32> {
+    Some(u32::from_le_bytes(hex::decode(s).ok()?.try_into().ok()?))
+}
+
+fn parse_addr_len(s: &str) -> Option<(u32, u32)> {
+    let (addr, len) = s.split_once(',')?;
+    Some((
+        u32::from_str_radix(addr, 16).ok()?,
+        u32::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+// As far as GDB is concerned, the PC is the address of the instruction about to be executed.
+fn pc(core: mgba::core::CoreMutRef) -> u32 {
+    let cpu = core.as_ref().gba().cpu();
+    match cpu.execution_mode() {
+        mgba::arm_core::ExecutionMode::ARM => cpu.arm_pc(),
+        mgba::arm_core::ExecutionMode::Thumb => cpu.thumb_pc(),
+    }
+}
+
+fn read_register(core: mgba::core::CoreMutRef, r: usize) -> Option<String> {
+    let cpu = core.as_ref().gba().cpu();
+    Some(match r {
+        0..=14 => hex_u32(cpu.gpr(r) as u32),
+        15 => hex_u32(pc(core)),
+        // The GBA doesn't have an FPA, so f0-f7 and fps are always zero.
+        16..=23 => "00".repeat(12),
+        24 => hex_u32(0),
+        25 => hex_u32(cpu.cpsr()),
+        _ => {
+            return None;
+        }
+    })
+}
+
+fn read_registers(core: mgba::core::CoreMutRef) -> String {
+    (0..NUM_REGISTERS)
+        .map(|r| read_register(core, r).unwrap())
+        .collect()
+}
+
+fn write_register(mut core: mgba::core::CoreMutRef, r: usize, v: u32) -> Option<()> {
+    let cpu = core.gba_mut().cpu_mut();
+    match r {
+        0..=14 => {
+            cpu.set_gpr(r, v as i32);
+        }
+        15 => match cpu.as_ref().execution_mode() {
+            mgba::arm_core::ExecutionMode::Thumb => {
+                cpu.set_thumb_pc(v);
+            }
+            mgba::arm_core::ExecutionMode::ARM => {
+                return None;
+            }
+        },
+        _ => {
+            return None;
+        }
+    }
+    Some(())
+}
+
+fn write_registers(core: mgba::core::CoreMutRef, args: &str) -> Option<()> {
+    // Only the general purpose registers are writable, so ignore the rest.
+    let buf = hex::decode(args).ok()?;
+    if buf.len() < NUM_GPRS * 4 {
+        return None;
+    }
+    for (r, chunk) in buf.chunks_exact(4).take(NUM_GPRS).enumerate() {
+        let v = u32::from_le_bytes(chunk.try_into().unwrap());
+        // Don't jump around unless we're actually being asked to.
+        if r == 15 && v == pc(core) {
+            continue;
+        }
+        write_register(core, r, v)?;
+    }
+    Some(())
+}
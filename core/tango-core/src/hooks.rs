@@ -9,7 +9,14 @@ mod bn6;
 mod exe45;
 
 pub fn get(mut core: mgba::core::CoreMutRef) -> Option<&'static Box<dyn Hooks + Send + Sync>> {
-    Some(match (&core.full_rom_name(), core.rom_revision()) {
+    get_by_rom_id(&core.full_rom_name(), core.rom_revision())
+}
+
+pub fn get_by_rom_id(
+    full_rom_name: &[u8; 16],
+    rom_revision: u8,
+) -> Option<&'static Box<dyn Hooks + Send + Sync>> {
+    Some(match (full_rom_name, rom_revision) {
         (b"MEGAMAN6_FXXBR6E", 0x00) => &bn6::MEGAMAN6_FXXBR6E_00,
         (b"MEGAMAN6_GXXBR5E", 0x00) => &bn6::MEGAMAN6_GXXBR5E_00,
         (b"ROCKEXE6_RXXBR6J", 0x00) => &bn6::ROCKEXE6_RXXBR6J_00,
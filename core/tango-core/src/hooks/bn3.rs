@@ -306,12 +306,7 @@ impl hooks::Hooks for BN3 {
                                 }
                             };
 
-                            let mut round_state = match_.lock_round_state().await;
-                            let result = {
-                                let round = round_state.round.as_ref().expect("round");
-                                round.on_draw_result()
-                            };
-                            round_state.set_last_result(result);
+                            match_.lock_round_state().await.set_draw_result();
                         });
                     }),
                 )
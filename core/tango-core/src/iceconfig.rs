@@ -0,0 +1,24 @@
+use prost::Message;
+
+pub async fn get(addr: &str) -> anyhow::Result<Vec<datachannel_wrapper::IceServer>> {
+    let client = reqwest::Client::builder()
+        .user_agent(format!("tango-core/{}", git_version::git_version!()))
+        .build()?;
+    let resp = client
+        .post(addr)
+        .header("Content-Type", "application/x-protobuf")
+        .body(tango_protos::iceconfig::GetRequest {}.encode_to_vec())
+        .send()
+        .await?
+        .error_for_status()?;
+    let resp = tango_protos::iceconfig::GetResponse::decode(resp.bytes().await?)?;
+    Ok(resp
+        .ice_servers
+        .into_iter()
+        .map(|ice_server| datachannel_wrapper::IceServer {
+            urls: ice_server.urls,
+            username: ice_server.username,
+            credential: ice_server.credential,
+        })
+        .collect())
+}
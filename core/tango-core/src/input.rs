@@ -33,6 +33,11 @@ pub struct Mapping {
     pub select: Vec<PhysicalInput>,
     pub start: Vec<PhysicalInput>,
     pub speed_up: Vec<PhysicalInput>,
+    pub rewind: Vec<PhysicalInput>,
+    /// Indexed by save state slot.
+    pub save_state: Vec<Vec<PhysicalInput>>,
+    /// Indexed by save state slot.
+    pub load_state: Vec<Vec<PhysicalInput>>,
 }
 
 impl Mapping {
@@ -14,8 +14,12 @@ pub struct Sender {
 
 impl Sender {
     pub fn new_from_stdout() -> Self {
+        Self::new_from_writer(tokio::io::stdout())
+    }
+
+    pub fn new_from_writer(writer: impl tokio::io::AsyncWrite + Send + 'static) -> Self {
         Sender {
-            writer: Box::pin(tokio::io::stdout()),
+            writer: Box::pin(writer),
             buf: bytes::BytesMut::new(),
         }
     }
@@ -0,0 +1,137 @@
+use crate::battle;
+use sha3::Digest;
+use std::io::BufRead;
+use std::io::Write;
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Entry {
+    MatchStarted {
+        rng: rand_pcg::Mcg128Xsl64,
+        last_result: battle::BattleResult,
+    },
+    RoundStarted {
+        number: u8,
+        local_player_index: u8,
+    },
+    RoundEnded {
+        number: u8,
+        result: Option<battle::BattleResult>,
+        draw: bool,
+        rng: rand_pcg::Mcg128Xsl64,
+    },
+}
+
+pub fn path_for_replays_path(replays_path: &std::path::Path) -> std::path::PathBuf {
+    let mut path = replays_path.as_os_str().to_owned();
+    path.push(".tangojournal");
+    path.into()
+}
+
+pub struct Writer {
+    file: std::fs::File,
+}
+
+impl Writer {
+    pub fn open(path: &std::path::Path) -> std::io::Result<Self> {
+        Ok(Writer {
+            file: std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?,
+        })
+    }
+
+    pub fn append(&mut self, entry: &Entry) -> anyhow::Result<()> {
+        let mut buf = serde_json::to_vec(entry)?;
+        buf.push(b'\n');
+        self.file.write_all(&buf)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Resume {
+    pub round_number: u8,
+    pub last_result: battle::BattleResult,
+    pub rng: rand_pcg::Mcg128Xsl64,
+    pub wins: u32,
+    pub losses: u32,
+    pub draws: u32,
+}
+
+impl Resume {
+    pub fn read(path: &std::path::Path) -> anyhow::Result<Self> {
+        let f = std::io::BufReader::new(std::fs::File::open(path)?);
+
+        let mut resume: Option<Resume> = None;
+        for line in f.lines() {
+            let line = line?;
+            let entry = match serde_json::from_str::<Entry>(&line) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    // If we crashed while writing, the last line may be truncated: anything we have read up until now is still good.
+                    log::warn!("stopping journal read at bad entry: {}", e);
+                    break;
+                }
+            };
+
+            match entry {
+                Entry::MatchStarted { rng, last_result } => {
+                    resume = Some(Resume {
+                        round_number: 0,
+                        last_result,
+                        rng,
+                        wins: 0,
+                        losses: 0,
+                        draws: 0,
+                    });
+                }
+                Entry::RoundStarted { .. } => {
+                    // A round that started but never ended will just be played again.
+                }
+                Entry::RoundEnded {
+                    number,
+                    result,
+                    draw,
+                    rng,
+                } => {
+                    let resume = match resume.as_mut() {
+                        Some(resume) => resume,
+                        None => {
+                            anyhow::bail!("round ended before match started");
+                        }
+                    };
+                    resume.round_number = number;
+                    resume.rng = rng;
+                    if let Some(result) = result {
+                        resume.last_result = result;
+                        match result {
+                            _ if draw => {
+                                resume.draws += 1;
+                            }
+                            battle::BattleResult::Win => {
+                                resume.wins += 1;
+                            }
+                            battle::BattleResult::Loss => {
+                                resume.losses += 1;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        match resume {
+            Some(resume) => Ok(resume),
+            None => anyhow::bail!("journal has no match"),
+        }
+    }
+
+    pub fn rng_digest(&self) -> Vec<u8> {
+        let mut hasher = sha3::Sha3_256::new();
+        hasher.update(&bincode::serialize(&self.rng).expect("serialize rng"));
+        hasher.finalize().to_vec()
+    }
+}
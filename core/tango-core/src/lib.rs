@@ -5,17 +5,26 @@ pub mod audio;
 pub mod battle;
 pub mod font;
 pub mod game;
+pub mod gdbstub;
 pub mod hooks;
+pub mod iceconfig;
 pub mod input;
 pub mod ipc;
+pub mod journal;
 pub mod lockstep;
 pub mod net;
+pub mod patch;
 pub mod protocol;
+pub mod relay;
 pub mod replay;
 pub mod replayer;
+pub mod rewind;
+pub mod savestate;
 pub mod session;
 pub mod shadow;
 pub mod signaling;
+pub mod stall;
 pub mod stats;
+pub mod telemetry;
 pub mod transport;
 pub mod video;
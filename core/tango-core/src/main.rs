@@ -24,6 +24,12 @@ pub struct InputMapping {
     pub start: Vec<PhysicalInput>,
     #[serde(rename = "speedUp")]
     pub speed_up: Vec<PhysicalInput>,
+    #[serde(default)]
+    pub rewind: Vec<PhysicalInput>,
+    #[serde(rename = "saveState", default)]
+    pub save_state: Vec<Vec<PhysicalInput>>,
+    #[serde(rename = "loadState", default)]
+    pub load_state: Vec<Vec<PhysicalInput>>,
 }
 
 fn parse_physical_input(input: &PhysicalInput) -> Option<tango_core::input::PhysicalInput> {
@@ -56,11 +62,20 @@ struct Cli {
     #[clap(long)]
     signaling_connect_addr: String,
 
+    #[clap(long)]
+    relay_connect_addr: Option<String>,
+
     #[clap(long)]
     ice_servers: Vec<String>,
 
+    #[clap(long)]
+    iceconfig_addr: Option<String>,
+
     #[clap(long)]
     session_id: Option<String>,
+
+    #[clap(long)]
+    gdb_listen_addr: Option<String>,
 }
 
 fn main() -> Result<(), anyhow::Error> {
@@ -74,6 +89,11 @@ fn main() -> Result<(), anyhow::Error> {
 
     let args = Cli::parse();
 
+    // Stopping in the debugger would look like a stall to the remote, so it's only available outside of matches.
+    if args.gdb_listen_addr.is_some() && args.session_id.is_some() {
+        anyhow::bail!("--gdb-listen-addr can't be used with --session-id");
+    }
+
     let raw_input_mapping = serde_json::from_str::<InputMapping>(&args.input_mapping)?;
     let input_mapping = tango_core::input::Mapping {
         up: raw_input_mapping
@@ -131,6 +151,31 @@ fn main() -> Result<(), anyhow::Error> {
             .iter()
             .flat_map(|v| parse_physical_input(v).into_iter().collect::<Vec<_>>())
             .collect(),
+        rewind: raw_input_mapping
+            .rewind
+            .iter()
+            .flat_map(|v| parse_physical_input(v).into_iter().collect::<Vec<_>>())
+            .collect(),
+        save_state: raw_input_mapping
+            .save_state
+            .iter()
+            .take(tango_core::savestate::NUM_SLOTS)
+            .map(|vs| {
+                vs.iter()
+                    .flat_map(|v| parse_physical_input(v).into_iter().collect::<Vec<_>>())
+                    .collect()
+            })
+            .collect(),
+        load_state: raw_input_mapping
+            .load_state
+            .iter()
+            .take(tango_core::savestate::NUM_SLOTS)
+            .map(|vs| {
+                vs.iter()
+                    .flat_map(|v| parse_physical_input(v).into_iter().collect::<Vec<_>>())
+                    .collect()
+            })
+            .collect(),
     };
 
     log::info!("input mapping: {:?}", input_mapping);
@@ -144,11 +189,25 @@ fn main() -> Result<(), anyhow::Error> {
 
     let (start_req, pvp_init) = if let Some(session_id) = &args.session_id {
         rt.block_on(async {
-            let (dc, peer_conn) = match tango_core::net::negotiate(
+            let mut ice_servers = args.ice_servers.clone();
+            if let Some(iceconfig_addr) = args.iceconfig_addr.as_ref() {
+                // If we can't get any ICE servers from the server, we may still be able to connect without them, so don't give up here.
+                match tango_core::iceconfig::get(iceconfig_addr).await {
+                    Ok(fetched) => {
+                        ice_servers.extend(fetched.iter().flat_map(|ice_server| ice_server.to_config_urls()));
+                    }
+                    Err(e) => {
+                        log::warn!("failed to get ice config: {}", e);
+                    }
+                }
+            }
+
+            let (dc, unreliable_dc, peer_conn) = match tango_core::net::negotiate(
                 &mut ipc_sender,
                 session_id,
                 &args.signaling_connect_addr,
-                &args.ice_servers,
+                args.relay_connect_addr.as_deref(),
+                &ice_servers,
             )
             .await {
                 Ok(v) => v,
@@ -163,6 +222,22 @@ fn main() -> Result<(), anyhow::Error> {
                         tango_core::net::Error::ProtocolVersionTooNew => {
                             std::process::exit(ExitCode::ProtocolVersionTooNew as i32);
                         }
+                        tango_core::net::Error::Signaling(e) => {
+                            log::error!("{}", e);
+                            ipc_sender.send(tango_core::ipc::protos::FromCoreMessage {
+                                which: Some(tango_core::ipc::protos::from_core_message::Which::SignalingErrorEv(tango_core::ipc::protos::from_core_message::SignalingErrorEvent {
+                                    code: match e.code {
+                                        tango_protos::signaling::packet::error::Code::Unknown => tango_core::ipc::protos::from_core_message::signaling_error_event::Code::Unknown,
+                                        tango_protos::signaling::packet::error::Code::SessionFull => tango_core::ipc::protos::from_core_message::signaling_error_event::Code::SessionFull,
+                                        tango_protos::signaling::packet::error::Code::SessionExpired => tango_core::ipc::protos::from_core_message::signaling_error_event::Code::SessionExpired,
+                                        tango_protos::signaling::packet::error::Code::ProtocolViolation => tango_core::ipc::protos::from_core_message::signaling_error_event::Code::ProtocolViolation,
+                                        tango_protos::signaling::packet::error::Code::RateLimited => tango_core::ipc::protos::from_core_message::signaling_error_event::Code::RateLimited,
+                                    }.into(),
+                                    message: e.message,
+                                }))
+                            }).await?;
+                            std::process::exit(ExitCode::SignalingError as i32);
+                        }
                         tango_core::net::Error::Other(_) => {
                             return Err(err.into());
                         }
@@ -173,9 +248,9 @@ fn main() -> Result<(), anyhow::Error> {
             let (mut dc_tx, mut dc_rx) = dc.split();
 
             let mut ping_timer = tokio::time::interval(std::time::Duration::from_secs(1));
-            let mut hola_received = false;
+            let mut remote_hola = None;
 
-            let start_req = loop {
+            let (start_req, resume) = loop {
                 tokio::select! {
                     msg = ipc_receiver.receive() => {
                         match msg?.which {
@@ -185,8 +260,22 @@ fn main() -> Result<(), anyhow::Error> {
                                 }).serialize()?).await?;
                             },
                             Some(tango_core::ipc::protos::to_core_message::Which::StartReq(start_req)) => {
-                                dc_tx.send(&tango_core::protocol::Packet::Hola(tango_core::protocol::Hola {}).serialize()?).await?;
-                                break start_req;
+                                let resume = match start_req.settings.as_ref().and_then(|settings| settings.resume_journal_path.as_ref()) {
+                                    Some(path) => Some(tango_core::journal::Resume::read(std::path::Path::new(path))?),
+                                    None => None,
+                                };
+                                dc_tx.send(&tango_core::protocol::Packet::Hola(tango_core::protocol::Hola {
+                                    input_delay: start_req.settings.as_ref().map(|settings| settings.input_delay).unwrap_or(0),
+                                    unreliable_inputs: unreliable_dc.is_some() && start_req.settings.as_ref().map(|settings| settings.unreliable_inputs).unwrap_or(false),
+                                    resume: resume.as_ref().map(|resume| tango_core::protocol::Resume {
+                                        round_number: resume.round_number,
+                                        rng_digest: resume.rng_digest(),
+                                    }),
+                                }).serialize()?).await?;
+                                break (start_req, resume);
+                            },
+                            Some(p) => {
+                                log::warn!("ignoring unexpected ipc request while connecting: {:?}", p);
                             },
                             None => {
                                 anyhow::bail!("ipc channel closed");
@@ -205,8 +294,8 @@ fn main() -> Result<(), anyhow::Error> {
                         match msg {
                             Some(msg) => {
                                 match tango_core::protocol::Packet::deserialize(&msg)? {
-                                    tango_core::protocol::Packet::Hola(_) => {
-                                        hola_received = true;
+                                    tango_core::protocol::Packet::Hola(hola) => {
+                                        remote_hola = Some(hola);
                                     }
                                     tango_core::protocol::Packet::Smuggle(tango_core::protocol::Smuggle {
                                         data,
@@ -248,14 +337,16 @@ fn main() -> Result<(), anyhow::Error> {
                 }
             };
 
-            if !hola_received {
+            let remote_hola = if let Some(remote_hola) = remote_hola {
+                remote_hola
+            } else {
                 // If we haven't received an Hola, pull packets until we do.
                 loop {
                     match dc_rx.receive().await {
                         Some(msg) => {
                             match tango_core::protocol::Packet::deserialize(&msg)? {
-                                tango_core::protocol::Packet::Hola(_) => {
-                                    break;
+                                tango_core::protocol::Packet::Hola(hola) => {
+                                    break hola;
                                 }
                                 tango_core::protocol::Packet::Ping(_) => {
                                     // Ignore stray pings.
@@ -273,12 +364,35 @@ fn main() -> Result<(), anyhow::Error> {
                         },
                     }
                 }
+            };
+
+            // Both sides must agree on where the set is being resumed from, otherwise we will desync immediately.
+            let local_resume = resume.as_ref().map(|resume| tango_core::protocol::Resume {
+                round_number: resume.round_number,
+                rng_digest: resume.rng_digest(),
+            });
+            if local_resume != remote_hola.resume {
+                log::error!(
+                    "journal mismatch: local = {:?}, remote = {:?}",
+                    local_resume,
+                    remote_hola.resume
+                );
+                std::process::exit(ExitCode::JournalMismatch as i32);
             }
 
             let settings = start_req.settings.clone().unwrap();
+
+            // Only use the unreliable channel if both sides want to, otherwise nobody is listening on the other end.
+            let unreliable_dc = if settings.unreliable_inputs && remote_hola.unreliable_inputs {
+                log::info!("sending redundant inputs over unreliable data channel");
+                unreliable_dc
+            } else {
+                None
+            };
+
             Ok((
                 start_req,
-                Some((peer_conn, dc_rx.unsplit(dc_tx), settings))
+                Some((peer_conn, dc_rx.unsplit(dc_tx), unreliable_dc, settings, remote_hola.input_delay, resume))
             ))
         })?
     } else {
@@ -315,34 +429,71 @@ fn main() -> Result<(), anyhow::Error> {
 
     mgba::log::init();
 
+    let gdb_stub = args
+        .gdb_listen_addr
+        .as_ref()
+        .map(|addr| tango_core::gdbstub::Stub::listen(addr))
+        .transpose()?;
+
     let video_filter = tango_core::video::filter_by_name(&start_req.video_filter).unwrap();
 
     tango_core::game::run(
         rt,
         std::sync::Arc::new(parking_lot::Mutex::new(ipc_sender)),
+        ipc_receiver,
         start_req.window_title,
         input_mapping,
         start_req.rom_path.into(),
+        tango_core::patch::Chain {
+            paths: start_req
+                .patch_paths
+                .into_iter()
+                .map(|p| p.into())
+                .collect(),
+            expected_crc32: start_req.patched_rom_crc32,
+        },
         start_req.save_path.into(),
         start_req.window_scale,
         video_filter,
+        start_req.show_scoreboard,
+        gdb_stub,
         match pvp_init {
             None => None,
-            Some((peer_conn, dc, settings)) => Some(tango_core::battle::MatchInit {
-                dc,
-                peer_conn,
-                settings: tango_core::battle::Settings {
-                    replay_metadata: settings.replay_metadata,
-                    replays_path: settings.replays_path.into(),
-                    shadow_save_path: settings.shadow_save_path.into(),
-                    shadow_rom_path: settings.shadow_rom_path.into(),
-                    match_type: (settings.match_type as u8, settings.match_subtype as u8),
-                    input_delay: settings.input_delay,
-                    rng_seed: settings.rng_seed,
-                    opponent_nickname: settings.opponent_nickname,
-                    max_queue_length: settings.max_queue_length as usize,
-                },
-            }),
+            Some((peer_conn, dc, unreliable_dc, settings, remote_input_delay, resume)) => {
+                Some(tango_core::battle::MatchInit {
+                    dc,
+                    unreliable_dc,
+                    peer_conn,
+                    settings: tango_core::battle::Settings {
+                        replay_metadata: settings.replay_metadata,
+                        replays_path: settings.replays_path.into(),
+                        shadow_save_path: settings.shadow_save_path.into(),
+                        shadow_rom_path: settings.shadow_rom_path.into(),
+                        shadow_rom_patches: tango_core::patch::Chain {
+                            paths: settings
+                                .shadow_patch_paths
+                                .into_iter()
+                                .map(|p| p.into())
+                                .collect(),
+                            expected_crc32: settings.shadow_patched_rom_crc32,
+                        },
+                        match_type: (settings.match_type as u8, settings.match_subtype as u8),
+                        input_delay: settings.input_delay,
+                        remote_input_delay,
+                        rng_seed: settings.rng_seed,
+                        local_nickname: settings.local_nickname,
+                        opponent_nickname: settings.opponent_nickname,
+                        max_queue_length: settings.max_queue_length as usize,
+                        resume,
+                        telemetry_path: settings.telemetry_path.map(|p| p.into()),
+                        stall_timeout: if settings.stall_timeout > 0 {
+                            std::time::Duration::from_millis(settings.stall_timeout as u64)
+                        } else {
+                            tango_core::stall::DEFAULT_TIMEOUT
+                        },
+                    },
+                })
+            }
         },
     )?;
     Ok(())
@@ -1,10 +1,11 @@
-use crate::{ipc, protocol, signaling};
+use crate::{ipc, protocol, relay, signaling};
 
 #[derive(Debug)]
 pub enum Error {
     ExpectedHello,
     ProtocolVersionTooOld,
     ProtocolVersionTooNew,
+    Signaling(signaling::ServerError),
     Other(anyhow::Error),
 }
 
@@ -32,6 +33,7 @@ impl std::fmt::Display for Error {
             Error::ExpectedHello => write!(f, "expected hello"),
             Error::ProtocolVersionTooOld => write!(f, "protocol version too old"),
             Error::ProtocolVersionTooNew => write!(f, "protocol version too new"),
+            Error::Signaling(e) => write!(f, "{}", e),
             Error::Other(e) => write!(f, "other error: {}", e),
         }
     }
@@ -43,10 +45,12 @@ pub async fn negotiate(
     ipc_sender: &mut ipc::Sender,
     session_id: &str,
     signaling_connect_addr: &str,
+    relay_connect_addr: Option<&str>,
     ice_servers: &[String],
 ) -> Result<
     (
         datachannel_wrapper::DataChannel,
+        Option<datachannel_wrapper::DataChannel>,
         datachannel_wrapper::PeerConnection,
     ),
     Error,
@@ -57,10 +61,36 @@ pub async fn negotiate(
         ice_servers
     );
 
-    let (mut peer_conn, mut event_rx) =
+    let (peer_conn, event_rx) =
         datachannel_wrapper::PeerConnection::new(datachannel_wrapper::RtcConfig::new(ice_servers))?;
+    negotiate_with_peer_connection(
+        ipc_sender,
+        session_id,
+        signaling_connect_addr,
+        relay_connect_addr,
+        peer_conn,
+        event_rx,
+    )
+    .await
+}
 
-    let dc = peer_conn.create_data_channel(
+/// Does the same as [`negotiate`], but over a peer connection that has already been created.
+pub async fn negotiate_with_peer_connection(
+    ipc_sender: &mut ipc::Sender,
+    session_id: &str,
+    signaling_connect_addr: &str,
+    relay_connect_addr: Option<&str>,
+    mut peer_conn: datachannel_wrapper::PeerConnection,
+    mut event_rx: tokio::sync::mpsc::Receiver<datachannel_wrapper::PeerConnectionEvent>,
+) -> Result<
+    (
+        datachannel_wrapper::DataChannel,
+        Option<datachannel_wrapper::DataChannel>,
+        datachannel_wrapper::PeerConnection,
+    ),
+    Error,
+> {
+    let mut dc = peer_conn.create_data_channel(
         "tango",
         datachannel_wrapper::DataChannelInit::default()
             .reliability(datachannel_wrapper::Reliability {
@@ -74,6 +104,23 @@ pub async fn negotiate(
             .stream(0),
     )?;
 
+    // Inputs may additionally be sent over this channel, so that a single lost packet doesn't hold up every input after it.
+    let mut unreliable_dc = Some(
+        peer_conn.create_data_channel(
+            "tango-unreliable",
+            datachannel_wrapper::DataChannelInit::default()
+                .reliability(datachannel_wrapper::Reliability {
+                    unordered: true,
+                    unreliable: true,
+                    max_packet_life_time: 0,
+                    max_retransmits: 0,
+                })
+                .negotiated()
+                .manual_stream()
+                .stream(1),
+        )?,
+    );
+
     loop {
         if let Some(datachannel_wrapper::PeerConnectionEvent::GatheringStateChange(
             datachannel_wrapper::GatheringState::Complete,
@@ -95,20 +142,42 @@ pub async fn negotiate(
         })
         .await?;
 
-    signaling::connect(signaling_connect_addr, &mut peer_conn, event_rx, session_id).await?;
+    match signaling::connect(signaling_connect_addr, &mut peer_conn, event_rx, session_id).await {
+        Ok(signaling::Route::Direct) => {
+            log::debug!(
+                "local sdp (type = {:?}): {}",
+                peer_conn.local_description().expect("local sdp").sdp_type,
+                peer_conn.local_description().expect("local sdp").sdp
+            );
+            log::debug!(
+                "remote sdp (type = {:?}): {}",
+                peer_conn.remote_description().expect("remote sdp").sdp_type,
+                peer_conn.remote_description().expect("remote sdp").sdp
+            );
+        }
+        Ok(signaling::Route::Relay(ticket)) => {
+            let relay_connect_addr = if let Some(relay_connect_addr) = relay_connect_addr {
+                relay_connect_addr
+            } else {
+                return Err(Error::Other(
+                    signaling::IceError("no relay to fall back to".to_string()).into(),
+                ));
+            };
 
-    let (mut dc_tx, mut dc_rx) = dc.split();
+            // Both sides have told each other how ICE went over signaling, so they'll both end up at the relay.
+            log::warn!("peer connection failed, falling back to relay");
+            dc = relay::connect(relay_connect_addr, session_id, &ticket).await?;
+            unreliable_dc = None;
+        }
+        Err(e) => {
+            return Err(match e.downcast::<signaling::ServerError>() {
+                Ok(e) => Error::Signaling(e),
+                Err(e) => Error::Other(e),
+            });
+        }
+    }
 
-    log::debug!(
-        "local sdp (type = {:?}): {}",
-        peer_conn.local_description().expect("local sdp").sdp_type,
-        peer_conn.local_description().expect("local sdp").sdp
-    );
-    log::debug!(
-        "remote sdp (type = {:?}): {}",
-        peer_conn.remote_description().expect("remote sdp").sdp_type,
-        peer_conn.remote_description().expect("remote sdp").sdp
-    );
+    let (mut dc_tx, mut dc_rx) = dc.split();
 
     ipc_sender
         .send(ipc::protos::FromCoreMessage {
@@ -166,5 +235,53 @@ pub async fn negotiate(
         })
         .await?;
 
-    Ok((dc_rx.unsplit(dc_tx), peer_conn))
+    Ok((dc_rx.unsplit(dc_tx), unreliable_dc, peer_conn))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn negotiate_with_peer_connection_exchanges_hello() {
+        let addr = signaling::testing::serve_one_session("").await;
+        let network = datachannel_wrapper::mock::Network::new();
+        let (a, a_event_rx) = network.peer_connection();
+        let (b, b_event_rx) = network.peer_connection();
+        let mut a_ipc_sender = ipc::Sender::new_from_writer(tokio::io::sink());
+        let mut b_ipc_sender = ipc::Sender::new_from_writer(tokio::io::sink());
+
+        let (a_r, b_r) = tokio::time::timeout(std::time::Duration::from_secs(10), async {
+            tokio::join!(
+                negotiate_with_peer_connection(
+                    &mut a_ipc_sender,
+                    "session",
+                    &addr,
+                    None,
+                    a,
+                    a_event_rx
+                ),
+                negotiate_with_peer_connection(
+                    &mut b_ipc_sender,
+                    "session",
+                    &addr,
+                    None,
+                    b,
+                    b_event_rx
+                ),
+            )
+        })
+        .await
+        .expect("timed out");
+        let (mut a_dc, a_unreliable_dc, _a_peer_conn) = a_r.unwrap();
+        let (mut b_dc, b_unreliable_dc, _b_peer_conn) = b_r.unwrap();
+
+        let mut a_unreliable_dc = a_unreliable_dc.expect("unreliable data channel");
+        let mut b_unreliable_dc = b_unreliable_dc.expect("unreliable data channel");
+
+        a_dc.send(b"reliable").await.unwrap();
+        assert_eq!(b_dc.receive().await.unwrap(), b"reliable");
+        b_unreliable_dc.send(b"unreliable").await.unwrap();
+        assert_eq!(a_unreliable_dc.receive().await.unwrap(), b"unreliable");
+    }
 }
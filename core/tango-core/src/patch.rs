@@ -0,0 +1,391 @@
+use crate::hooks;
+use byteorder::ByteOrder;
+
+#[derive(Debug)]
+pub enum Error {
+    UnknownFormat,
+    Truncated,
+    OutOfBounds,
+    SourceChecksumMismatch { expected: u32, actual: u32 },
+    TargetChecksumMismatch { expected: u32, actual: u32 },
+    PatchChecksumMismatch { expected: u32, actual: u32 },
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::UnknownFormat => write!(f, "unknown patch format"),
+            Error::Truncated => write!(f, "patch is truncated"),
+            Error::OutOfBounds => write!(f, "patch reads or writes out of bounds"),
+            Error::SourceChecksumMismatch { expected, actual } => write!(
+                f,
+                "source checksum mismatch: expected {:08x}, got {:08x}",
+                expected, actual
+            ),
+            Error::TargetChecksumMismatch { expected, actual } => write!(
+                f,
+                "target checksum mismatch: expected {:08x}, got {:08x}",
+                expected, actual
+            ),
+            Error::PatchChecksumMismatch { expected, actual } => write!(
+                f,
+                "patch checksum mismatch: expected {:08x}, got {:08x}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Ips,
+    Ups,
+    Bps,
+}
+
+impl Format {
+    pub fn detect(patch: &[u8]) -> Option<Self> {
+        if patch.starts_with(b"PATCH") {
+            Some(Format::Ips)
+        } else if patch.starts_with(b"UPS1") {
+            Some(Format::Ups)
+        } else if patch.starts_with(b"BPS1") {
+            Some(Format::Bps)
+        } else {
+            None
+        }
+    }
+}
+
+/// Applies a patch, detecting its format from its header. UPS and BPS patches carry their own checksums, which are verified.
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, Error> {
+    match Format::detect(patch).ok_or(Error::UnknownFormat)? {
+        Format::Ips => apply_ips(rom, patch),
+        Format::Ups => apply_ups(rom, patch),
+        Format::Bps => apply_bps(rom, patch),
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8], pos: usize) -> Self {
+        Self { buf, pos }
+    }
+
+    fn read(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        let r = self
+            .buf
+            .get(self.pos..self.pos.checked_add(n).ok_or(Error::Truncated)?)
+            .ok_or(Error::Truncated)?;
+        self.pos += n;
+        Ok(r)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, Error> {
+        Ok(self.read(1)?[0])
+    }
+
+    fn read_be(&mut self, n: usize) -> Result<usize, Error> {
+        Ok(self
+            .read(n)?
+            .iter()
+            .fold(0, |acc, b| (acc << 8) | *b as usize))
+    }
+
+    /// Reads a variable-length number as used by UPS and BPS.
+    fn read_varint(&mut self) -> Result<usize, Error> {
+        let mut data = 0usize;
+        let mut shift = 1usize;
+        loop {
+            let x = self.read_u8()?;
+            data = ((x & 0x7f) as usize)
+                .checked_mul(shift)
+                .and_then(|v| data.checked_add(v))
+                .ok_or(Error::OutOfBounds)?;
+            if x & 0x80 != 0 {
+                break;
+            }
+            shift = shift.checked_mul(0x80).ok_or(Error::OutOfBounds)?;
+            data = data.checked_add(shift).ok_or(Error::OutOfBounds)?;
+        }
+        Ok(data)
+    }
+}
+
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, Error> {
+    const EOF: usize = 0x454f46;
+
+    let mut out = rom.to_vec();
+    let mut r = Reader::new(patch, 5);
+    loop {
+        let offset = r.read_be(3)?;
+        if offset == EOF {
+            break;
+        }
+        let size = r.read_be(2)?;
+        let (size, data) = if size == 0 {
+            // Run-length encoded record.
+            let size = r.read_be(2)?;
+            (size, std::borrow::Cow::Owned(vec![r.read_u8()?; size]))
+        } else {
+            (size, std::borrow::Cow::Borrowed(r.read(size)?))
+        };
+        if out.len() < offset + size {
+            out.resize(offset + size, 0);
+        }
+        out[offset..offset + size].copy_from_slice(&data);
+    }
+
+    // Some patches follow the EOF marker with a size to truncate to.
+    if let Ok(size) = r.read_be(3) {
+        out.truncate(size);
+    }
+
+    Ok(out)
+}
+
+/// Splits off and verifies the checksums at the end of a UPS or BPS patch, returning the body of the patch and the expected target checksum.
+fn verify_footer<'a>(rom: &[u8], patch: &'a [u8]) -> Result<(&'a [u8], u32), Error> {
+    if patch.len() < 12 {
+        return Err(Error::Truncated);
+    }
+    let (body, footer) = patch.split_at(patch.len() - 12);
+
+    let expected_patch_crc32 = byteorder::LittleEndian::read_u32(&footer[8..12]);
+    let patch_crc32 = crc32fast::hash(&patch[..patch.len() - 4]);
+    if patch_crc32 != expected_patch_crc32 {
+        return Err(Error::PatchChecksumMismatch {
+            expected: expected_patch_crc32,
+            actual: patch_crc32,
+        });
+    }
+
+    let expected_source_crc32 = byteorder::LittleEndian::read_u32(&footer[0..4]);
+    let source_crc32 = crc32fast::hash(rom);
+    if source_crc32 != expected_source_crc32 {
+        return Err(Error::SourceChecksumMismatch {
+            expected: expected_source_crc32,
+            actual: source_crc32,
+        });
+    }
+
+    Ok((body, byteorder::LittleEndian::read_u32(&footer[4..8])))
+}
+
+fn verify_target(out: &[u8], expected: u32) -> Result<(), Error> {
+    let actual = crc32fast::hash(out);
+    if actual != expected {
+        return Err(Error::TargetChecksumMismatch { expected, actual });
+    }
+    Ok(())
+}
+
+fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, Error> {
+    let (body, target_crc32) = verify_footer(rom, patch)?;
+
+    let mut r = Reader::new(body, 4);
+    let _source_size = r.read_varint()?;
+    let target_size = r.read_varint()?;
+
+    let mut out = rom.to_vec();
+    out.resize(target_size, 0);
+
+    let mut ptr = 0;
+    while r.pos < body.len() {
+        ptr = ptr
+            .checked_add(r.read_varint()?)
+            .ok_or(Error::OutOfBounds)?;
+        loop {
+            let x = r.read_u8()?;
+            if x == 0 {
+                ptr += 1;
+                break;
+            }
+            *out.get_mut(ptr).ok_or(Error::OutOfBounds)? ^= x;
+            ptr += 1;
+        }
+    }
+
+    verify_target(&out, target_crc32)?;
+    Ok(out)
+}
+
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, Error> {
+    let (body, target_crc32) = verify_footer(rom, patch)?;
+
+    let mut r = Reader::new(body, 4);
+    let _source_size = r.read_varint()?;
+    let target_size = r.read_varint()?;
+    let metadata_size = r.read_varint()?;
+    r.read(metadata_size)?;
+
+    let mut out = Vec::with_capacity(target_size);
+    let mut source_relative_offset = 0isize;
+    let mut target_relative_offset = 0isize;
+
+    fn read_relative_offset(r: &mut Reader) -> Result<isize, Error> {
+        let offset = r.read_varint()?;
+        let magnitude = (offset >> 1) as isize;
+        Ok(if offset & 1 != 0 {
+            -magnitude
+        } else {
+            magnitude
+        })
+    }
+
+    while r.pos < body.len() {
+        let data = r.read_varint()?;
+        let length = (data >> 2) + 1;
+        if out.len() + length > target_size {
+            return Err(Error::OutOfBounds);
+        }
+        match data & 3 {
+            // SourceRead
+            0 => {
+                let start = out.len();
+                out.extend_from_slice(rom.get(start..start + length).ok_or(Error::OutOfBounds)?);
+            }
+            // TargetRead
+            1 => {
+                out.extend_from_slice(r.read(length)?);
+            }
+            // SourceCopy
+            2 => {
+                source_relative_offset = source_relative_offset
+                    .checked_add(read_relative_offset(&mut r)?)
+                    .ok_or(Error::OutOfBounds)?;
+                if source_relative_offset < 0 {
+                    return Err(Error::OutOfBounds);
+                }
+                let start = source_relative_offset as usize;
+                out.extend_from_slice(rom.get(start..start + length).ok_or(Error::OutOfBounds)?);
+                source_relative_offset += length as isize;
+            }
+            // TargetCopy
+            _ => {
+                target_relative_offset = target_relative_offset
+                    .checked_add(read_relative_offset(&mut r)?)
+                    .ok_or(Error::OutOfBounds)?;
+                if target_relative_offset < 0 {
+                    return Err(Error::OutOfBounds);
+                }
+                // The copy may overlap with what it is writing, so it has to go a byte at a time.
+                for _ in 0..length {
+                    let b = *out
+                        .get(target_relative_offset as usize)
+                        .ok_or(Error::OutOfBounds)?;
+                    out.push(b);
+                    target_relative_offset += 1;
+                }
+            }
+        }
+    }
+
+    if out.len() != target_size {
+        return Err(Error::OutOfBounds);
+    }
+
+    verify_target(&out, target_crc32)?;
+    Ok(out)
+}
+
+struct BundledPatch {
+    full_rom_name: &'static [u8; 16],
+    from_revision: u8,
+    patch: &'static [u8],
+}
+
+// Patches to bring older revisions of supported games up to the revision we have hooks for.
+const BUNDLED_PATCHES: &[BundledPatch] = &[
+    BundledPatch {
+        full_rom_name: b"ROCKMAN_EXE2AE2J",
+        from_revision: 0x00,
+        patch: include_bytes!("../../../patches/ROCKMAN_EXE2AE2J-rev0-to-rev1.bps"),
+    },
+    BundledPatch {
+        full_rom_name: b"ROCKMAN_EXE3A6BJ",
+        from_revision: 0x00,
+        patch: include_bytes!("../../../patches/ROCKMAN_EXE3A6BJ-rev0-to-rev1.bps"),
+    },
+    BundledPatch {
+        full_rom_name: b"ROCK_EXE3_BKA3XJ",
+        from_revision: 0x00,
+        patch: include_bytes!("../../../patches/ROCK_EXE3_BKA3XJ-rev0-to-rev1.bps"),
+    },
+];
+
+fn rom_id(rom: &[u8]) -> Option<([u8; 16], u8)> {
+    Some((rom.get(0xa0..0xb0)?.try_into().ok()?, *rom.get(0xbc)?))
+}
+
+/// If we don't have hooks for this revision of the game but do have a patch to a revision we do have hooks for, applies it. Otherwise, the ROM is returned unchanged.
+pub fn normalize_revision(rom: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    let (full_rom_name, rom_revision) = if let Some(rom_id) = rom_id(&rom) {
+        rom_id
+    } else {
+        return Ok(rom);
+    };
+
+    if hooks::get_by_rom_id(&full_rom_name, rom_revision).is_some() {
+        return Ok(rom);
+    }
+
+    let bundled_patch = if let Some(bundled_patch) = BUNDLED_PATCHES
+        .iter()
+        .find(|p| p.full_rom_name == &full_rom_name && p.from_revision == rom_revision)
+    {
+        bundled_patch
+    } else {
+        return Ok(rom);
+    };
+
+    // The BPS checksums ensure we only ever end up with exactly the ROM the hooks were written for.
+    let rom = apply(&rom, bundled_patch.patch)?;
+    log::info!(
+        "normalized {} from rev {} to rev {}",
+        String::from_utf8_lossy(&full_rom_name),
+        rom_revision,
+        rom.get(0xbc).copied().unwrap_or(0),
+    );
+    Ok(rom)
+}
+
+/// An ordered list of patches to apply to a ROM before loading it.
+///
+/// Patches are written against the revisions we have hooks for, so the ROM should be passed through [`normalize_revision`] first.
+#[derive(Clone, Debug, Default)]
+pub struct Chain {
+    pub paths: Vec<std::path::PathBuf>,
+    /// If set, the CRC32 the ROM must have once all the patches have been applied.
+    pub expected_crc32: Option<u32>,
+}
+
+impl Chain {
+    pub fn apply(&self, mut rom: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        for path in self.paths.iter() {
+            let patch = std::fs::read(path)?;
+            rom = apply(&rom, &patch)
+                .map_err(|e| anyhow::anyhow!("failed to apply {}: {}", path.display(), e))?;
+            log::info!("applied patch: {}", path.display());
+        }
+
+        if let Some(expected_crc32) = self.expected_crc32 {
+            let crc32 = crc32fast::hash(&rom);
+            if crc32 != expected_crc32 {
+                anyhow::bail!(
+                    "patched rom checksum mismatch: expected {:08x}, got {:08x}",
+                    expected_crc32,
+                    crc32
+                );
+            }
+        }
+
+        Ok(rom)
+    }
+}
@@ -1,6 +1,6 @@
 use bincode::Options;
 
-pub const VERSION: u8 = 0x23;
+pub const VERSION: u8 = 0x27;
 
 lazy_static! {
     static ref BINCODE_OPTIONS: bincode::config::WithOtherLimit<
@@ -22,6 +22,7 @@ pub enum Packet {
     Ping(Ping),
     Pong(Pong),
     Input(Input),
+    RedundantInputs(RedundantInputs),
 }
 
 impl Packet {
@@ -40,7 +41,17 @@ pub struct Hello {
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
-pub struct Hola {}
+pub struct Hola {
+    pub input_delay: u32,
+    pub resume: Option<Resume>,
+    pub unreliable_inputs: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+pub struct Resume {
+    pub round_number: u8,
+    pub rng_digest: Vec<u8>,
+}
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct Smuggle {
@@ -64,3 +75,12 @@ pub struct Input {
     pub tick_diff: i8,
     pub joyflags: u16,
 }
+
+/// Sent over the unreliable channel alongside every input: this carries every input the remote hasn't acknowledged yet, so losing any one of these packets doesn't hold anything up.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct RedundantInputs {
+    // The round and tick of the next input we are expecting from the remote.
+    pub ack_round_number: u8,
+    pub ack_tick: u32,
+    pub inputs: Vec<Input>,
+}
@@ -0,0 +1,73 @@
+use futures_util::{SinkExt, StreamExt};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+/// Connects to the signaling server's relay for the given session and returns a data channel that carries match traffic through it.
+///
+/// This is only used when a direct peer connection can't be established: it adds latency and costs the server bandwidth. The ticket is the one the signaling server handed out for this session.
+pub async fn connect(
+    addr: &str,
+    session_id: &str,
+    ticket: &str,
+) -> anyhow::Result<datachannel_wrapper::DataChannel> {
+    let mut url = url::Url::parse(addr)?;
+    url.set_query(Some(
+        &url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("session_id", session_id)
+            .append_pair("ticket", ticket)
+            .finish(),
+    ));
+
+    let mut req = url.to_string().into_client_request()?;
+    req.headers_mut().append(
+        "User-Agent",
+        tokio_tungstenite::tungstenite::http::HeaderValue::from_str(&format!(
+            "tango-core/{}",
+            git_version::git_version!()
+        ))?,
+    );
+    let (stream, _) = tokio_tungstenite::connect_async(req).await?;
+    log::info!("connected to relay");
+
+    let (mut ws_tx, mut ws_rx) = stream.split();
+    let (outgoing_tx, mut outgoing_rx) = tokio::sync::mpsc::channel::<Vec<u8>>(256);
+    let (incoming_tx, incoming_rx) = tokio::sync::mpsc::channel::<Vec<u8>>(256);
+
+    tokio::spawn(async move {
+        while let Some(msg) = outgoing_rx.recv().await {
+            if let Err(e) = ws_tx
+                .send(tokio_tungstenite::tungstenite::Message::Binary(msg))
+                .await
+            {
+                log::error!("failed to send to relay: {}", e);
+                break;
+            }
+        }
+        let _ = ws_tx.close().await;
+    });
+
+    tokio::spawn(async move {
+        while let Some(msg) = ws_rx.next().await {
+            match msg {
+                Ok(tokio_tungstenite::tungstenite::Message::Binary(d)) => {
+                    if incoming_tx.send(d).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(tokio_tungstenite::tungstenite::Message::Close(_)) => {
+                    break;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    log::error!("failed to receive from relay: {}", e);
+                    break;
+                }
+            }
+        }
+        log::info!("relay connection closed");
+    });
+
+    Ok(datachannel_wrapper::DataChannel::from_channels(
+        outgoing_tx,
+        incoming_rx,
+    ))
+}
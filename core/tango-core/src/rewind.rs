@@ -0,0 +1,67 @@
+// Capture a state every this many frames: rewinding steps back by this much each frame, so this is also how much faster than real time rewinding goes.
+pub const CAPTURE_INTERVAL: u32 = 4;
+
+// At 60 fps, this is about 40 seconds of rewinding.
+pub const DEFAULT_CAPACITY: usize = 600;
+
+const COMPRESSION_LEVEL: i32 = 1;
+
+/// A ring of compressed save states, oldest first.
+pub struct Buffer {
+    states: std::collections::VecDeque<Vec<u8>>,
+    state_size: usize,
+    capacity: usize,
+    frames_since_capture: u32,
+}
+
+impl Buffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            states: std::collections::VecDeque::with_capacity(capacity),
+            capacity,
+            state_size: 0,
+            frames_since_capture: 0,
+        }
+    }
+
+    /// Captures the current state if it is time to, evicting the oldest state if the buffer is full.
+    pub fn capture(&mut self, core: &mut mgba::core::CoreMutRef) -> anyhow::Result<()> {
+        self.frames_since_capture += 1;
+        if self.frames_since_capture < CAPTURE_INTERVAL {
+            return Ok(());
+        }
+        self.frames_since_capture = 0;
+
+        let state = core.save_state()?;
+        self.state_size = state.as_slice().len();
+        if self.states.len() == self.capacity {
+            self.states.pop_front();
+        }
+        self.states
+            .push_back(zstd::bulk::compress(state.as_slice(), COMPRESSION_LEVEL)?);
+        Ok(())
+    }
+
+    /// Steps back to the most recently captured state, if there is one left.
+    pub fn rewind(&mut self, core: &mut mgba::core::CoreMutRef) -> anyhow::Result<bool> {
+        let compressed = if let Some(compressed) = self.states.pop_back() {
+            compressed
+        } else {
+            return Ok(false);
+        };
+        let state =
+            mgba::state::State::from_slice(&zstd::bulk::decompress(&compressed, self.state_size)?);
+        core.load_state(&state)?;
+        // Start capturing afresh from here once we stop rewinding.
+        self.frames_since_capture = 0;
+        Ok(true)
+    }
+
+    pub fn len(&self) -> usize {
+        self.states.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+}
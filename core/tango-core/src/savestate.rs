@@ -0,0 +1,58 @@
+// Slots are numbered the same way as mGBA's own .ss0 through .ss9 files, but ours are zstd-compressed and so not interchangeable with them: they get an extension of their own so neither tries to load the other's.
+pub const NUM_SLOTS: usize = 10;
+
+pub fn state_path(save_path: &std::path::Path, slot: usize) -> std::path::PathBuf {
+    save_path.with_extension(format!("tangoss{}", slot))
+}
+
+pub fn thumbnail_path(save_path: &std::path::Path, slot: usize) -> std::path::PathBuf {
+    save_path.with_extension(format!("tangoss{}.png", slot))
+}
+
+fn check_slot(slot: usize) -> anyhow::Result<()> {
+    if slot >= NUM_SLOTS {
+        anyhow::bail!("invalid save state slot: {}", slot);
+    }
+    Ok(())
+}
+
+/// Writes a state to the given slot next to the save file, along with a screenshot of the frame it was taken on.
+pub fn save(
+    save_path: &std::path::Path,
+    slot: usize,
+    state: &mgba::state::State,
+    vbuf: &[u8],
+) -> anyhow::Result<()> {
+    check_slot(slot)?;
+
+    std::fs::write(
+        state_path(save_path, slot),
+        zstd::stream::encode_all(state.as_slice(), 0)?,
+    )?;
+
+    let thumbnail_f = std::fs::File::create(thumbnail_path(save_path, slot))?;
+    let mut encoder = png::Encoder::new(
+        thumbnail_f,
+        mgba::gba::SCREEN_WIDTH,
+        mgba::gba::SCREEN_HEIGHT,
+    );
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+
+    // The video buffer leaves alpha at 0, which would make the thumbnail entirely transparent.
+    let mut vbuf = vbuf.to_vec();
+    for i in (0..vbuf.len()).step_by(4) {
+        vbuf[i + 3] = 0xff;
+    }
+    writer.write_image_data(&vbuf)?;
+    Ok(())
+}
+
+pub fn load(save_path: &std::path::Path, slot: usize) -> anyhow::Result<mgba::state::State> {
+    check_slot(slot)?;
+    let compressed = std::fs::read(state_path(save_path, slot))?;
+    Ok(mgba::state::State::from_slice(&zstd::stream::decode_all(
+        &compressed[..],
+    )?))
+}
@@ -1,4 +1,4 @@
-use crate::{audio, battle, game, hooks, ipc, stats};
+use crate::{audio, battle, game, gdbstub, hooks, ipc, patch, rewind, savestate, stats};
 use parking_lot::Mutex;
 use rand::SeedableRng;
 use std::sync::Arc;
@@ -8,6 +8,9 @@ pub struct Session {
     _audio_binding: audio::Binding<i16>,
     thread: mgba::thread::Thread,
     joyflags: std::sync::Arc<std::sync::atomic::AtomicU32>,
+    rewinding: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    save_path: std::path::PathBuf,
+    cheats: Option<Arc<Mutex<Vec<mgba::cheats::Cheat>>>>,
     match_: Option<std::sync::Arc<tokio::sync::Mutex<Option<std::sync::Arc<battle::Match>>>>>,
 }
 
@@ -18,14 +21,20 @@ impl Session {
         audio_cb: audio::LateBinder<i16>,
         audio_spec: sdl2::audio::AudioSpec,
         rom_path: std::path::PathBuf,
+        rom_patches: patch::Chain,
         save_path: std::path::PathBuf,
         emu_tps_counter: Arc<Mutex<stats::Counter>>,
+        gdb_stub: Option<Arc<gdbstub::Stub>>,
         match_init: Option<battle::MatchInit>,
     ) -> Result<Self, anyhow::Error> {
+        if gdb_stub.is_some() && match_init.is_some() {
+            anyhow::bail!("the gdb stub can't be used in a match");
+        }
+
         let mut core = mgba::core::Core::new_gba("tango")?;
         core.enable_video_buffer();
 
-        let rom = std::fs::read(rom_path)?;
+        let rom = rom_patches.apply(patch::normalize_revision(std::fs::read(rom_path)?)?)?;
         let rom_vf = mgba::vfile::VFile::open_memory(&rom);
         core.as_mut().load_rom(rom_vf)?;
 
@@ -36,7 +45,7 @@ impl Session {
             )?
         } else {
             log::info!("in pvp mode, save file will not be written back to disk");
-            mgba::vfile::VFile::open_memory(&std::fs::read(save_path)?)
+            mgba::vfile::VFile::open_memory(&std::fs::read(&save_path)?)
         };
 
         core.as_mut().load_save(save_vf)?;
@@ -52,6 +61,10 @@ impl Session {
             let mut traps = hooks.common_traps();
             traps.extend(hooks.primary_traps(handle.clone(), joyflags.clone(), match_.clone()));
             core.set_traps(traps);
+        } else if gdb_stub.is_some() {
+            // Breakpoints are installed with the trapper, so we need one even if we have no traps of our own.
+            core.set_traps(vec![]);
+            core.enable_watchpoints();
         }
 
         let thread = mgba::thread::Thread::new(core);
@@ -73,6 +86,7 @@ impl Session {
                         hooks,
                         match_init.peer_conn,
                         match_init.dc,
+                        match_init.unreliable_dc,
                         rand_pcg::Mcg128Xsl64::from_seed(rng_seed),
                         is_offerer,
                         thread.handle(),
@@ -100,6 +114,21 @@ impl Session {
             audio_spec,
         ))))?;
 
+        // Rewinding would desync us from the remote, so it's only available in single-player.
+        let rewind_buffer = if match_.is_none() {
+            Some(Mutex::new(rewind::Buffer::new(rewind::DEFAULT_CAPACITY)))
+        } else {
+            None
+        };
+        let rewinding = Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+        // Likewise, cheats are strictly for single-player.
+        let cheats = if match_.is_none() {
+            Some(Arc::new(Mutex::new(vec![])))
+        } else {
+            None
+        };
+
         let vbuf = Arc::new(Mutex::new(vec![
             0u8;
             (mgba::gba::SCREEN_WIDTH * mgba::gba::SCREEN_HEIGHT * 4)
@@ -109,9 +138,29 @@ impl Session {
             let joyflags = joyflags.clone();
             let vbuf = vbuf.clone();
             let emu_tps_counter = emu_tps_counter.clone();
+            let rewinding = rewinding.clone();
+            let cheats = cheats.clone();
             thread.set_frame_callback(move |mut core, video_buffer| {
+                if let Some(gdb_stub) = gdb_stub.as_ref() {
+                    gdb_stub.poll(core);
+                }
                 let mut vbuf = vbuf.lock();
                 vbuf.copy_from_slice(video_buffer);
+                if let Some(rewind_buffer) = rewind_buffer.as_ref() {
+                    let mut rewind_buffer = rewind_buffer.lock();
+                    if let Err(e) = if rewinding.load(std::sync::atomic::Ordering::Relaxed) {
+                        rewind_buffer.rewind(&mut core).map(|_| ())
+                    } else {
+                        rewind_buffer.capture(&mut core)
+                    } {
+                        log::error!("rewind failed: {}", e);
+                    }
+                }
+                if let Some(cheats) = cheats.as_ref() {
+                    for cheat in cheats.lock().iter() {
+                        cheat.apply(&mut core);
+                    }
+                }
                 core.set_keys(joyflags.load(std::sync::atomic::Ordering::Relaxed));
                 emu_tps_counter.lock().mark();
             });
@@ -121,6 +170,9 @@ impl Session {
             _audio_binding: audio_binding,
             thread,
             joyflags,
+            rewinding,
+            save_path,
+            cheats,
             match_,
         })
     }
@@ -150,6 +202,52 @@ impl Session {
         self.vbuf.lock()
     }
 
+    /// Has no effect during a match.
+    pub fn set_rewinding(&self, rewinding: bool) {
+        self.rewinding
+            .store(rewinding, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    pub fn save_state(&self, slot: usize) -> anyhow::Result<()> {
+        // A state saved mid-match could only be loaded back outside of it, where it would be meaningless.
+        if self.match_.is_some() {
+            anyhow::bail!("cannot save a save state during a match");
+        }
+
+        let handle = self.thread.handle();
+        handle.pause();
+        let r = (|| {
+            let state = handle.lock_audio().core().save_state()?;
+            savestate::save(&self.save_path, slot, &state, &self.vbuf.lock())
+        })();
+        handle.unpause();
+        r
+    }
+
+    pub fn load_state(&self, slot: usize) -> anyhow::Result<()> {
+        // Loading a state in the middle of a match would desync us from the remote.
+        if self.match_.is_some() {
+            anyhow::bail!("cannot load a save state during a match");
+        }
+
+        let state = savestate::load(&self.save_path, slot)?;
+        let handle = self.thread.handle();
+        handle.pause();
+        let r = handle.lock_audio().core_mut().load_state(&state);
+        handle.unpause();
+        r
+    }
+
+    pub fn set_cheats(&self, cheats: Vec<mgba::cheats::Cheat>) -> anyhow::Result<()> {
+        let current_cheats = if let Some(current_cheats) = self.cheats.as_ref() {
+            current_cheats
+        } else {
+            anyhow::bail!("cannot use cheats during a match");
+        };
+        *current_cheats.lock() = cheats;
+        Ok(())
+    }
+
     pub fn set_joyflags(&self, joyflags: u32) {
         self.joyflags
             .store(joyflags, std::sync::atomic::Ordering::Relaxed);
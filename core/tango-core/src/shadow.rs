@@ -76,6 +76,7 @@ impl Round {
 }
 
 pub struct RoundState {
+    pub number: u8,
     pub round: Option<Round>,
     pub last_result: Option<battle::BattleResult>,
 }
@@ -114,6 +115,7 @@ impl State {
         match_type: (u8, u8),
         is_offerer: bool,
         rng: rand_pcg::Mcg128Xsl64,
+        round_number: u8,
         last_result: battle::BattleResult,
     ) -> State {
         State(std::sync::Arc::new(InnerState {
@@ -121,6 +123,7 @@ impl State {
             is_offerer,
             rng: parking_lot::Mutex::new(rng),
             round_state: parking_lot::Mutex::new(RoundState {
+                number: round_number,
                 round: None,
                 last_result: Some(last_result),
             }),
@@ -147,12 +150,14 @@ impl State {
 
     pub fn start_round(&self) {
         let mut round_state = self.0.round_state.lock();
+        round_state.number += 1;
         let local_player_index = match round_state.last_result.take().unwrap() {
             battle::BattleResult::Win => 0,
             battle::BattleResult::Loss => 1,
         };
         log::info!(
-            "starting shadow round: local_player_index = {}",
+            "starting shadow round {}: local_player_index = {}",
+            round_state.number,
             local_player_index
         );
         round_state.round = Some(Round {
@@ -186,6 +191,7 @@ impl Shadow {
         save_path: &std::path::Path,
         match_type: (u8, u8),
         is_offerer: bool,
+        round_number: u8,
         battle_result: battle::BattleResult,
         rng: rand_pcg::Mcg128Xsl64,
     ) -> anyhow::Result<Self> {
@@ -202,7 +208,7 @@ impl Shadow {
         let save_vf = mgba::vfile::VFile::open_memory(&std::fs::read(save_path)?);
         core.as_mut().load_save(save_vf)?;
 
-        let state = State::new(match_type, is_offerer, rng, battle_result);
+        let state = State::new(match_type, is_offerer, rng, round_number, battle_result);
 
         let hooks = hooks::get(core.as_mut()).unwrap();
         hooks.patch(core.as_mut());
@@ -215,6 +221,11 @@ impl Shadow {
         Ok(Shadow { core, hooks, state })
     }
 
+    /// The number of the round the shadow is in, or of the last round it played if it is between rounds.
+    pub fn round_number(&self) -> u8 {
+        self.state.lock_round_state().number
+    }
+
     pub fn advance_until_first_committed_state(&mut self) -> anyhow::Result<mgba::state::State> {
         log::info!("advancing shadow until first committed state");
         loop {
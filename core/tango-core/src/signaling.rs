@@ -3,12 +3,97 @@ use futures_util::TryStreamExt;
 use prost::Message;
 use tokio_tungstenite::tungstenite::client::IntoClientRequest;
 
+#[derive(Debug)]
+pub struct ServerError {
+    pub code: tango_protos::signaling::packet::error::Code,
+    pub message: String,
+}
+
+impl std::fmt::Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "signaling server error {:?}: {}",
+            self.code, self.message
+        )
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+/// Signaling succeeded, but ICE could not find a way for the two peers to reach each other.
+#[derive(Debug)]
+pub struct IceError(pub String);
+
+impl std::fmt::Display for IceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "ice connection failed: {}", self.0)
+    }
+}
+
+impl std::error::Error for IceError {}
+
+const ICE_CONNECTION_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// How long to wait for the server to answer our connection result with a relay ticket, which it does straight away if it supports relay fallback at all.
+const RELAY_TICKET_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Where match traffic should go once signaling is over.
+#[derive(Debug, PartialEq)]
+pub enum Route {
+    /// Over the peer connection.
+    Direct,
+    /// Through the signaling server's relay, using the given ticket.
+    Relay(String),
+}
+
+async fn wait_for_connection(
+    event_rx: &mut tokio::sync::mpsc::Receiver<datachannel_wrapper::PeerConnectionEvent>,
+) -> Result<(), IceError> {
+    match tokio::time::timeout(ICE_CONNECTION_TIMEOUT, async {
+        loop {
+            match event_rx.recv().await {
+                Some(signal) => match signal {
+                    datachannel_wrapper::PeerConnectionEvent::ConnectionStateChange(c) => match c {
+                        datachannel_wrapper::ConnectionState::Connected => {
+                            return Ok(());
+                        }
+                        datachannel_wrapper::ConnectionState::Disconnected => {
+                            return Err(IceError(
+                                "peer connection unexpectedly disconnected".to_string(),
+                            ));
+                        }
+                        datachannel_wrapper::ConnectionState::Failed => {
+                            return Err(IceError("peer connection failed".to_string()));
+                        }
+                        datachannel_wrapper::ConnectionState::Closed => {
+                            return Err(IceError(
+                                "peer connection unexpectedly closed".to_string(),
+                            ));
+                        }
+                        _ => {}
+                    },
+                    _ => {}
+                },
+                None => unreachable!(),
+            }
+        }
+    })
+    .await
+    {
+        Ok(r) => r,
+        Err(_) => Err(IceError(
+            "timed out waiting for peer connection".to_string(),
+        )),
+    }
+}
+
 pub async fn connect(
     addr: &str,
     peer_conn: &mut datachannel_wrapper::PeerConnection,
     mut event_rx: tokio::sync::mpsc::Receiver<datachannel_wrapper::PeerConnectionEvent>,
     session_id: &str,
-) -> Result<(), anyhow::Error> {
+) -> Result<Route, anyhow::Error> {
     let mut url = url::Url::parse(addr)?;
     url.set_query(Some(
         &url::form_urlencoded::Serializer::new(String::new())
@@ -121,6 +206,12 @@ pub async fn connect(
                     Some(tango_protos::signaling::packet::Which::IceCandidate(_ice_candidate)) => {
                         anyhow::bail!("ice candidates not supported");
                     }
+                    Some(tango_protos::signaling::packet::Which::Error(error)) => {
+                        return Err(ServerError {
+                            code: tango_protos::signaling::packet::error::Code::from_i32(error.code).unwrap_or(tango_protos::signaling::packet::error::Code::Unknown),
+                            message: error.message,
+                        }.into());
+                    }
                     p => {
                         anyhow::bail!("unexpected packet: {:?}", p);
                     }
@@ -129,31 +220,362 @@ pub async fn connect(
         };
     }
 
-    stream.close(None).await?;
+    // Stay on the line until we know how the other side fared, so that we both reach the same conclusion about whether to fall back to the relay.
+    let connected = match wait_for_connection(&mut event_rx).await {
+        Ok(()) => true,
+        Err(e) => {
+            log::warn!("{}", e);
+            false
+        }
+    };
+    if let Err(e) = stream
+        .send(tokio_tungstenite::tungstenite::Message::Binary(
+            tango_protos::signaling::Packet {
+                which: Some(tango_protos::signaling::packet::Which::ConnectionResult(
+                    tango_protos::signaling::packet::ConnectionResult { connected },
+                )),
+            }
+            .encode_to_vec(),
+        ))
+        .await
+    {
+        if connected {
+            log::warn!("could not send connection result, going direct: {}", e);
+            return Ok(Route::Direct);
+        }
+        return Err(e.into());
+    }
 
-    loop {
-        match event_rx.recv().await {
-            Some(signal) => match signal {
-                datachannel_wrapper::PeerConnectionEvent::ConnectionStateChange(c) => match c {
-                    datachannel_wrapper::ConnectionState::Connected => {
-                        break;
-                    }
-                    datachannel_wrapper::ConnectionState::Disconnected => {
-                        anyhow::bail!("peer connection unexpectedly disconnected");
-                    }
-                    datachannel_wrapper::ConnectionState::Failed => {
-                        anyhow::bail!("peer connection failed");
-                    }
-                    datachannel_wrapper::ConnectionState::Closed => {
-                        anyhow::bail!("peer connection unexpectedly closed");
-                    }
-                    _ => {}
+    let mut remote_connected = None;
+    let mut relay_token = None;
+    let (remote_connected, relay_token) = loop {
+        if let (Some(remote_connected), Some(relay_token)) =
+            (remote_connected, relay_token.as_ref())
+        {
+            break (remote_connected, relay_token.clone());
+        }
+
+        // Servers from before relay fallback never send a ticket, and hang up on us when they see our connection result. That's fine if we're connected: we only need the ticket if we have to fall back.
+        let raw = if connected && relay_token.is_none() {
+            match tokio::time::timeout(RELAY_TICKET_TIMEOUT, stream.try_next()).await {
+                Ok(Ok(Some(raw))) => raw,
+                Ok(Ok(None)) | Ok(Err(_)) | Err(_) => {
+                    log::info!("signaling server did not send a relay ticket, going direct");
+                    return Ok(Route::Direct);
+                }
+            }
+        } else if let Some(raw) = stream.try_next().await? {
+            raw
+        } else {
+            anyhow::bail!("stream ended early");
+        };
+
+        let packet = match raw {
+            tokio_tungstenite::tungstenite::Message::Binary(d) => {
+                tango_protos::signaling::Packet::decode(bytes::Bytes::from(d))?
+            }
+            tokio_tungstenite::tungstenite::Message::Ping(_)
+            | tokio_tungstenite::tungstenite::Message::Pong(_) => {
+                continue;
+            }
+            _ => {
+                anyhow::bail!("invalid packet");
+            }
+        };
+
+        match packet.which {
+            Some(tango_protos::signaling::packet::Which::ConnectionResult(connection_result)) => {
+                remote_connected = Some(connection_result.connected);
+            }
+            Some(tango_protos::signaling::packet::Which::RelayTicket(relay_ticket)) => {
+                relay_token = Some(relay_ticket.token);
+            }
+            Some(tango_protos::signaling::packet::Which::Error(error)) => {
+                return Err(ServerError {
+                    code: tango_protos::signaling::packet::error::Code::from_i32(error.code)
+                        .unwrap_or(tango_protos::signaling::packet::error::Code::Unknown),
+                    message: error.message,
+                }
+                .into());
+            }
+            p => {
+                anyhow::bail!("unexpected packet: {:?}", p);
+            }
+        }
+    };
+
+    // We've heard everything we need from the server, so it doesn't matter if it has hung up on us already.
+    let _ = stream.close(None).await;
+
+    if connected && remote_connected {
+        return Ok(Route::Direct);
+    }
+
+    if relay_token.is_empty() {
+        return Err(IceError(if connected {
+            "remote could not connect to us, and there is no relay to fall back to".to_string()
+        } else {
+            "could not connect to remote, and there is no relay to fall back to".to_string()
+        })
+        .into());
+    }
+
+    Ok(Route::Relay(relay_token))
+}
+
+/// A stand-in for the signaling server, for exercising negotiation without one.
+#[cfg(test)]
+pub(crate) mod testing {
+    use futures_util::SinkExt;
+    use futures_util::StreamExt;
+    use prost::Message;
+
+    type Stream = tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>;
+
+    async fn accept(listener: &tokio::net::TcpListener) -> Stream {
+        let (stream, _) = listener.accept().await.unwrap();
+        tokio_tungstenite::accept_async(stream).await.unwrap()
+    }
+
+    async fn receive(stream: &mut Stream) -> Option<tango_protos::signaling::packet::Which> {
+        loop {
+            match stream.next().await? {
+                Ok(tokio_tungstenite::tungstenite::Message::Binary(d)) => {
+                    return tango_protos::signaling::Packet::decode(bytes::Bytes::from(d))
+                        .unwrap()
+                        .which;
+                }
+                Ok(tokio_tungstenite::tungstenite::Message::Close(_)) | Err(_) => {
+                    return None;
+                }
+                Ok(_) => {}
+            }
+        }
+    }
+
+    async fn send(stream: &mut Stream, which: tango_protos::signaling::packet::Which) {
+        stream
+            .send(tokio_tungstenite::tungstenite::Message::Binary(
+                tango_protos::signaling::Packet { which: Some(which) }.encode_to_vec(),
+            ))
+            .await
+            .unwrap();
+    }
+
+    async fn exchange_connection_result(
+        stream: &mut Stream,
+        relay_token: &str,
+    ) -> tango_protos::signaling::packet::ConnectionResult {
+        let connection_result = match receive(stream).await {
+            Some(tango_protos::signaling::packet::Which::ConnectionResult(connection_result)) => {
+                connection_result
+            }
+            p => panic!("expected connection result, got {:?}", p),
+        };
+        send(
+            stream,
+            tango_protos::signaling::packet::Which::RelayTicket(
+                tango_protos::signaling::packet::RelayTicket {
+                    token: relay_token.to_string(),
                 },
-                _ => {}
-            },
-            None => unreachable!(),
+            ),
+        )
+        .await;
+        connection_result
+    }
+
+    /// Serves a single session between the next two clients to connect, as the signaling server would, returning the address to connect to.
+    ///
+    /// The first client to connect is the impolite side: the second gets its offer, and the first gets the second's answer. Both are given the same relay ticket, which may be empty for there being no relay.
+    pub async fn serve_one_session(relay_token: &str) -> String {
+        serve(Some(relay_token.to_string())).await
+    }
+
+    /// Like [`serve_one_session`], but as a server from before relay fallback would: it hangs up on clients once they send their connection result.
+    pub async fn serve_one_legacy_session() -> String {
+        serve(None).await
+    }
+
+    async fn serve(relay_token: Option<String>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut first = accept(&listener).await;
+            let offer_sdp = match receive(&mut first).await {
+                Some(tango_protos::signaling::packet::Which::Start(start)) => start.offer_sdp,
+                p => panic!("expected start, got {:?}", p),
+            };
+
+            let mut second = accept(&listener).await;
+            match receive(&mut second).await {
+                Some(tango_protos::signaling::packet::Which::Start(_)) => {}
+                p => panic!("expected start, got {:?}", p),
+            }
+            send(
+                &mut second,
+                tango_protos::signaling::packet::Which::Offer(
+                    tango_protos::signaling::packet::Offer { sdp: offer_sdp },
+                ),
+            )
+            .await;
+
+            let answer_sdp = match receive(&mut second).await {
+                Some(tango_protos::signaling::packet::Which::Answer(answer)) => answer.sdp,
+                p => panic!("expected answer, got {:?}", p),
+            };
+            send(
+                &mut first,
+                tango_protos::signaling::packet::Which::Answer(
+                    tango_protos::signaling::packet::Answer { sdp: answer_sdp },
+                ),
+            )
+            .await;
+
+            let relay_token = match relay_token {
+                Some(relay_token) => relay_token,
+                None => {
+                    // Unknown packets get the connection dropped.
+                    receive(&mut first).await;
+                    receive(&mut second).await;
+                    return;
+                }
+            };
+
+            let first_result = exchange_connection_result(&mut first, &relay_token).await;
+            let second_result = exchange_connection_result(&mut second, &relay_token).await;
+            send(
+                &mut first,
+                tango_protos::signaling::packet::Which::ConnectionResult(second_result),
+            )
+            .await;
+            send(
+                &mut second,
+                tango_protos::signaling::packet::Which::ConnectionResult(first_result),
+            )
+            .await;
+
+            // Wait for both sides to hang up.
+            while receive(&mut first).await.is_some() {}
+            while receive(&mut second).await.is_some() {}
+        });
+        addr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn peer_connection(
+        network: &datachannel_wrapper::mock::Network,
+    ) -> (
+        datachannel_wrapper::PeerConnection,
+        tokio::sync::mpsc::Receiver<datachannel_wrapper::PeerConnectionEvent>,
+        datachannel_wrapper::DataChannel,
+    ) {
+        let (mut peer_conn, mut event_rx) = network.peer_connection();
+        let dc = peer_conn
+            .create_data_channel("tango", datachannel_wrapper::DataChannelInit::default())
+            .unwrap();
+        match event_rx.recv().await {
+            Some(datachannel_wrapper::PeerConnectionEvent::GatheringStateChange(
+                datachannel_wrapper::GatheringState::Complete,
+            )) => {}
+            e => panic!("expected candidates to be gathered, got {:?}", e),
         }
+        (peer_conn, event_rx, dc)
+    }
+
+    async fn connect_both(
+        addr: String,
+        network: &datachannel_wrapper::mock::Network,
+    ) -> (
+        (
+            anyhow::Result<Route>,
+            datachannel_wrapper::PeerConnection,
+            datachannel_wrapper::DataChannel,
+        ),
+        (
+            anyhow::Result<Route>,
+            datachannel_wrapper::PeerConnection,
+            datachannel_wrapper::DataChannel,
+        ),
+    ) {
+        let (mut a, a_event_rx, a_dc) = peer_connection(network).await;
+        let (mut b, b_event_rx, b_dc) = peer_connection(network).await;
+        let (a_r, b_r) = tokio::time::timeout(std::time::Duration::from_secs(10), async {
+            tokio::join!(
+                connect(&addr, &mut a, a_event_rx, "session"),
+                connect(&addr, &mut b, b_event_rx, "session"),
+            )
+        })
+        .await
+        .expect("timed out");
+        ((a_r, a, a_dc), (b_r, b, b_dc))
+    }
+
+    #[tokio::test]
+    async fn connect_pairs_offerer_with_answerer() {
+        let ((a_r, a, mut a_dc), (b_r, b, mut b_dc)) = connect_both(
+            testing::serve_one_session("ticket").await,
+            &datachannel_wrapper::mock::Network::new(),
+        )
+        .await;
+        assert_eq!(a_r.unwrap(), Route::Direct);
+        assert_eq!(b_r.unwrap(), Route::Direct);
+
+        // Whoever connected first made the offer, and the other answered it.
+        assert!(matches!(
+            (
+                a.remote_description().unwrap().sdp_type,
+                b.remote_description().unwrap().sdp_type,
+            ),
+            (
+                datachannel_wrapper::SdpType::Answer,
+                datachannel_wrapper::SdpType::Offer
+            ) | (
+                datachannel_wrapper::SdpType::Offer,
+                datachannel_wrapper::SdpType::Answer
+            )
+        ));
+
+        a_dc.send(b"hello").await.unwrap();
+        assert_eq!(b_dc.receive().await.unwrap(), b"hello");
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn connect_falls_back_to_relay_together() {
+        let ((a_r, _, _), (b_r, _, _)) = connect_both(
+            testing::serve_one_session("ticket").await,
+            &datachannel_wrapper::mock::Network::unreachable(),
+        )
+        .await;
+        assert_eq!(a_r.unwrap(), Route::Relay("ticket".to_string()));
+        assert_eq!(b_r.unwrap(), Route::Relay("ticket".to_string()));
+    }
+
+    #[tokio::test]
+    async fn connect_fails_without_relay() {
+        let ((a_r, _, _), (b_r, _, _)) = connect_both(
+            testing::serve_one_session("").await,
+            &datachannel_wrapper::mock::Network::unreachable(),
+        )
+        .await;
+        assert!(a_r.unwrap_err().downcast::<IceError>().is_ok());
+        assert!(b_r.unwrap_err().downcast::<IceError>().is_ok());
+    }
+
+    #[tokio::test]
+    async fn connect_goes_direct_with_legacy_server() {
+        let ((a_r, _, mut a_dc), (b_r, _, mut b_dc)) = connect_both(
+            testing::serve_one_legacy_session().await,
+            &datachannel_wrapper::mock::Network::new(),
+        )
+        .await;
+        assert_eq!(a_r.unwrap(), Route::Direct);
+        assert_eq!(b_r.unwrap(), Route::Direct);
+
+        a_dc.send(b"hello").await.unwrap();
+        assert_eq!(b_dc.receive().await.unwrap(), b"hello");
+    }
 }
@@ -0,0 +1,88 @@
+pub const WARNING_THRESHOLDS: &[std::time::Duration] = &[
+    std::time::Duration::from_secs(2),
+    std::time::Duration::from_secs(5),
+    std::time::Duration::from_secs(10),
+];
+
+pub const DEFAULT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(20);
+
+pub enum Status {
+    Ok,
+    Warning(std::time::Duration),
+    Resumed,
+    TimedOut(std::time::Duration),
+}
+
+struct State {
+    last_remote_input_at: Option<std::time::Instant>,
+    warnings_sent: usize,
+}
+
+pub struct Detector {
+    timeout: std::time::Duration,
+    state: parking_lot::Mutex<State>,
+}
+
+impl Detector {
+    pub fn new(timeout: std::time::Duration) -> Self {
+        Self {
+            timeout,
+            state: parking_lot::Mutex::new(State {
+                last_remote_input_at: None,
+                warnings_sent: 0,
+            }),
+        }
+    }
+
+    /// Starts expecting remote input, i.e. when a round starts.
+    pub fn arm(&self) {
+        let mut state = self.state.lock();
+        state.last_remote_input_at = Some(std::time::Instant::now());
+        state.warnings_sent = 0;
+    }
+
+    /// Stops expecting remote input, i.e. between rounds where the remote may sit in menus for as long as they like.
+    pub fn disarm(&self) {
+        let mut state = self.state.lock();
+        state.last_remote_input_at = None;
+        state.warnings_sent = 0;
+    }
+
+    pub fn mark_remote_input(&self) {
+        let mut state = self.state.lock();
+        if state.last_remote_input_at.is_some() {
+            state.last_remote_input_at = Some(std::time::Instant::now());
+        }
+    }
+
+    pub fn poll(&self) -> Status {
+        let mut state = self.state.lock();
+        let last_remote_input_at = match state.last_remote_input_at {
+            Some(last_remote_input_at) => last_remote_input_at,
+            None => {
+                return Status::Ok;
+            }
+        };
+
+        let elapsed = last_remote_input_at.elapsed();
+        if elapsed >= self.timeout {
+            return Status::TimedOut(elapsed);
+        }
+
+        let warnings_due = WARNING_THRESHOLDS
+            .iter()
+            .filter(|threshold| elapsed >= **threshold && **threshold < self.timeout)
+            .count();
+        if warnings_due > state.warnings_sent {
+            state.warnings_sent = warnings_due;
+            return Status::Warning(elapsed);
+        }
+
+        if warnings_due == 0 && state.warnings_sent > 0 {
+            state.warnings_sent = 0;
+            return Status::Resumed;
+        }
+
+        Status::Ok
+    }
+}
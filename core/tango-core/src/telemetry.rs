@@ -0,0 +1,246 @@
+use crate::battle;
+use std::io::Write;
+
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct Sample {
+    pub ts: u64,
+    pub round_number: u8,
+    pub rtt: u64,
+    pub jitter: u64,
+    pub local_queue_length: usize,
+    pub remote_queue_length: usize,
+    pub tps_adjustment: f32,
+    pub dtick: i32,
+    pub predicted_frames_per_tick: f32,
+    pub max_rollback_depth: u32,
+}
+
+impl Sample {
+    pub fn to_ipc(&self) -> crate::ipc::protos::from_core_message::TelemetryEvent {
+        crate::ipc::protos::from_core_message::TelemetryEvent {
+            round_number: self.round_number as u32,
+            rtt: self.rtt,
+            jitter: self.jitter,
+            local_queue_length: self.local_queue_length as u32,
+            remote_queue_length: self.remote_queue_length as u32,
+            tps_adjustment: self.tps_adjustment,
+            dtick: self.dtick,
+            predicted_frames_per_tick: self.predicted_frames_per_tick,
+            max_rollback_depth: self.max_rollback_depth,
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    round_number: u8,
+    rtt: Option<std::time::Duration>,
+    jitter: std::time::Duration,
+    local_queue_length: usize,
+    remote_queue_length: usize,
+    tps_adjustment: f32,
+    dtick: i32,
+    ticks: u32,
+    predicted_frames: u32,
+    max_rollback_depth: u32,
+}
+
+/// Collects connection quality figures from the round and the ping loop, to be drained periodically as a sample.
+#[derive(Default)]
+pub struct Collector {
+    state: parking_lot::Mutex<State>,
+}
+
+impl Collector {
+    pub fn add_rtt(&self, rtt: std::time::Duration) {
+        let mut state = self.state.lock();
+        // Interarrival jitter as in RFC 3550, section 6.4.1.
+        if let Some(last_rtt) = state.rtt {
+            let d = if rtt > last_rtt {
+                rtt - last_rtt
+            } else {
+                last_rtt - rtt
+            };
+            state.jitter = if d > state.jitter {
+                state.jitter + (d - state.jitter) / 16
+            } else {
+                state.jitter - (state.jitter - d) / 16
+            };
+        }
+        state.rtt = Some(rtt);
+    }
+
+    pub fn rtt(&self) -> Option<std::time::Duration> {
+        self.state.lock().rtt
+    }
+
+    pub fn add_tick(&self, round: &battle::Round, predicted_frames: u32, rollback_depth: u32) {
+        let mut state = self.state.lock();
+        state.round_number = round.number();
+        state.local_queue_length = round.local_queue_length();
+        state.remote_queue_length = round.remote_queue_length();
+        state.tps_adjustment = round.tps_adjustment();
+        state.dtick = round.dtick();
+        state.ticks += 1;
+        state.predicted_frames += predicted_frames;
+        state.max_rollback_depth = state.max_rollback_depth.max(rollback_depth);
+    }
+
+    pub fn take_sample(&self) -> Sample {
+        let mut state = self.state.lock();
+        let sample = Sample {
+            ts: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or(0),
+            round_number: state.round_number,
+            rtt: state.rtt.map(|rtt| rtt.as_nanos() as u64).unwrap_or(0),
+            jitter: state.jitter.as_nanos() as u64,
+            local_queue_length: state.local_queue_length,
+            remote_queue_length: state.remote_queue_length,
+            tps_adjustment: state.tps_adjustment,
+            dtick: state.dtick,
+            predicted_frames_per_tick: if state.ticks > 0 {
+                state.predicted_frames as f32 / state.ticks as f32
+            } else {
+                0.0
+            },
+            max_rollback_depth: state.max_rollback_depth,
+        };
+        state.ticks = 0;
+        state.predicted_frames = 0;
+        state.max_rollback_depth = 0;
+        sample
+    }
+}
+
+/// Where match traffic is going and how much of it there has been.
+#[derive(Clone, Debug)]
+pub struct ConnectionStats {
+    pub peer_conn: datachannel_wrapper::PeerConnectionStats,
+    pub data_channel: datachannel_wrapper::DataChannelStats,
+    pub rtt: Option<std::time::Duration>,
+}
+
+fn candidate_type_to_ipc(
+    candidate_type: datachannel_wrapper::CandidateType,
+) -> crate::ipc::protos::from_core_message::connection_stats_event::CandidateType {
+    match candidate_type {
+        datachannel_wrapper::CandidateType::Host => {
+            crate::ipc::protos::from_core_message::connection_stats_event::CandidateType::Host
+        }
+        datachannel_wrapper::CandidateType::ServerReflexive => crate::ipc::protos::from_core_message::connection_stats_event::CandidateType::ServerReflexive,
+        datachannel_wrapper::CandidateType::PeerReflexive => crate::ipc::protos::from_core_message::connection_stats_event::CandidateType::PeerReflexive,
+        datachannel_wrapper::CandidateType::Relayed => {
+            crate::ipc::protos::from_core_message::connection_stats_event::CandidateType::Relayed
+        }
+    }
+}
+
+impl ConnectionStats {
+    pub fn is_server_relayed(&self) -> bool {
+        self.data_channel.relayed
+    }
+
+    /// Describes the route in the terms users care about, e.g. whether they're going through a TURN relay.
+    pub fn route(&self) -> &'static str {
+        if self.is_server_relayed() {
+            return "server relay";
+        }
+        let is_relayed = |candidate: &Option<datachannel_wrapper::Candidate>| {
+            candidate
+                .as_ref()
+                .map(|c| c.candidate_type == datachannel_wrapper::CandidateType::Relayed)
+                .unwrap_or(false)
+        };
+        if is_relayed(&self.peer_conn.local_candidate)
+            || is_relayed(&self.peer_conn.remote_candidate)
+        {
+            "turn relay"
+        } else {
+            "direct"
+        }
+    }
+
+    pub fn to_ipc(&self) -> crate::ipc::protos::from_core_message::ConnectionStatsEvent {
+        let (local_candidate_type, local_address) = match self.peer_conn.local_candidate.as_ref()
+        {
+            Some(c) => (candidate_type_to_ipc(c.candidate_type), c.address.to_string()),
+            None => (
+                crate::ipc::protos::from_core_message::connection_stats_event::CandidateType::Unknown,
+                "".to_string(),
+            ),
+        };
+        let (remote_candidate_type, remote_address) =
+            match self.peer_conn.remote_candidate.as_ref() {
+                Some(c) => (candidate_type_to_ipc(c.candidate_type), c.address.to_string()),
+                None => (
+                    crate::ipc::protos::from_core_message::connection_stats_event::CandidateType::Unknown,
+                    "".to_string(),
+                ),
+            };
+        crate::ipc::protos::from_core_message::ConnectionStatsEvent {
+            server_relayed: self.is_server_relayed(),
+            local_candidate_type: local_candidate_type.into(),
+            local_address,
+            remote_candidate_type: remote_candidate_type.into(),
+            remote_address,
+            rtt: self.rtt.map(|rtt| rtt.as_nanos() as u64).unwrap_or(0),
+            bytes_sent: self.data_channel.bytes_sent,
+            bytes_received: self.data_channel.bytes_received,
+            buffered_amount: self.data_channel.buffered_amount as u64,
+        }
+    }
+}
+
+pub enum Format {
+    Csv,
+    JsonLines,
+}
+
+pub struct Writer {
+    file: std::io::BufWriter<std::fs::File>,
+    format: Format,
+}
+
+impl Writer {
+    /// Opens a telemetry file, picking CSV if the path ends in .csv and JSON lines otherwise.
+    pub fn create(path: &std::path::Path) -> std::io::Result<Self> {
+        let format = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("csv") => Format::Csv,
+            _ => Format::JsonLines,
+        };
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        if let Format::Csv = format {
+            writeln!(file, "ts,round_number,rtt,jitter,local_queue_length,remote_queue_length,tps_adjustment,dtick,predicted_frames_per_tick,max_rollback_depth")?;
+        }
+        Ok(Self { file, format })
+    }
+
+    pub fn write(&mut self, sample: &Sample) -> anyhow::Result<()> {
+        match self.format {
+            Format::Csv => {
+                writeln!(
+                    self.file,
+                    "{},{},{},{},{},{},{},{},{},{}",
+                    sample.ts,
+                    sample.round_number,
+                    sample.rtt,
+                    sample.jitter,
+                    sample.local_queue_length,
+                    sample.remote_queue_length,
+                    sample.tps_adjustment,
+                    sample.dtick,
+                    sample.predicted_frames_per_tick,
+                    sample.max_rollback_depth
+                )?;
+            }
+            Format::JsonLines => {
+                serde_json::to_writer(&mut self.file, sample)?;
+                self.file.write_all(b"\n")?;
+            }
+        }
+        self.file.flush()?;
+        Ok(())
+    }
+}
@@ -1,17 +1,202 @@
 use crate::protocol;
 
+// Anything older than this will have long since arrived over the reliable channel anyway: the unreliable channel only ever gets inputs to the remote sooner, so dropping them here never loses anything.
+const MAX_REDUNDANT_INPUTS: usize = 8;
+
+#[derive(Default)]
+struct RedundancyState {
+    unacked: std::collections::VecDeque<protocol::Input>,
+    next_remote: (u8, u32),
+}
+
+/// Keeps track of which inputs each side has received, for the redundant inputs sent over the unreliable channel.
+#[derive(Default)]
+pub struct Redundancy {
+    state: parking_lot::Mutex<RedundancyState>,
+}
+
+impl Redundancy {
+    /// Records that we now expect the given tick next from the remote, which we'll acknowledge in our next packet.
+    pub fn mark_received(&self, round_number: u8, next_tick: u32) {
+        self.state.lock().next_remote = (round_number, next_tick);
+    }
+
+    /// Forgets about every input before the given one, as the remote has told us it has them.
+    pub fn ack(&self, round_number: u8, tick: u32) {
+        let mut state = self.state.lock();
+        while let Some(input) = state.unacked.front() {
+            if input.round_number > round_number
+                || (input.round_number == round_number && input.local_tick >= tick)
+            {
+                break;
+            }
+            state.unacked.pop_front();
+        }
+    }
+
+    fn push(&self, input: protocol::Input) -> protocol::RedundantInputs {
+        let mut state = self.state.lock();
+        if state.unacked.len() >= MAX_REDUNDANT_INPUTS {
+            state.unacked.pop_front();
+        }
+        state.unacked.push_back(input);
+        protocol::RedundantInputs {
+            ack_round_number: state.next_remote.0,
+            ack_tick: state.next_remote.1,
+            inputs: state.unacked.iter().cloned().collect(),
+        }
+    }
+}
+
+/// Something received from the remote.
+#[derive(Debug)]
+pub enum Received {
+    /// An input, along with whether it arrived over the reliable channel.
+    Input(protocol::Input, bool),
+    Ping(u64),
+    Pong(u64),
+}
+
+/// Receives from both data channels, handing out only the inputs we can use next.
+pub struct Receiver {
+    dc_rx: datachannel_wrapper::DataChannelReceiver,
+    unreliable_dc_rx: Option<datachannel_wrapper::DataChannelReceiver>,
+    redundancy: std::sync::Arc<Redundancy>,
+    pending: std::collections::VecDeque<(protocol::Input, bool)>,
+    round_number: u8,
+    next_tick: u32,
+}
+
+impl Receiver {
+    pub fn new(
+        dc_rx: datachannel_wrapper::DataChannelReceiver,
+        unreliable_dc_rx: Option<datachannel_wrapper::DataChannelReceiver>,
+        redundancy: std::sync::Arc<Redundancy>,
+    ) -> Receiver {
+        Receiver {
+            dc_rx,
+            unreliable_dc_rx,
+            redundancy,
+            pending: std::collections::VecDeque::new(),
+            round_number: 0,
+            next_tick: 0,
+        }
+    }
+
+    pub fn round_number(&self) -> u8 {
+        self.round_number
+    }
+
+    /// Starts expecting inputs for the given round, from its first tick.
+    pub fn start_round(&mut self, round_number: u8) {
+        self.round_number = round_number;
+        self.next_tick = 0;
+    }
+
+    /// Moves on to the next tick once the input handed out for this one has been used.
+    ///
+    /// Until then, the same input may be handed out again when its copy from the other channel turns up.
+    pub fn mark_received(&mut self) {
+        self.next_tick += 1;
+        self.redundancy
+            .mark_received(self.round_number, self.next_tick);
+    }
+
+    fn is_wanted(&self, input: &protocol::Input, reliable: bool) -> bool {
+        if input.round_number == self.round_number {
+            // Each input may arrive over both channels, and the unreliable one may skip some or deliver them out of order: only take the one we're expecting next, and let the reliable channel fill in any gaps.
+            return input.local_tick == self.next_tick;
+        }
+
+        // Only the reliable channel may move us on to the next round, as whoever receives it may need to wait for that round to start: every input also arrives over it, so inputs from the unreliable channel can just be dropped instead of holding up the reliable channel.
+        //
+        // Stragglers from earlier rounds are always dropped.
+        reliable && input.round_number > self.round_number
+    }
+
+    /// Waits for the next input we can use, or for a ping or pong.
+    ///
+    /// Returns None once the reliable channel has closed.
+    pub async fn receive(&mut self) -> anyhow::Result<Option<Received>> {
+        loop {
+            while let Some((input, reliable)) = self.pending.pop_front() {
+                if self.is_wanted(&input, reliable) {
+                    return Ok(Some(Received::Input(input, reliable)));
+                }
+            }
+
+            let buf = tokio::select! {
+                buf = self.dc_rx.receive() => buf,
+                buf = async {
+                    match self.unreliable_dc_rx.as_mut() {
+                        Some(unreliable_dc_rx) => unreliable_dc_rx.receive().await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    match buf {
+                        Some(buf) => Some(buf),
+                        None => {
+                            // We can still carry on without it.
+                            log::warn!("unreliable data channel closed");
+                            self.unreliable_dc_rx = None;
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            let buf = match buf {
+                None => {
+                    log::info!("data channel closed");
+                    return Ok(None);
+                }
+                Some(buf) => buf,
+            };
+
+            match protocol::Packet::deserialize(buf.as_slice())? {
+                protocol::Packet::Input(input) => {
+                    self.pending.push_back((input, true));
+                }
+                protocol::Packet::RedundantInputs(redundant_inputs) => {
+                    self.redundancy
+                        .ack(redundant_inputs.ack_round_number, redundant_inputs.ack_tick);
+                    self.pending.extend(
+                        redundant_inputs
+                            .inputs
+                            .into_iter()
+                            .map(|input| (input, false)),
+                    );
+                }
+                protocol::Packet::Ping(protocol::Ping { ts }) => {
+                    return Ok(Some(Received::Ping(ts)));
+                }
+                protocol::Packet::Pong(protocol::Pong { ts }) => {
+                    return Ok(Some(Received::Pong(ts)));
+                }
+                p => anyhow::bail!("unknown packet: {:?}", p),
+            }
+        }
+    }
+}
+
 pub struct Transport {
     dc_tx: datachannel_wrapper::DataChannelSender,
+    unreliable_dc_tx: Option<datachannel_wrapper::DataChannelSender>,
+    redundancy: std::sync::Arc<Redundancy>,
     rendezvous_rx: Option<tokio::sync::oneshot::Receiver<()>>,
 }
 
 impl Transport {
     pub fn new(
         dc_tx: datachannel_wrapper::DataChannelSender,
+        unreliable_dc_tx: Option<datachannel_wrapper::DataChannelSender>,
+        redundancy: std::sync::Arc<Redundancy>,
         rendezvous_rx: tokio::sync::oneshot::Receiver<()>,
     ) -> Transport {
         Transport {
             dc_tx,
+            unreliable_dc_tx,
+            redundancy,
             rendezvous_rx: Some(rendezvous_rx),
         }
     }
@@ -23,21 +208,187 @@ impl Transport {
         tick_diff: i8,
         joyflags: u16,
     ) -> anyhow::Result<()> {
+        let input = protocol::Input {
+            round_number,
+            local_tick,
+            tick_diff,
+            joyflags,
+        };
+
+        // Whatever makes it over the unreliable channel first wins: the reliable channel makes sure everything arrives eventually.
+        if let Some(unreliable_dc_tx) = self.unreliable_dc_tx.as_mut() {
+            unreliable_dc_tx
+                .send(
+                    protocol::Packet::RedundantInputs(self.redundancy.push(input.clone()))
+                        .serialize()?
+                        .as_slice(),
+                )
+                .await?;
+        }
+
         self.dc_tx
-            .send(
-                protocol::Packet::Input(protocol::Input {
-                    round_number,
-                    local_tick,
-                    tick_diff,
-                    joyflags,
-                })
-                .serialize()?
-                .as_slice(),
-            )
+            .send(protocol::Packet::Input(input).serialize()?.as_slice())
             .await?;
         if let Some(rendezvous_rx) = self.rendezvous_rx.take() {
             rendezvous_rx.await?;
         }
         Ok(())
     }
+
+    pub fn stats(&self) -> datachannel_wrapper::DataChannelStats {
+        self.dc_tx.stats()
+    }
+
+    pub async fn send_ping(&mut self, ts: u64) -> anyhow::Result<()> {
+        self.dc_tx
+            .send(
+                protocol::Packet::Ping(protocol::Ping { ts })
+                    .serialize()?
+                    .as_slice(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn send_pong(&mut self, ts: u64) -> anyhow::Result<()> {
+        self.dc_tx
+            .send(
+                protocol::Packet::Pong(protocol::Pong { ts })
+                    .serialize()?
+                    .as_slice(),
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transport(
+        dc: datachannel_wrapper::DataChannel,
+        unreliable_dc: Option<datachannel_wrapper::DataChannel>,
+    ) -> Transport {
+        let (dc_tx, _) = dc.split();
+        let (rendezvous_tx, rendezvous_rx) = tokio::sync::oneshot::channel();
+        rendezvous_tx.send(()).unwrap();
+        Transport::new(
+            dc_tx,
+            unreliable_dc.map(|dc| dc.split().0),
+            std::sync::Arc::new(Redundancy::default()),
+            rendezvous_rx,
+        )
+    }
+
+    fn receiver(
+        dc: datachannel_wrapper::DataChannel,
+        unreliable_dc: Option<datachannel_wrapper::DataChannel>,
+    ) -> Receiver {
+        Receiver::new(
+            dc.split().1,
+            unreliable_dc.map(|dc| dc.split().1),
+            std::sync::Arc::new(Redundancy::default()),
+        )
+    }
+
+    async fn receive_input(receiver: &mut Receiver) -> (protocol::Input, bool) {
+        match tokio::time::timeout(std::time::Duration::from_secs(5), receiver.receive())
+            .await
+            .expect("timed out")
+            .unwrap()
+        {
+            Some(Received::Input(input, reliable)) => (input, reliable),
+            r => panic!("expected input, got {:?}", r),
+        }
+    }
+
+    async fn receive_ticks(receiver: &mut Receiver, round_number: u8, n: u32) {
+        for tick in 0..n {
+            let (input, _) = receive_input(receiver).await;
+            if input.round_number != receiver.round_number() {
+                receiver.start_round(input.round_number);
+            }
+            assert_eq!(input.round_number, round_number);
+            assert_eq!(input.local_tick, tick);
+            assert_eq!(input.joyflags, tick as u16);
+            receiver.mark_received();
+        }
+    }
+
+    #[tokio::test]
+    async fn hands_out_each_input_once_in_order() {
+        let (dc, remote_dc) = datachannel_wrapper::mock::pair(64);
+        let (unreliable_dc, remote_unreliable_dc) = datachannel_wrapper::mock::pair(64);
+        let mut transport = transport(dc, Some(unreliable_dc));
+        let mut receiver = receiver(remote_dc, Some(remote_unreliable_dc));
+
+        for tick in 0..32 {
+            transport.send_input(1, tick, 0, tick as u16).await.unwrap();
+        }
+        receive_ticks(&mut receiver, 1, 32).await;
+
+        // Everything left over is a duplicate, so nothing else may be handed out.
+        transport.send_ping(1234).await.unwrap();
+        match receiver.receive().await.unwrap() {
+            Some(Received::Ping(1234)) => {}
+            r => panic!("expected ping, got {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn lost_unreliable_inputs_are_filled_in_by_reliable_channel() {
+        let (dc, remote_dc) = datachannel_wrapper::mock::pair(64);
+        let (unreliable_dc, mut unreliable_remote) = datachannel_wrapper::mock::new(64);
+        let (remote_unreliable_dc, remote_unreliable_remote) = datachannel_wrapper::mock::new(64);
+        unreliable_remote.open();
+        let mut transport = transport(dc, Some(unreliable_dc));
+        let mut receiver = receiver(remote_dc, Some(remote_unreliable_dc));
+
+        for tick in 0..32 {
+            transport.send_input(1, tick, 0, tick as u16).await.unwrap();
+        }
+        for i in 0..32 {
+            let msg = unreliable_remote.next_sent().await.unwrap();
+            if i % 3 == 0 {
+                remote_unreliable_remote.deliver(&msg).await.unwrap();
+            }
+        }
+        receive_ticks(&mut receiver, 1, 32).await;
+    }
+
+    #[tokio::test]
+    async fn inputs_not_marked_received_are_handed_out_again() {
+        let (dc, remote_dc) = datachannel_wrapper::mock::pair(64);
+        let (unreliable_dc, remote_unreliable_dc) = datachannel_wrapper::mock::pair(64);
+        let mut transport = transport(dc, Some(unreliable_dc));
+        let mut receiver = receiver(remote_dc, Some(remote_unreliable_dc));
+        receiver.start_round(1);
+
+        transport.send_input(1, 0, 0, 0).await.unwrap();
+        let (first, first_reliable) = receive_input(&mut receiver).await;
+        let (second, second_reliable) = receive_input(&mut receiver).await;
+        assert_eq!(first.local_tick, 0);
+        assert_eq!(second.local_tick, 0);
+        assert_ne!(first_reliable, second_reliable);
+    }
+
+    #[tokio::test]
+    async fn only_reliable_channel_moves_on_to_next_round() {
+        let (dc, remote_dc) = datachannel_wrapper::mock::pair(64);
+        let (unreliable_dc, mut unreliable_remote) = datachannel_wrapper::mock::new(64);
+        let (remote_unreliable_dc, remote_unreliable_remote) = datachannel_wrapper::mock::new(64);
+        unreliable_remote.open();
+        let mut transport = transport(dc, Some(unreliable_dc));
+        let mut receiver = receiver(remote_dc, Some(remote_unreliable_dc));
+
+        transport.send_input(2, 0, 0, 0).await.unwrap();
+
+        // Deliver the unreliable copy first: it must be skipped rather than handed out for a round we haven't started.
+        let msg = unreliable_remote.next_sent().await.unwrap();
+        remote_unreliable_remote.deliver(&msg).await.unwrap();
+        let (input, reliable) = receive_input(&mut receiver).await;
+        assert_eq!(input.round_number, 2);
+        assert!(reliable);
+    }
 }
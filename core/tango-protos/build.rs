@@ -5,6 +5,7 @@ fn main() -> Result<()> {
         &[
             "src/protos/signaling.proto",
             "src/protos/iceconfig.proto",
+            "src/protos/matchmaking.proto",
             "src/protos/replay.proto",
         ],
         &["src/"],
@@ -6,6 +6,10 @@ pub mod iceconfig {
     include!(concat!(env!("OUT_DIR"), "/tango.iceconfig.rs"));
 }
 
+pub mod matchmaking {
+    include!(concat!(env!("OUT_DIR"), "/tango.matchmaking.rs"));
+}
+
 pub mod replay {
     include!(concat!(env!("OUT_DIR"), "/tango.replay.rs"));
 }
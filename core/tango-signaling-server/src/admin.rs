@@ -0,0 +1,197 @@
+use routerify::ext::RequestExt;
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Returns the response to send instead if the request doesn't carry the admin token.
+fn check_authorization(
+    request: &hyper::Request<hyper::Body>,
+) -> Option<hyper::Response<hyper::Body>> {
+    // Without a token configured, the admin API doesn't exist as far as anyone can tell.
+    let admin_token = match request.data::<crate::State>().unwrap().admin_token.as_ref() {
+        Some(admin_token) => admin_token,
+        None => {
+            return Some(
+                hyper::Response::builder()
+                    .status(hyper::StatusCode::NOT_FOUND)
+                    .body(
+                        hyper::StatusCode::NOT_FOUND
+                            .canonical_reason()
+                            .unwrap()
+                            .into(),
+                    )
+                    .unwrap(),
+            );
+        }
+    };
+
+    match request
+        .headers()
+        .get(hyper::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+    {
+        Some(token) if constant_time_eq(token.as_bytes(), admin_token.as_bytes()) => None,
+        _ => Some(
+            hyper::Response::builder()
+                .status(hyper::StatusCode::UNAUTHORIZED)
+                .header(hyper::header::WWW_AUTHENTICATE, "Bearer")
+                .body(
+                    hyper::StatusCode::UNAUTHORIZED
+                        .canonical_reason()
+                        .unwrap()
+                        .into(),
+                )
+                .unwrap(),
+        ),
+    }
+}
+
+fn json_response<T: serde::Serialize>(v: &T) -> anyhow::Result<hyper::Response<hyper::Body>> {
+    Ok(hyper::Response::builder()
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(hyper::Body::from(serde_json::to_vec(v)?))?)
+}
+
+fn status_response(found: bool) -> hyper::Response<hyper::Body> {
+    let status = if found {
+        hyper::StatusCode::NO_CONTENT
+    } else {
+        hyper::StatusCode::NOT_FOUND
+    };
+    hyper::Response::builder()
+        .status(status)
+        .body(hyper::Body::empty())
+        .unwrap()
+}
+
+fn bad_request(message: &'static str) -> hyper::Response<hyper::Body> {
+    hyper::Response::builder()
+        .status(hyper::StatusCode::BAD_REQUEST)
+        .body(hyper::Body::from(message))
+        .unwrap()
+}
+
+async fn handle_list_sessions_request(
+    request: hyper::Request<hyper::Body>,
+) -> Result<hyper::Response<hyper::Body>, anyhow::Error> {
+    if let Some(response) = check_authorization(&request) {
+        return Ok(response);
+    }
+    let state = request.data::<crate::State>().unwrap();
+    json_response(&state.signaling_server.list_sessions().await?)
+}
+
+async fn handle_close_session_request(
+    request: hyper::Request<hyper::Body>,
+) -> Result<hyper::Response<hyper::Body>, anyhow::Error> {
+    if let Some(response) = check_authorization(&request) {
+        return Ok(response);
+    }
+    let state = request.data::<crate::State>().unwrap();
+    let session_id = request.param("session_id").unwrap();
+    Ok(status_response(
+        state.signaling_server.close_session(session_id).await?,
+    ))
+}
+
+async fn handle_list_bans_request(
+    request: hyper::Request<hyper::Body>,
+) -> Result<hyper::Response<hyper::Body>, anyhow::Error> {
+    if let Some(response) = check_authorization(&request) {
+        return Ok(response);
+    }
+    json_response(&request.data::<crate::State>().unwrap().bans.list())
+}
+
+async fn handle_ban_addr_request(
+    request: hyper::Request<hyper::Body>,
+) -> Result<hyper::Response<hyper::Body>, anyhow::Error> {
+    if let Some(response) = check_authorization(&request) {
+        return Ok(response);
+    }
+    let addr = match request.param("addr").unwrap().parse() {
+        Ok(addr) => addr,
+        Err(_) => {
+            return Ok(bad_request("invalid address"));
+        }
+    };
+    log::info!("banning address: {}", addr);
+    request.data::<crate::State>().unwrap().bans.ban_addr(addr);
+    Ok(status_response(true))
+}
+
+async fn handle_unban_addr_request(
+    request: hyper::Request<hyper::Body>,
+) -> Result<hyper::Response<hyper::Body>, anyhow::Error> {
+    if let Some(response) = check_authorization(&request) {
+        return Ok(response);
+    }
+    let addr = match request.param("addr").unwrap().parse() {
+        Ok(addr) => addr,
+        Err(_) => {
+            return Ok(bad_request("invalid address"));
+        }
+    };
+    log::info!("unbanning address: {}", addr);
+    Ok(status_response(
+        request
+            .data::<crate::State>()
+            .unwrap()
+            .bans
+            .unban_addr(addr),
+    ))
+}
+
+async fn handle_ban_client_version_request(
+    request: hyper::Request<hyper::Body>,
+) -> Result<hyper::Response<hyper::Body>, anyhow::Error> {
+    if let Some(response) = check_authorization(&request) {
+        return Ok(response);
+    }
+    let client_version = request.param("client_version").unwrap();
+    log::info!("banning client version: {}", client_version);
+    request
+        .data::<crate::State>()
+        .unwrap()
+        .bans
+        .ban_client_version(client_version);
+    Ok(status_response(true))
+}
+
+async fn handle_unban_client_version_request(
+    request: hyper::Request<hyper::Body>,
+) -> Result<hyper::Response<hyper::Body>, anyhow::Error> {
+    if let Some(response) = check_authorization(&request) {
+        return Ok(response);
+    }
+    let client_version = request.param("client_version").unwrap();
+    log::info!("unbanning client version: {}", client_version);
+    Ok(status_response(
+        request
+            .data::<crate::State>()
+            .unwrap()
+            .bans
+            .unban_client_version(client_version),
+    ))
+}
+
+pub fn router() -> routerify::Router<hyper::Body, anyhow::Error> {
+    routerify::Router::builder()
+        .get("/sessions", handle_list_sessions_request)
+        .delete("/sessions/:session_id", handle_close_session_request)
+        .get("/bans", handle_list_bans_request)
+        .put("/bans/addrs/:addr", handle_ban_addr_request)
+        .delete("/bans/addrs/:addr", handle_unban_addr_request)
+        .put(
+            "/bans/client_versions/:client_version",
+            handle_ban_client_version_request,
+        )
+        .delete(
+            "/bans/client_versions/:client_version",
+            handle_unban_client_version_request,
+        )
+        .build()
+        .unwrap()
+}
@@ -0,0 +1,55 @@
+/// Clients that operators have told us to turn away.
+#[derive(Default)]
+pub struct Bans {
+    addrs: std::sync::Mutex<std::collections::BTreeSet<std::net::IpAddr>>,
+    client_versions: std::sync::Mutex<std::collections::BTreeSet<String>>,
+}
+
+#[derive(serde::Serialize)]
+pub struct List {
+    pub addrs: Vec<std::net::IpAddr>,
+    pub client_versions: Vec<String>,
+}
+
+impl Bans {
+    pub fn is_banned(&self, addr: std::net::IpAddr, client_version: &str) -> bool {
+        self.addrs.lock().unwrap().contains(&addr)
+            || self
+                .client_versions
+                .lock()
+                .unwrap()
+                .contains(client_version)
+    }
+
+    pub fn ban_addr(&self, addr: std::net::IpAddr) -> bool {
+        self.addrs.lock().unwrap().insert(addr)
+    }
+
+    pub fn unban_addr(&self, addr: std::net::IpAddr) -> bool {
+        self.addrs.lock().unwrap().remove(&addr)
+    }
+
+    pub fn ban_client_version(&self, client_version: &str) -> bool {
+        self.client_versions
+            .lock()
+            .unwrap()
+            .insert(client_version.to_string())
+    }
+
+    pub fn unban_client_version(&self, client_version: &str) -> bool {
+        self.client_versions.lock().unwrap().remove(client_version)
+    }
+
+    pub fn list(&self) -> List {
+        List {
+            addrs: self.addrs.lock().unwrap().iter().cloned().collect(),
+            client_versions: self
+                .client_versions
+                .lock()
+                .unwrap()
+                .iter()
+                .cloned()
+                .collect(),
+        }
+    }
+}
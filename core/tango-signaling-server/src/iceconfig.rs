@@ -0,0 +1,52 @@
+use hmac::Mac;
+
+pub struct Settings {
+    pub stun_urls: Vec<String>,
+    pub turn_urls: Vec<String>,
+    pub turn_shared_secret: Option<String>,
+    pub turn_credential_ttl: std::time::Duration,
+}
+
+pub struct Server {
+    settings: Settings,
+}
+
+impl Server {
+    pub fn new(settings: Settings) -> Server {
+        Server { settings }
+    }
+
+    pub fn get(
+        &self,
+        _req: tango_protos::iceconfig::GetRequest,
+    ) -> anyhow::Result<tango_protos::iceconfig::GetResponse> {
+        let mut ice_servers = vec![];
+
+        if !self.settings.stun_urls.is_empty() {
+            ice_servers.push(tango_protos::iceconfig::get_response::IceServer {
+                credential: None,
+                username: None,
+                urls: self.settings.stun_urls.clone(),
+            });
+        }
+
+        if !self.settings.turn_urls.is_empty() {
+            if let Some(shared_secret) = self.settings.turn_shared_secret.as_ref() {
+                // This is the TURN REST API scheme that coturn implements with use-auth-secret: the username is the expiry time, and the credential is the HMAC of the username with the shared secret.
+                let expiry = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?
+                    + self.settings.turn_credential_ttl;
+                let username = format!("{}:tango", expiry.as_secs());
+                let mut mac = hmac::Hmac::<sha1::Sha1>::new_from_slice(shared_secret.as_bytes())?;
+                mac.update(username.as_bytes());
+                let credential = base64::encode(mac.finalize().into_bytes());
+                ice_servers.push(tango_protos::iceconfig::get_response::IceServer {
+                    credential: Some(credential),
+                    username: Some(username),
+                    urls: self.settings.turn_urls.clone(),
+                });
+            }
+        }
+
+        Ok(tango_protos::iceconfig::GetResponse { ice_servers })
+    }
+}
@@ -1,20 +1,350 @@
+mod admin;
+mod bans;
+mod iceconfig;
+mod matchmaking;
+mod metrics;
+mod ratelimit;
+mod relay;
 mod signaling;
+mod store;
+mod tls;
 use envconfig::Envconfig;
+use prost::Message;
 use routerify::ext::RequestExt;
 
-#[derive(Envconfig)]
+#[derive(Envconfig, serde::Deserialize)]
+#[serde(default)]
 struct Config {
+    // This may be a comma-separated list of addresses.
     #[envconfig(from = "LISTEN_ADDR", default = "[::]:1984")]
     listen_addr: String,
+
+    #[envconfig(from = "TLS_CERT_PATH")]
+    tls_cert_path: Option<String>,
+
+    #[envconfig(from = "TLS_KEY_PATH")]
+    tls_key_path: Option<String>,
+
+    // A comma-separated list of origins that browsers may connect to us from: if empty, any origin is allowed.
+    #[envconfig(from = "ALLOWED_ORIGINS", default = "")]
+    allowed_origins: String,
+
+    #[envconfig(from = "SHUTDOWN_GRACE_SECS", default = "30")]
+    shutdown_grace_secs: u64,
+
+    #[envconfig(from = "SESSION_TTL_SECS", default = "3600")]
+    session_ttl_secs: u64,
+
+    #[envconfig(from = "IDLE_TIMEOUT_SECS", default = "90")]
+    idle_timeout_secs: u64,
+
+    #[envconfig(from = "KEEPALIVE_INTERVAL_SECS", default = "30")]
+    keepalive_interval_secs: u64,
+
+    #[envconfig(from = "STUN_URLS", default = "")]
+    stun_urls: String,
+
+    #[envconfig(from = "TURN_URLS", default = "")]
+    turn_urls: String,
+
+    #[envconfig(from = "TURN_SHARED_SECRET")]
+    turn_shared_secret: Option<String>,
+
+    #[envconfig(from = "TURN_CREDENTIAL_TTL_SECS", default = "86400")]
+    turn_credential_ttl_secs: u64,
+
+    // Setting any of these rates to 0 turns that limit off.
+    #[envconfig(from = "CONNECTIONS_PER_MINUTE", default = "30")]
+    connections_per_minute: u32,
+
+    #[envconfig(from = "CONNECTION_BURST", default = "10")]
+    connection_burst: u32,
+
+    #[envconfig(from = "MESSAGES_PER_SECOND", default = "20")]
+    messages_per_second: u32,
+
+    #[envconfig(from = "MESSAGE_BURST", default = "50")]
+    message_burst: u32,
+
+    #[envconfig(from = "MAX_SESSIONS", default = "10000")]
+    max_sessions: usize,
+
+    // Either "memory", or "file" to keep sessions in the directory at SESSION_STORE_PATH: every instance sharing the directory can pair clients with each other.
+    #[envconfig(from = "SESSION_STORE", default = "memory")]
+    session_store: String,
+
+    #[envconfig(from = "SESSION_STORE_PATH", default = "sessions")]
+    session_store_path: String,
+
+    #[envconfig(from = "SESSION_STORE_POLL_INTERVAL_MS", default = "100")]
+    session_store_poll_interval_ms: u64,
+
+    #[envconfig(from = "TRUST_X_FORWARDED_FOR", default = "false")]
+    trust_x_forwarded_for: bool,
+
+    // Relaying match traffic costs us bandwidth, so it must be turned on explicitly.
+    #[envconfig(from = "RELAY_ENABLED", default = "false")]
+    relay_enabled: bool,
+
+    #[envconfig(from = "RELAY_PAIRING_TIMEOUT_SECS", default = "60")]
+    relay_pairing_timeout_secs: u64,
+
+    // How long clients have to get to the relay once signaling is over.
+    #[envconfig(from = "RELAY_TICKET_TTL_SECS", default = "120")]
+    relay_ticket_ttl_secs: u64,
+
+    // The key relay tickets are signed with: if unset, each instance makes one up, and only takes tickets it issued itself.
+    //
+    // The relay pairs clients up in memory, so there can only be one relay instance. If sessions are shared between instances, set the same secret on all of them, turn the relay on for just one, and point clients at that one: the others will still issue tickets for it.
+    #[envconfig(from = "RELAY_TICKET_SECRET")]
+    relay_ticket_secret: Option<String>,
+
+    // Match traffic is tiny, so this only needs to be big enough for the handshake not to be held up.
+    #[envconfig(from = "RELAY_MAX_BYTES_PER_SECOND", default = "65536")]
+    relay_max_bytes_per_second: u64,
+
+    #[envconfig(from = "RELAY_MAX_BURST_BYTES", default = "1048576")]
+    relay_max_burst_bytes: u64,
+
+    // The bearer token for the admin API under /admin: if unset, the admin API is disabled.
+    #[envconfig(from = "ADMIN_TOKEN")]
+    admin_token: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config::init_from_hashmap(&std::collections::HashMap::new()).unwrap()
+    }
+}
+
+impl Config {
+    /// Reads the config from the TOML file at CONFIG_PATH if it is set, otherwise from the environment.
+    fn load() -> anyhow::Result<Self> {
+        match std::env::var("CONFIG_PATH") {
+            Ok(path) => Ok(toml::from_str(&std::fs::read_to_string(path)?)?),
+            Err(_) => Ok(Config::init_from_env()?),
+        }
+    }
+}
+
+const MAX_SESSION_ID_LENGTH: usize = 64;
+
+// ICE config requests are a handful of fields: anything bigger than this is not one.
+const MAX_ICECONFIG_REQUEST_LENGTH: usize = 4096;
+
+fn is_valid_session_id(session_id: &str) -> bool {
+    !session_id.is_empty()
+        && session_id.len() <= MAX_SESSION_ID_LENGTH
+        && session_id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
 }
 
+#[derive(Clone)]
 struct State {
     signaling_server: std::sync::Arc<signaling::Server>,
+    iceconfig_server: std::sync::Arc<iceconfig::Server>,
+    matchmaking_server: std::sync::Arc<matchmaking::Server>,
+    relay_server: Option<std::sync::Arc<relay::Server>>,
+    connection_limiter: std::sync::Arc<ratelimit::Limiter>,
+    trust_x_forwarded_for: bool,
+    metrics: std::sync::Arc<metrics::Metrics>,
+    allowed_origins: std::sync::Arc<Vec<String>>,
+    draining: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    bans: std::sync::Arc<bans::Bans>,
+    admin_token: Option<std::sync::Arc<String>>,
+}
+
+fn is_origin_allowed(request: &hyper::Request<hyper::Body>) -> bool {
+    let allowed_origins = &request.data::<State>().unwrap().allowed_origins;
+    if allowed_origins.is_empty() {
+        return true;
+    }
+    // Native clients don't send an origin at all.
+    match request
+        .headers()
+        .get(hyper::header::ORIGIN)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(origin) => allowed_origins.iter().any(|o| o == origin),
+        None => true,
+    }
+}
+
+fn forbidden() -> hyper::Response<hyper::Body> {
+    hyper::Response::builder()
+        .status(hyper::StatusCode::FORBIDDEN)
+        .body(
+            hyper::StatusCode::FORBIDDEN
+                .canonical_reason()
+                .unwrap()
+                .into(),
+        )
+        .unwrap()
+}
+
+fn shutting_down() -> hyper::Response<hyper::Body> {
+    hyper::Response::builder()
+        .status(hyper::StatusCode::SERVICE_UNAVAILABLE)
+        .body(hyper::Body::from("server is shutting down"))
+        .unwrap()
+}
+
+async fn handle_healthz_request(
+    request: hyper::Request<hyper::Body>,
+) -> Result<hyper::Response<hyper::Body>, anyhow::Error> {
+    // Report unhealthy while draining so load balancers stop sending us new clients.
+    if request
+        .data::<State>()
+        .unwrap()
+        .draining
+        .load(std::sync::atomic::Ordering::Relaxed)
+    {
+        return Ok(shutting_down());
+    }
+    Ok(hyper::Response::builder().body(hyper::Body::from("ok"))?)
+}
+
+async fn handle_metrics_request(
+    request: hyper::Request<hyper::Body>,
+) -> Result<hyper::Response<hyper::Body>, anyhow::Error> {
+    let state = request.data::<State>().unwrap();
+    let active_sessions = state.signaling_server.num_sessions().await?;
+    Ok(hyper::Response::builder()
+        .header(
+            hyper::header::CONTENT_TYPE,
+            "text/plain; version=0.0.4; charset=utf-8",
+        )
+        .body(hyper::Body::from(state.metrics.render(active_sessions)))?)
+}
+
+fn client_addr(request: &hyper::Request<hyper::Body>) -> std::net::IpAddr {
+    if request.data::<State>().unwrap().trust_x_forwarded_for {
+        // Only the last entry is written by our proxy: anything before it came from the client, who can put whatever they like there.
+        if let Some(addr) = request
+            .headers()
+            .get_all("X-Forwarded-For")
+            .iter()
+            .last()
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.rsplit(',').next())
+            .and_then(|v| v.trim().parse().ok())
+        {
+            return addr;
+        }
+    }
+    request.remote_addr().ip()
+}
+
+fn client_version(request: &hyper::Request<hyper::Body>) -> String {
+    request
+        .headers()
+        .get(hyper::header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|user_agent| user_agent.strip_prefix("tango-core/"))
+        .unwrap_or("unknown")
+        .to_string()
+}
+
+fn too_many_requests(retry_after: std::time::Duration) -> hyper::Response<hyper::Body> {
+    hyper::Response::builder()
+        .status(hyper::StatusCode::TOO_MANY_REQUESTS)
+        .header(
+            hyper::header::RETRY_AFTER,
+            retry_after.as_secs().max(1).to_string(),
+        )
+        .body(
+            hyper::StatusCode::TOO_MANY_REQUESTS
+                .canonical_reason()
+                .unwrap()
+                .into(),
+        )
+        .unwrap()
+}
+
+fn split_urls(urls: &str) -> Vec<String> {
+    urls.split(',')
+        .map(|url| url.trim())
+        .filter(|url| !url.is_empty())
+        .map(|url| url.to_string())
+        .collect()
+}
+
+/// Reads the whole body, or returns None if it's longer than max_len.
+async fn read_body(
+    mut body: hyper::Body,
+    max_len: usize,
+) -> Result<Option<Vec<u8>>, anyhow::Error> {
+    if hyper::body::HttpBody::size_hint(&body).lower() > max_len as u64 {
+        return Ok(None);
+    }
+    let mut buf = vec![];
+    while let Some(chunk) = hyper::body::HttpBody::data(&mut body).await {
+        let chunk = chunk?;
+        if buf.len() + chunk.len() > max_len {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(Some(buf))
+}
+
+async fn handle_iceconfig_request(
+    request: hyper::Request<hyper::Body>,
+) -> Result<hyper::Response<hyper::Body>, anyhow::Error> {
+    let addr = client_addr(&request);
+    let client_version = client_version(&request);
+    let state = request.data::<State>().unwrap();
+    if state.bans.is_banned(addr, &client_version) {
+        return Ok(forbidden());
+    }
+
+    if !state.connection_limiter.try_acquire(addr) {
+        return Ok(too_many_requests(state.connection_limiter.retry_after()));
+    }
+
+    if !is_origin_allowed(&request) {
+        return Ok(forbidden());
+    }
+
+    let iceconfig_server = state.iceconfig_server.clone();
+    let body = match read_body(request.into_body(), MAX_ICECONFIG_REQUEST_LENGTH).await? {
+        Some(body) => body,
+        None => {
+            return Ok(hyper::Response::builder()
+                .status(hyper::StatusCode::PAYLOAD_TOO_LARGE)
+                .body(hyper::Body::from("request too large"))?);
+        }
+    };
+    let req = match tango_protos::iceconfig::GetRequest::decode(&body[..]) {
+        Ok(req) => req,
+        Err(_) => {
+            return Ok(hyper::Response::builder()
+                .status(hyper::StatusCode::BAD_REQUEST)
+                .body(hyper::Body::from("invalid request"))?);
+        }
+    };
+    Ok(hyper::Response::builder()
+        .header(hyper::header::CONTENT_TYPE, "application/x-protobuf")
+        .body(hyper::Body::from(
+            iceconfig_server.get(req)?.encode_to_vec(),
+        ))?)
 }
 
 async fn handle_signaling_request(
     mut request: hyper::Request<hyper::Body>,
 ) -> Result<hyper::Response<hyper::Body>, anyhow::Error> {
+    let addr = client_addr(&request);
+    let client_version = client_version(&request);
+    let state = request.data::<State>().unwrap();
+    if state.bans.is_banned(addr, &client_version) {
+        return Ok(forbidden());
+    }
+
+    if !state.connection_limiter.try_acquire(addr) {
+        return Ok(too_many_requests(state.connection_limiter.retry_after()));
+    }
+
     let session_id = if let Some(session_id) = request.uri().query().and_then(|query| {
         url::form_urlencoded::parse(query.as_bytes())
             .into_owned()
@@ -29,6 +359,31 @@ async fn handle_signaling_request(
             .unwrap());
     };
 
+    if !is_valid_session_id(&session_id) {
+        return Ok(hyper::Response::builder()
+            .status(hyper::StatusCode::BAD_REQUEST)
+            .body(hyper::Body::from("invalid session_id"))
+            .unwrap());
+    }
+
+    if !is_origin_allowed(&request) {
+        return Ok(forbidden());
+    }
+
+    // While draining, only let clients join negotiations that are already in progress.
+    if state.draining.load(std::sync::atomic::Ordering::Relaxed)
+        && !state.signaling_server.has_session(&session_id).await?
+    {
+        return Ok(shutting_down());
+    }
+
+    if state.signaling_server.is_full().await? {
+        return Ok(hyper::Response::builder()
+            .status(hyper::StatusCode::SERVICE_UNAVAILABLE)
+            .body(hyper::Body::from("too many sessions"))
+            .unwrap());
+    }
+
     if !hyper_tungstenite::is_upgrade_request(&request) {
         return Ok(hyper::Response::builder()
             .status(hyper::StatusCode::BAD_REQUEST)
@@ -40,6 +395,8 @@ async fn handle_signaling_request(
             )?);
     }
 
+    state.metrics.record_client(&client_version);
+
     let (response, websocket) = hyper_tungstenite::upgrade(
         &mut request,
         Some(tungstenite::protocol::WebSocketConfig {
@@ -58,7 +415,10 @@ async fn handle_signaling_request(
                 return;
             }
         };
-        if let Err(e) = signaling_server.handle_stream(websocket, &session_id).await {
+        if let Err(e) = signaling_server
+            .handle_stream(websocket, &session_id, addr, &client_version)
+            .await
+        {
             log::error!("error in websocket connection: {}", e);
         }
     });
@@ -66,27 +426,415 @@ async fn handle_signaling_request(
     Ok(response)
 }
 
-fn router() -> routerify::Router<hyper::Body, anyhow::Error> {
-    routerify::Router::builder()
-        .data(State {
-            signaling_server: std::sync::Arc::new(signaling::Server::new()),
+async fn handle_matchmaking_request(
+    mut request: hyper::Request<hyper::Body>,
+) -> Result<hyper::Response<hyper::Body>, anyhow::Error> {
+    let addr = client_addr(&request);
+    let client_version = client_version(&request);
+    let state = request.data::<State>().unwrap();
+    if state.bans.is_banned(addr, &client_version) {
+        return Ok(forbidden());
+    }
+
+    if !state.connection_limiter.try_acquire(addr) {
+        return Ok(too_many_requests(state.connection_limiter.retry_after()));
+    }
+
+    if !is_origin_allowed(&request) {
+        return Ok(forbidden());
+    }
+
+    if state.draining.load(std::sync::atomic::Ordering::Relaxed) {
+        return Ok(shutting_down());
+    }
+
+    if !hyper_tungstenite::is_upgrade_request(&request) {
+        return Ok(hyper::Response::builder()
+            .status(hyper::StatusCode::BAD_REQUEST)
+            .body(
+                hyper::StatusCode::BAD_REQUEST
+                    .canonical_reason()
+                    .unwrap()
+                    .into(),
+            )?);
+    }
+
+    let (response, websocket) = hyper_tungstenite::upgrade(
+        &mut request,
+        Some(tungstenite::protocol::WebSocketConfig {
+            max_message_size: Some(64 * 1024),
+            max_frame_size: Some(64 * 1024),
+            ..Default::default()
+        }),
+    )?;
+
+    let matchmaking_server = request.data::<State>().unwrap().matchmaking_server.clone();
+    tokio::spawn(async move {
+        let websocket = match websocket.await {
+            Ok(websocket) => websocket,
+            Err(e) => {
+                log::error!("error in websocket connection: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = matchmaking_server.handle_stream(websocket).await {
+            log::error!("error in websocket connection: {}", e);
+        }
+    });
+
+    Ok(response)
+}
+
+async fn handle_relay_request(
+    mut request: hyper::Request<hyper::Body>,
+) -> Result<hyper::Response<hyper::Body>, anyhow::Error> {
+    let addr = client_addr(&request);
+    let client_version = client_version(&request);
+    let state = request.data::<State>().unwrap();
+    if state.bans.is_banned(addr, &client_version) {
+        return Ok(forbidden());
+    }
+
+    let relay_server = if let Some(relay_server) = state.relay_server.clone() {
+        relay_server
+    } else {
+        return Ok(hyper::Response::builder()
+            .status(hyper::StatusCode::NOT_FOUND)
+            .body(hyper::Body::from("relay is not enabled"))
+            .unwrap());
+    };
+
+    if !state.connection_limiter.try_acquire(addr) {
+        return Ok(too_many_requests(state.connection_limiter.retry_after()));
+    }
+
+    let session_id = if let Some(session_id) = request.uri().query().and_then(|query| {
+        url::form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .find(|(k, _)| k == "session_id")
+            .map(|(_, v)| v)
+    }) {
+        session_id
+    } else {
+        return Ok(hyper::Response::builder()
+            .status(hyper::StatusCode::BAD_REQUEST)
+            .body(hyper::Body::from("missing session_id"))
+            .unwrap());
+    };
+
+    if !is_valid_session_id(&session_id) {
+        return Ok(hyper::Response::builder()
+            .status(hyper::StatusCode::BAD_REQUEST)
+            .body(hyper::Body::from("invalid session_id"))
+            .unwrap());
+    }
+
+    if !is_origin_allowed(&request) {
+        return Ok(forbidden());
+    }
+
+    let me = match request
+        .uri()
+        .query()
+        .and_then(|query| {
+            url::form_urlencoded::parse(query.as_bytes())
+                .into_owned()
+                .find(|(k, _)| k == "ticket")
+                .map(|(_, v)| v)
         })
+        .and_then(|ticket| relay_server.tickets().verify(&session_id, &ticket))
+    {
+        Some(me) => me,
+        None => {
+            return Ok(forbidden());
+        }
+    };
+
+    // While draining, only let clients join peers already waiting at the relay.
+    if state.draining.load(std::sync::atomic::Ordering::Relaxed)
+        && !relay_server.is_waiting(&session_id).await
+    {
+        return Ok(shutting_down());
+    }
+
+    if !hyper_tungstenite::is_upgrade_request(&request) {
+        return Ok(hyper::Response::builder()
+            .status(hyper::StatusCode::BAD_REQUEST)
+            .body(
+                hyper::StatusCode::BAD_REQUEST
+                    .canonical_reason()
+                    .unwrap()
+                    .into(),
+            )?);
+    }
+
+    let (response, websocket) = hyper_tungstenite::upgrade(
+        &mut request,
+        Some(tungstenite::protocol::WebSocketConfig {
+            max_message_size: Some(64 * 1024),
+            max_frame_size: Some(64 * 1024),
+            ..Default::default()
+        }),
+    )?;
+
+    tokio::spawn(async move {
+        let websocket = match websocket.await {
+            Ok(websocket) => websocket,
+            Err(e) => {
+                log::error!("error in websocket connection: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = relay_server.handle_stream(websocket, &session_id, me).await {
+            log::error!("error in relay connection: {}", e);
+        }
+    });
+
+    Ok(response)
+}
+
+fn router(state: State) -> routerify::Router<hyper::Body, anyhow::Error> {
+    routerify::Router::builder()
+        .data(state)
         .get("/", handle_signaling_request)
         .get("/signaling", handle_signaling_request)
+        .post("/iceconfig", handle_iceconfig_request)
+        .get("/matchmaking", handle_matchmaking_request)
+        .get("/relay", handle_relay_request)
+        .get("/healthz", handle_healthz_request)
+        .get("/metrics", handle_metrics_request)
+        .scope("/admin", admin::router())
         .build()
         .unwrap()
 }
 
+async fn serve(
+    listener: tokio::net::TcpListener,
+    tls_acceptor: Option<tokio_rustls::TlsAcceptor>,
+    state: State,
+    mut shutdown_rx: tokio::sync::watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let mut builder = routerify::RequestServiceBuilder::new(router(state)).unwrap();
+    loop {
+        let (stream, remote_addr) = tokio::select! {
+            r = listener.accept() => r?,
+            _ = shutdown_rx.changed() => {
+                return Ok(());
+            }
+        };
+        let service = builder.build(remote_addr);
+        let tls_acceptor = tls_acceptor.clone();
+        tokio::spawn(async move {
+            let r = match tls_acceptor {
+                Some(tls_acceptor) => {
+                    let stream = match tls_acceptor.accept(stream).await {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            log::debug!("tls handshake with {} failed: {}", remote_addr, e);
+                            return;
+                        }
+                    };
+                    hyper::server::conn::Http::new()
+                        .serve_connection(stream, service)
+                        .with_upgrades()
+                        .await
+                }
+                None => {
+                    hyper::server::conn::Http::new()
+                        .serve_connection(stream, service)
+                        .with_upgrades()
+                        .await
+                }
+            };
+            if let Err(e) = r {
+                log::debug!("error serving {}: {}", remote_addr, e);
+            }
+        });
+    }
+}
+
+async fn wait_for_shutdown_signal() -> anyhow::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut sigterm =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            _ = sigterm.recv() => {}
+            r = tokio::signal::ctrl_c() => r?,
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     env_logger::Builder::from_default_env()
         .filter(Some("tango_server"), log::LevelFilter::Info)
         .init();
     log::info!("welcome to tango-server {}!", git_version::git_version!());
-    let config = Config::init_from_env().unwrap();
-    let addr = config.listen_addr.parse()?;
-    let router = router();
-    let service = routerify::RouterService::new(router).unwrap();
-    hyper::Server::bind(&addr).serve(service).await?;
+    let config = Config::load()?;
+    let tls_acceptor = match (config.tls_cert_path.as_ref(), config.tls_key_path.as_ref()) {
+        (Some(cert_path), Some(key_path)) => Some(tls::load_acceptor(
+            std::path::Path::new(cert_path),
+            std::path::Path::new(key_path),
+        )?),
+        (None, None) => None,
+        _ => anyhow::bail!("both a TLS certificate and key must be provided"),
+    };
+    let metrics = std::sync::Arc::new(metrics::Metrics::default());
+    let session_store: std::sync::Arc<dyn store::SessionStore> = match config.session_store.as_str()
+    {
+        "memory" => std::sync::Arc::new(store::MemoryStore::default()),
+        "file" => std::sync::Arc::new(store::FileStore::new(
+            std::path::Path::new(&config.session_store_path),
+            std::time::Duration::from_millis(config.session_store_poll_interval_ms),
+        )?),
+        s => anyhow::bail!("unknown session store: {}", s),
+    };
+    if config.relay_enabled
+        && config.session_store != "memory"
+        && config.relay_ticket_secret.is_none()
+    {
+        anyhow::bail!(
+            "RELAY_TICKET_SECRET must be set to use the relay with a shared session store"
+        );
+    }
+    let relay_tickets = match config.relay_ticket_secret.as_ref() {
+        Some(secret) => Some(secret.as_bytes().to_vec()),
+        None if config.relay_enabled => Some(rand::random::<[u8; 32]>().to_vec()),
+        None => None,
+    }
+    .map(|key| {
+        std::sync::Arc::new(relay::Tickets::new(
+            &key,
+            std::time::Duration::from_secs(config.relay_ticket_ttl_secs),
+        ))
+    });
+    let relay_server = match relay_tickets.as_ref() {
+        Some(relay_tickets) if config.relay_enabled => {
+            Some(std::sync::Arc::new(relay::Server::new(
+                relay::Settings {
+                    pairing_timeout: std::time::Duration::from_secs(
+                        config.relay_pairing_timeout_secs,
+                    ),
+                    max_bytes_per_second: config.relay_max_bytes_per_second,
+                    max_burst_bytes: config.relay_max_burst_bytes,
+                },
+                relay_tickets.clone(),
+                metrics.clone(),
+            )))
+        }
+        _ => None,
+    };
+    let signaling_server = std::sync::Arc::new(signaling::Server::new(
+        signaling::Settings {
+            session_ttl: std::time::Duration::from_secs(config.session_ttl_secs),
+            idle_timeout: std::time::Duration::from_secs(config.idle_timeout_secs),
+            keepalive_interval: std::time::Duration::from_secs(config.keepalive_interval_secs),
+            max_sessions: config.max_sessions,
+            message_limiter: ratelimit::Limiter::new(
+                config.messages_per_second as f64,
+                config.message_burst,
+            ),
+        },
+        session_store,
+        metrics.clone(),
+        relay_tickets,
+    ));
+    {
+        let signaling_server = signaling_server.clone();
+        tokio::spawn(async move {
+            signaling_server.reap_sessions().await;
+        });
+    }
+    let iceconfig_server = std::sync::Arc::new(iceconfig::Server::new(iceconfig::Settings {
+        stun_urls: split_urls(&config.stun_urls),
+        turn_urls: split_urls(&config.turn_urls),
+        turn_shared_secret: config.turn_shared_secret,
+        turn_credential_ttl: std::time::Duration::from_secs(config.turn_credential_ttl_secs),
+    }));
+    let matchmaking_server = std::sync::Arc::new(matchmaking::Server::new(matchmaking::Settings {
+        idle_timeout: std::time::Duration::from_secs(config.idle_timeout_secs),
+        keepalive_interval: std::time::Duration::from_secs(config.keepalive_interval_secs),
+    }));
+    let connection_limiter = std::sync::Arc::new(ratelimit::Limiter::new(
+        config.connections_per_minute as f64 / 60.0,
+        config.connection_burst,
+    ));
+    {
+        let connection_limiter = connection_limiter.clone();
+        let signaling_server = signaling_server.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                connection_limiter.prune();
+                signaling_server.prune_message_limiter();
+            }
+        });
+    }
+    let draining = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let state = State {
+        signaling_server,
+        iceconfig_server,
+        matchmaking_server,
+        relay_server,
+        connection_limiter,
+        trust_x_forwarded_for: config.trust_x_forwarded_for,
+        metrics: metrics.clone(),
+        allowed_origins: std::sync::Arc::new(split_urls(&config.allowed_origins)),
+        draining: draining.clone(),
+        bans: std::sync::Arc::new(bans::Bans::default()),
+        admin_token: config.admin_token.map(std::sync::Arc::new),
+    };
+
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let mut servers = vec![];
+    for addr in split_urls(&config.listen_addr) {
+        let listener = tokio::net::TcpListener::bind(&addr).await?;
+        log::info!("listening on {}", addr);
+        servers.push(tokio::spawn(serve(
+            listener,
+            tls_acceptor.clone(),
+            state.clone(),
+            shutdown_rx.clone(),
+        )));
+    }
+    drop(state);
+
+    wait_for_shutdown_signal().await?;
+    log::info!("shutting down: no longer accepting new sessions");
+
+    // Clients joining negotiations in progress must still be able to reach us, so keep listening until the grace period is over.
+    draining.store(true, std::sync::atomic::Ordering::Relaxed);
+    let grace_period = std::time::Duration::from_secs(config.shutdown_grace_secs);
+    let draining_since = std::time::Instant::now();
+    loop {
+        let connections = metrics
+            .connections
+            .load(std::sync::atomic::Ordering::Relaxed);
+        let relay_connections = metrics
+            .relay_connections
+            .load(std::sync::atomic::Ordering::Relaxed);
+        if connections <= 0 && relay_connections <= 0 {
+            break;
+        }
+        if draining_since.elapsed() > grace_period {
+            log::warn!(
+                "grace period elapsed with {} signaling connections and {} relay connections still open",
+                connections,
+                relay_connections
+            );
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+
+    let _ = shutdown_tx.send(true);
+    for server in servers {
+        server.await??;
+    }
     Ok(())
 }
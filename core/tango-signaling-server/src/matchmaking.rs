@@ -0,0 +1,217 @@
+use futures_util::{SinkExt, StreamExt, TryStreamExt};
+use prost::Message;
+use rand::Rng;
+
+const SESSION_ID_LENGTH: usize = 16;
+
+// For every this long a ticket has been waiting, we are willing to stretch its input delay range by one more frame in each direction.
+const INPUT_DELAY_RELAXATION_INTERVAL: std::time::Duration = std::time::Duration::from_secs(15);
+
+struct Ticket {
+    id: u64,
+    enqueue: tango_protos::matchmaking::packet::Enqueue,
+    enqueued_at: std::time::Instant,
+    matched_tx: tokio::sync::oneshot::Sender<tango_protos::matchmaking::packet::Matched>,
+}
+
+impl Ticket {
+    fn input_delay_range(&self) -> (u32, u32) {
+        let slack = (self.enqueued_at.elapsed().as_secs()
+            / INPUT_DELAY_RELAXATION_INTERVAL.as_secs()) as u32;
+        (
+            self.enqueue.min_input_delay.saturating_sub(slack),
+            self.enqueue.max_input_delay.saturating_add(slack),
+        )
+    }
+}
+
+fn is_compatible(
+    ticket: &Ticket,
+    enqueue: &tango_protos::matchmaking::packet::Enqueue,
+) -> Option<u32> {
+    if ticket.enqueue.game_family != enqueue.game_family
+        || ticket.enqueue.rom_revision != enqueue.rom_revision
+        || ticket.enqueue.match_type != enqueue.match_type
+        || ticket.enqueue.match_subtype != enqueue.match_subtype
+    {
+        return None;
+    }
+
+    let (min_input_delay, max_input_delay) = ticket.input_delay_range();
+    let min_input_delay = min_input_delay.max(enqueue.min_input_delay);
+    let max_input_delay = max_input_delay.min(enqueue.max_input_delay);
+    if min_input_delay > max_input_delay {
+        return None;
+    }
+    Some(min_input_delay)
+}
+
+fn mint_session_id() -> String {
+    rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(SESSION_ID_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+pub struct Settings {
+    pub idle_timeout: std::time::Duration,
+    pub keepalive_interval: std::time::Duration,
+}
+
+pub struct Server {
+    settings: Settings,
+    queue: tokio::sync::Mutex<Vec<Ticket>>,
+    next_ticket_id: std::sync::atomic::AtomicU64,
+}
+
+impl Server {
+    pub fn new(settings: Settings) -> Server {
+        Server {
+            settings,
+            queue: tokio::sync::Mutex::new(vec![]),
+            next_ticket_id: std::sync::atomic::AtomicU64::new(0),
+        }
+    }
+
+    pub async fn handle_stream(
+        &self,
+        ws: hyper_tungstenite::WebSocketStream<hyper::upgrade::Upgraded>,
+    ) -> anyhow::Result<()> {
+        let (mut tx, mut rx) = ws.split();
+
+        let enqueue = match rx.try_next().await? {
+            Some(tungstenite::Message::Binary(d)) => {
+                match tango_protos::matchmaking::Packet::decode(bytes::Bytes::from(d))?.which {
+                    Some(tango_protos::matchmaking::packet::Which::Enqueue(enqueue)) => enqueue,
+                    p => anyhow::bail!("expected enqueue, got: {:?}", p),
+                }
+            }
+            Some(tungstenite::Message::Close(_)) | None => {
+                return Ok(());
+            }
+            Some(m) => {
+                anyhow::bail!("unexpected message: {:?}", m);
+            }
+        };
+
+        if enqueue.min_input_delay > enqueue.max_input_delay {
+            anyhow::bail!("invalid input delay range");
+        }
+
+        let (ticket_id, mut matched_rx, queue_length) = {
+            let mut queue = self.queue.lock().await;
+
+            // The queue is kept in order of arrival, so the first compatible ticket is the one that has been waiting the longest.
+            while let Some((i, input_delay)) = queue
+                .iter()
+                .enumerate()
+                .find_map(|(i, ticket)| is_compatible(ticket, &enqueue).map(|d| (i, d)))
+            {
+                let ticket = queue.remove(i);
+                let matched = tango_protos::matchmaking::packet::Matched {
+                    session_id: mint_session_id(),
+                    input_delay,
+                };
+                if ticket.matched_tx.send(matched.clone()).is_err() {
+                    // The ticket's client left before we got to it, so try the next one instead.
+                    log::info!("ticket {} is gone, skipping it", ticket.id);
+                    continue;
+                }
+                log::info!(
+                    "matched ticket {} with new client in session {}",
+                    ticket.id,
+                    matched.session_id
+                );
+                drop(queue);
+                send_packet(
+                    &mut tx,
+                    tango_protos::matchmaking::packet::Which::Matched(matched),
+                )
+                .await?;
+                return Ok(());
+            }
+
+            let ticket_id = self
+                .next_ticket_id
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let (matched_tx, matched_rx) = tokio::sync::oneshot::channel();
+            queue.push(Ticket {
+                id: ticket_id,
+                enqueue,
+                enqueued_at: std::time::Instant::now(),
+                matched_tx,
+            });
+            (ticket_id, matched_rx, queue.len())
+        };
+
+        let r = async {
+            send_packet(
+                &mut tx,
+                tango_protos::matchmaking::packet::Which::Queued(
+                    tango_protos::matchmaking::packet::Queued {
+                        queue_length: queue_length as u32,
+                    },
+                ),
+            )
+            .await?;
+
+            let mut keepalive = tokio::time::interval(self.settings.keepalive_interval);
+            let mut last_seen_at = std::time::Instant::now();
+            loop {
+                tokio::select! {
+                    matched = &mut matched_rx => {
+                        let matched = matched?;
+                        send_packet(
+                            &mut tx,
+                            tango_protos::matchmaking::packet::Which::Matched(matched),
+                        )
+                        .await?;
+                        break;
+                    }
+                    msg = rx.next() => {
+                        // Anything else the client sends while queued, including closing the connection, takes them out of the queue.
+                        match msg {
+                            Some(Ok(tungstenite::Message::Ping(_)))
+                            | Some(Ok(tungstenite::Message::Pong(_))) => {
+                                last_seen_at = std::time::Instant::now();
+                            }
+                            _ => {
+                                log::info!("ticket {} left the queue", ticket_id);
+                                break;
+                            }
+                        }
+                    }
+                    _ = keepalive.tick() => {
+                        // If the client has vanished without a close frame, we won't even get pongs back, and we'd otherwise match someone with them.
+                        if last_seen_at.elapsed() > self.settings.idle_timeout {
+                            anyhow::bail!("ticket {} timed out", ticket_id);
+                        }
+                        tx.send(tungstenite::Message::Ping(vec![])).await?;
+                    }
+                }
+            }
+            Ok(())
+        }
+        .await;
+
+        let mut queue = self.queue.lock().await;
+        queue.retain(|ticket| ticket.id != ticket_id);
+
+        r
+    }
+}
+
+async fn send_packet(
+    tx: &mut futures_util::stream::SplitSink<
+        hyper_tungstenite::WebSocketStream<hyper::upgrade::Upgraded>,
+        tungstenite::Message,
+    >,
+    which: tango_protos::matchmaking::packet::Which,
+) -> anyhow::Result<()> {
+    tx.send(tungstenite::Message::Binary(
+        tango_protos::matchmaking::Packet { which: Some(which) }.encode_to_vec(),
+    ))
+    .await?;
+    Ok(())
+}
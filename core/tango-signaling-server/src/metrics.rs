@@ -0,0 +1,176 @@
+use std::fmt::Write;
+use std::sync::atomic::Ordering;
+
+// Upper bounds of the session lifetime histogram buckets, in seconds.
+const SESSION_LIFETIME_BUCKETS: &[f64] = &[1.0, 5.0, 15.0, 30.0, 60.0, 300.0, 900.0, 3600.0];
+
+// Anyone can put anything in their User-Agent, so don't let them blow up our label set.
+const MAX_CLIENT_VERSIONS: usize = 64;
+
+struct Histogram {
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    pub connections: std::sync::atomic::AtomicI64,
+    pub relay_connections: std::sync::atomic::AtomicI64,
+    pub offers_relayed: std::sync::atomic::AtomicU64,
+    pub answers_relayed: std::sync::atomic::AtomicU64,
+    pub ice_candidates_relayed: std::sync::atomic::AtomicU64,
+    session_lifetimes: std::sync::Mutex<Option<Histogram>>,
+    errors: std::sync::Mutex<std::collections::BTreeMap<String, u64>>,
+    client_versions: std::sync::Mutex<std::collections::BTreeMap<String, u64>>,
+}
+
+impl Metrics {
+    pub fn observe_session_lifetime(&self, lifetime: std::time::Duration) {
+        let lifetime = lifetime.as_secs_f64();
+        let mut session_lifetimes = self.session_lifetimes.lock().unwrap();
+        let histogram = session_lifetimes.get_or_insert_with(|| Histogram {
+            counts: vec![0; SESSION_LIFETIME_BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        });
+        for (bound, count) in SESSION_LIFETIME_BUCKETS
+            .iter()
+            .zip(histogram.counts.iter_mut())
+        {
+            if lifetime <= *bound {
+                *count += 1;
+            }
+        }
+        histogram.sum += lifetime;
+        histogram.count += 1;
+    }
+
+    pub fn record_error(&self, reason: &str) {
+        *self
+            .errors
+            .lock()
+            .unwrap()
+            .entry(reason.to_string())
+            .or_insert(0) += 1;
+    }
+
+    pub fn record_client(&self, version: &str) {
+        let mut client_versions = self.client_versions.lock().unwrap();
+        let version = if client_versions.contains_key(version)
+            || client_versions.len() < MAX_CLIENT_VERSIONS
+        {
+            version
+        } else {
+            "other"
+        };
+        *client_versions.entry(version.to_string()).or_insert(0) += 1;
+    }
+
+    /// Renders everything in the Prometheus text exposition format.
+    pub fn render(&self, active_sessions: usize) -> String {
+        let mut out = String::new();
+
+        writeln!(out, "# TYPE tango_signaling_active_sessions gauge").unwrap();
+        writeln!(out, "tango_signaling_active_sessions {}", active_sessions).unwrap();
+
+        writeln!(out, "# TYPE tango_signaling_connections gauge").unwrap();
+        writeln!(
+            out,
+            "tango_signaling_connections {}",
+            self.connections.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        writeln!(out, "# TYPE tango_signaling_relay_connections gauge").unwrap();
+        writeln!(
+            out,
+            "tango_signaling_relay_connections {}",
+            self.relay_connections.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        writeln!(out, "# TYPE tango_signaling_relayed_total counter").unwrap();
+        for (kind, value) in [
+            ("offer", &self.offers_relayed),
+            ("answer", &self.answers_relayed),
+            ("ice_candidate", &self.ice_candidates_relayed),
+        ] {
+            writeln!(
+                out,
+                "tango_signaling_relayed_total{{kind=\"{}\"}} {}",
+                kind,
+                value.load(Ordering::Relaxed)
+            )
+            .unwrap();
+        }
+
+        writeln!(
+            out,
+            "# TYPE tango_signaling_session_lifetime_seconds histogram"
+        )
+        .unwrap();
+        {
+            let session_lifetimes = self.session_lifetimes.lock().unwrap();
+            let (counts, sum, count) = match session_lifetimes.as_ref() {
+                Some(h) => (h.counts.clone(), h.sum, h.count),
+                None => (vec![0; SESSION_LIFETIME_BUCKETS.len()], 0.0, 0),
+            };
+            for (bound, count) in SESSION_LIFETIME_BUCKETS.iter().zip(counts.iter()) {
+                writeln!(
+                    out,
+                    "tango_signaling_session_lifetime_seconds_bucket{{le=\"{}\"}} {}",
+                    bound, count
+                )
+                .unwrap();
+            }
+            writeln!(
+                out,
+                "tango_signaling_session_lifetime_seconds_bucket{{le=\"+Inf\"}} {}",
+                count
+            )
+            .unwrap();
+            writeln!(out, "tango_signaling_session_lifetime_seconds_sum {}", sum).unwrap();
+            writeln!(
+                out,
+                "tango_signaling_session_lifetime_seconds_count {}",
+                count
+            )
+            .unwrap();
+        }
+
+        writeln!(out, "# TYPE tango_signaling_errors_total counter").unwrap();
+        for (reason, count) in self.errors.lock().unwrap().iter() {
+            writeln!(
+                out,
+                "tango_signaling_errors_total{{reason=\"{}\"}} {}",
+                escape_label_value(reason),
+                count
+            )
+            .unwrap();
+        }
+
+        writeln!(
+            out,
+            "# TYPE tango_signaling_client_connections_total counter"
+        )
+        .unwrap();
+        for (version, count) in self.client_versions.lock().unwrap().iter() {
+            writeln!(
+                out,
+                "tango_signaling_client_connections_total{{version=\"{}\"}} {}",
+                escape_label_value(version),
+                count
+            )
+            .unwrap();
+        }
+
+        out
+    }
+}
+
+fn escape_label_value(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
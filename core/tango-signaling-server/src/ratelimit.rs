@@ -0,0 +1,60 @@
+struct Bucket {
+    tokens: f64,
+    last_refill_at: std::time::Instant,
+}
+
+/// A token bucket per client address.
+///
+/// A rate of 0 turns limiting off altogether.
+pub struct Limiter {
+    rate: f64,
+    burst: f64,
+    buckets: std::sync::Mutex<std::collections::HashMap<std::net::IpAddr, Bucket>>,
+}
+
+impl Limiter {
+    pub fn new(rate: f64, burst: u32) -> Limiter {
+        Limiter {
+            rate,
+            burst: burst as f64,
+            buckets: std::sync::Mutex::new(std::collections::HashMap::new()),
+        }
+    }
+
+    pub fn try_acquire(&self, addr: std::net::IpAddr) -> bool {
+        if self.rate <= 0.0 {
+            return true;
+        }
+        let mut buckets = self.buckets.lock().unwrap();
+        let now = std::time::Instant::now();
+        let bucket = buckets.entry(addr).or_insert_with(|| Bucket {
+            tokens: self.burst,
+            last_refill_at: now,
+        });
+        bucket.tokens = (bucket.tokens + (now - bucket.last_refill_at).as_secs_f64() * self.rate)
+            .min(self.burst);
+        bucket.last_refill_at = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// How long until the next token is available for an address that was just refused.
+    pub fn retry_after(&self) -> std::time::Duration {
+        if self.rate <= 0.0 {
+            return std::time::Duration::ZERO;
+        }
+        std::time::Duration::from_secs_f64(1.0 / self.rate)
+    }
+
+    /// Forgets buckets that have refilled completely, as they are no different from a fresh one.
+    pub fn prune(&self) {
+        let mut buckets = self.buckets.lock().unwrap();
+        let now = std::time::Instant::now();
+        buckets.retain(|_, bucket| {
+            bucket.tokens + (now - bucket.last_refill_at).as_secs_f64() * self.rate < self.burst
+        });
+    }
+}
@@ -0,0 +1,245 @@
+use crate::metrics;
+use futures_util::{SinkExt, StreamExt};
+use hmac::Mac;
+
+type WebSocket = hyper_tungstenite::WebSocketStream<hyper::upgrade::Upgraded>;
+
+pub struct Settings {
+    pub pairing_timeout: std::time::Duration,
+    /// How many bytes a second each side may send through the relay. 0 means unlimited.
+    pub max_bytes_per_second: u64,
+    pub max_burst_bytes: u64,
+}
+
+/// Issues and checks tickets for the relay, so only the participants of a signaling session can be relayed to each other.
+///
+/// Tickets can be checked by any instance that has the key they were signed with, so instances that only do signaling can send clients to a relay elsewhere.
+pub struct Tickets {
+    key: Vec<u8>,
+    ttl: std::time::Duration,
+}
+
+impl Tickets {
+    pub fn new(key: &[u8], ttl: std::time::Duration) -> Tickets {
+        Tickets {
+            key: key.to_vec(),
+            ttl,
+        }
+    }
+
+    fn mac(&self, session_id: &str, me: usize, expiry: u64) -> hmac::Hmac<sha1::Sha1> {
+        let mut mac = hmac::Hmac::<sha1::Sha1>::new_from_slice(&self.key).unwrap();
+        mac.update(format!("{}:{}:{}", session_id, me, expiry).as_bytes());
+        mac
+    }
+
+    /// Issues a ticket for a participant of a session.
+    pub fn issue(&self, session_id: &str, me: usize) -> anyhow::Result<String> {
+        let expiry = (std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?
+            + self.ttl)
+            .as_secs();
+        Ok(format!(
+            "{}.{}.{}",
+            me,
+            expiry,
+            base64::encode_config(
+                self.mac(session_id, me, expiry).finalize().into_bytes(),
+                base64::URL_SAFE_NO_PAD
+            )
+        ))
+    }
+
+    /// Checks a ticket for a session, returning which participant it was issued to if it is valid.
+    pub fn verify(&self, session_id: &str, token: &str) -> Option<usize> {
+        let mut parts = token.splitn(3, '.');
+        let me = parts.next()?.parse::<usize>().ok()?;
+        let expiry = parts.next()?.parse::<u64>().ok()?;
+        let signature = base64::decode_config(parts.next()?, base64::URL_SAFE_NO_PAD).ok()?;
+        if std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .ok()?
+            .as_secs()
+            > expiry
+        {
+            return None;
+        }
+        self.mac(session_id, me, expiry)
+            .verify_slice(&signature)
+            .ok()?;
+        Some(me)
+    }
+}
+
+// A token bucket over bytes which holds traffic back rather than refusing it, so clients over the limit see latency instead of errors.
+struct Throttle {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill_at: tokio::time::Instant,
+}
+
+impl Throttle {
+    fn new(rate: u64, burst: u64) -> Throttle {
+        Throttle {
+            rate: rate as f64,
+            burst: burst as f64,
+            tokens: burst as f64,
+            last_refill_at: tokio::time::Instant::now(),
+        }
+    }
+
+    async fn consume(&mut self, n: usize) {
+        if self.rate == 0.0 {
+            return;
+        }
+        let now = tokio::time::Instant::now();
+        self.tokens = (self.tokens + (now - self.last_refill_at).as_secs_f64() * self.rate)
+            .min(self.burst)
+            - n as f64;
+        self.last_refill_at = now;
+        if self.tokens < 0.0 {
+            tokio::time::sleep(std::time::Duration::from_secs_f64(-self.tokens / self.rate)).await;
+        }
+    }
+}
+
+/// Carries raw bytes between the two clients of a session when they can't reach each other directly.
+pub struct Server {
+    settings: Settings,
+    waiting: tokio::sync::Mutex<
+        std::collections::HashMap<String, (u64, usize, tokio::sync::oneshot::Sender<WebSocket>)>,
+    >,
+    next_waiter_id: std::sync::atomic::AtomicU64,
+    tickets: std::sync::Arc<Tickets>,
+    metrics: std::sync::Arc<metrics::Metrics>,
+}
+
+impl Server {
+    pub fn new(
+        settings: Settings,
+        tickets: std::sync::Arc<Tickets>,
+        metrics: std::sync::Arc<metrics::Metrics>,
+    ) -> Server {
+        Server {
+            settings,
+            waiting: tokio::sync::Mutex::new(std::collections::HashMap::new()),
+            next_waiter_id: std::sync::atomic::AtomicU64::new(0),
+            tickets,
+            metrics,
+        }
+    }
+
+    pub fn tickets(&self) -> std::sync::Arc<Tickets> {
+        self.tickets.clone()
+    }
+
+    /// Returns whether someone in the session is waiting for their peer to arrive.
+    pub async fn is_waiting(&self, session_id: &str) -> bool {
+        self.waiting.lock().await.contains_key(session_id)
+    }
+
+    /// Relays for a participant of a session, as given by their ticket.
+    pub async fn handle_stream(
+        &self,
+        ws: WebSocket,
+        session_id: &str,
+        me: usize,
+    ) -> anyhow::Result<()> {
+        self.metrics
+            .relay_connections
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let r = self.handle_stream_inner(ws, session_id, me).await;
+        self.metrics
+            .relay_connections
+            .fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+        r
+    }
+
+    async fn handle_stream_inner(
+        &self,
+        ws: WebSocket,
+        session_id: &str,
+        me: usize,
+    ) -> anyhow::Result<()> {
+        let peer_rx = {
+            let mut waiting = self.waiting.lock().await;
+            if let Some((_, peer_me, _)) = waiting.get(session_id) {
+                if *peer_me == me {
+                    anyhow::bail!("participant {} is already waiting at the relay", me);
+                }
+            }
+            if let Some((_, _, peer_tx)) = waiting.remove(session_id) {
+                // The other side is already waiting: hand our stream over and let them do the relaying.
+                if peer_tx.send(ws).is_err() {
+                    anyhow::bail!("peer left before we arrived");
+                }
+                return Ok(());
+            }
+
+            let waiter_id = self
+                .next_waiter_id
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let (peer_tx, peer_rx) = tokio::sync::oneshot::channel();
+            waiting.insert(session_id.to_string(), (waiter_id, me, peer_tx));
+            (waiter_id, peer_rx)
+        };
+        let (waiter_id, peer_rx) = peer_rx;
+
+        let peer = match tokio::time::timeout(self.settings.pairing_timeout, peer_rx).await {
+            Ok(Ok(peer)) => peer,
+            _ => {
+                let mut waiting = self.waiting.lock().await;
+                if waiting
+                    .get(session_id)
+                    .map(|(id, _, _)| *id == waiter_id)
+                    .unwrap_or(false)
+                {
+                    waiting.remove(session_id);
+                }
+                anyhow::bail!("timed out waiting for peer");
+            }
+        };
+
+        log::info!("relaying session: {}", session_id);
+        let (mut tx1, mut rx1) = ws.split();
+        let (mut tx2, mut rx2) = peer.split();
+        let r = tokio::select! {
+            r = pump(&mut rx1, &mut tx2, self.throttle()) => r,
+            r = pump(&mut rx2, &mut tx1, self.throttle()) => r,
+        };
+        let _ = tx1.send(tungstenite::Message::Close(None)).await;
+        let _ = tx2.send(tungstenite::Message::Close(None)).await;
+        log::info!("relay ended for session: {}", session_id);
+        r
+    }
+
+    fn throttle(&self) -> Throttle {
+        Throttle::new(
+            self.settings.max_bytes_per_second,
+            self.settings.max_burst_bytes,
+        )
+    }
+}
+
+async fn pump(
+    rx: &mut futures_util::stream::SplitStream<WebSocket>,
+    tx: &mut futures_util::stream::SplitSink<WebSocket, tungstenite::Message>,
+    mut throttle: Throttle,
+) -> anyhow::Result<()> {
+    while let Some(msg) = rx.next().await {
+        match msg? {
+            tungstenite::Message::Binary(d) => {
+                throttle.consume(d.len()).await;
+                tx.send(tungstenite::Message::Binary(d)).await?;
+            }
+            tungstenite::Message::Ping(_) | tungstenite::Message::Pong(_) => {}
+            tungstenite::Message::Close(_) => {
+                break;
+            }
+            m => {
+                anyhow::bail!("unexpected message: {:?}", m);
+            }
+        }
+    }
+    Ok(())
+}
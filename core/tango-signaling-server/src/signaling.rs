@@ -1,30 +1,208 @@
+use crate::metrics;
+use crate::ratelimit;
+use crate::relay;
+use crate::store;
 use futures_util::{SinkExt, StreamExt, TryStreamExt};
 use prost::Message;
 
-struct Session {
-    offer_sdp: String,
-    sinks: Vec<
-        futures_util::stream::SplitSink<
-            hyper_tungstenite::WebSocketStream<hyper::upgrade::Upgraded>,
-            tungstenite::Message,
-        >,
-    >,
+pub struct Settings {
+    pub session_ttl: std::time::Duration,
+    pub idle_timeout: std::time::Duration,
+    pub keepalive_interval: std::time::Duration,
+    pub max_sessions: usize,
+    pub message_limiter: ratelimit::Limiter,
+}
+
+const MAX_PARTICIPANTS: usize = 2;
+
+#[derive(Debug)]
+pub struct ClientError {
+    code: tango_protos::signaling::packet::error::Code,
+    message: String,
+}
+
+impl ClientError {
+    pub fn new(code: tango_protos::signaling::packet::error::Code, message: String) -> Self {
+        Self { code, message }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        tango_protos::signaling::Packet {
+            which: Some(tango_protos::signaling::packet::Which::Error(
+                tango_protos::signaling::packet::Error {
+                    code: self.code as i32,
+                    message: self.message.clone(),
+                },
+            )),
+        }
+        .encode_to_vec()
+    }
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+#[derive(serde::Serialize)]
+pub struct ParticipantInfo {
+    pub addr: std::net::IpAddr,
+    pub client_version: String,
+}
+
+#[derive(serde::Serialize)]
+pub struct SessionInfo {
+    pub session_id: String,
+    pub age_secs: u64,
+    pub idle_secs: u64,
+    pub participants: Vec<ParticipantInfo>,
 }
 
 pub struct Server {
-    sessions: std::sync::Arc<
-        tokio::sync::Mutex<
-            std::collections::HashMap<String, std::sync::Arc<tokio::sync::Mutex<Session>>>,
-        >,
-    >,
+    settings: Settings,
+    metrics: std::sync::Arc<metrics::Metrics>,
+    store: std::sync::Arc<dyn store::SessionStore>,
+    relay_tickets: Option<std::sync::Arc<relay::Tickets>>,
 }
 
 impl Server {
-    pub fn new() -> Server {
+    pub fn new(
+        settings: Settings,
+        store: std::sync::Arc<dyn store::SessionStore>,
+        metrics: std::sync::Arc<metrics::Metrics>,
+        relay_tickets: Option<std::sync::Arc<relay::Tickets>>,
+    ) -> Server {
         Server {
-            sessions: std::sync::Arc::new(
-                tokio::sync::Mutex::new(std::collections::HashMap::new()),
-            ),
+            settings,
+            metrics,
+            store,
+            relay_tickets,
+        }
+    }
+
+    pub async fn num_sessions(&self) -> anyhow::Result<usize> {
+        self.store.num_open().await
+    }
+
+    pub async fn has_session(&self, session_id: &str) -> anyhow::Result<bool> {
+        Ok(self
+            .store
+            .get(session_id)
+            .await?
+            .map(|record| !record.closed)
+            .unwrap_or(false))
+    }
+
+    pub async fn is_full(&self) -> anyhow::Result<bool> {
+        Ok(self.num_sessions().await? >= self.settings.max_sessions)
+    }
+
+    pub async fn list_sessions(&self) -> anyhow::Result<Vec<SessionInfo>> {
+        Ok(self
+            .store
+            .list()
+            .await?
+            .into_iter()
+            .filter(|(_, record)| !record.closed)
+            .map(|(session_id, record)| SessionInfo {
+                session_id,
+                age_secs: record.age().as_secs(),
+                idle_secs: record.idle().as_secs(),
+                participants: record
+                    .participants
+                    .iter()
+                    .filter(|participant| !participant.left)
+                    .map(|participant| ParticipantInfo {
+                        addr: participant.addr,
+                        client_version: participant.client_version.clone(),
+                    })
+                    .collect(),
+            })
+            .collect())
+    }
+
+    /// Forcibly ends a session, returning whether it existed.
+    pub async fn close_session(&self, session_id: &str) -> anyhow::Result<bool> {
+        log::info!("closing session: {}", session_id);
+        self.close(
+            session_id,
+            None,
+            Some(ClientError::new(
+                tango_protos::signaling::packet::error::Code::Unknown,
+                "session was closed by the server operator".to_string(),
+            )),
+        )
+        .await
+    }
+
+    // The participants' handlers pick up the final error and hang up on their clients by themselves.
+    async fn close(
+        &self,
+        session_id: &str,
+        generation: Option<u64>,
+        error: Option<ClientError>,
+    ) -> anyhow::Result<bool> {
+        match self
+            .store
+            .close(session_id, generation, error.map(|e| e.encode()))
+            .await?
+        {
+            Some(record) => {
+                self.metrics.observe_session_lifetime(record.age());
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    pub fn prune_message_limiter(&self) {
+        self.settings.message_limiter.prune();
+    }
+
+    pub async fn reap_sessions(&self) {
+        let mut interval = tokio::time::interval(self.settings.keepalive_interval);
+        loop {
+            interval.tick().await;
+
+            let sessions = match self.store.list().await {
+                Ok(sessions) => sessions,
+                Err(e) => {
+                    log::error!("failed to list sessions: {}", e);
+                    continue;
+                }
+            };
+
+            for (session_id, record) in sessions {
+                if record.idle() <= self.settings.idle_timeout
+                    && (record.closed || record.age() <= self.settings.session_ttl)
+                {
+                    continue;
+                }
+
+                let r = if record.closed {
+                    // Nobody is around to pick up what's left: they must have been on an instance that went away.
+                    log::info!("forgetting abandoned session: {}", session_id);
+                    self.store.remove(&session_id).await
+                } else {
+                    log::info!("reaping session: {}", session_id);
+                    self.close(
+                        &session_id,
+                        Some(record.generation),
+                        Some(ClientError::new(
+                            tango_protos::signaling::packet::error::Code::SessionExpired,
+                            "session has expired".to_string(),
+                        )),
+                    )
+                    .await
+                    .map(|_| ())
+                };
+                if let Err(e) = r {
+                    log::error!("failed to reap session {}: {}", session_id, e);
+                }
+            }
         }
     }
 
@@ -32,147 +210,357 @@ impl Server {
         &self,
         ws: hyper_tungstenite::WebSocketStream<hyper::upgrade::Upgraded>,
         session_id: &str,
+        addr: std::net::IpAddr,
+        client_version: &str,
     ) -> anyhow::Result<()> {
-        let (tx, mut rx) = ws.split();
-        let session_id_for_cleanup = std::sync::Arc::new(tokio::sync::Mutex::new(None));
-
-        let r = {
-            let sessions = self.sessions.clone();
-            let session_id_for_cleanup = session_id_for_cleanup.clone();
-            (move || async move {
-                let mut session = None;
-                let mut tx = Some(tx);
-                let mut me: usize = 0;
-
-                loop {
-                    let msg = match rx.try_next().await? {
-                        Some(tungstenite::Message::Binary(d)) => {
-                            tango_protos::signaling::Packet::decode(bytes::Bytes::from(d))?
+        self.metrics
+            .connections
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let r = self
+            .handle_stream_inner(ws, session_id, addr, client_version)
+            .await;
+        self.metrics
+            .connections
+            .fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+        if let Err(e) = r.as_ref() {
+            self.metrics
+                .record_error(&match e.downcast_ref::<ClientError>() {
+                    Some(e) => format!("{:?}", e.code),
+                    None => "Internal".to_string(),
+                });
+        }
+        r
+    }
+
+    async fn handle_stream_inner(
+        &self,
+        ws: hyper_tungstenite::WebSocketStream<hyper::upgrade::Upgraded>,
+        session_id: &str,
+        addr: std::net::IpAddr,
+        client_version: &str,
+    ) -> anyhow::Result<()> {
+        let (mut tx, mut rx) = ws.split();
+        let mut joined: Option<store::Joined> = None;
+        // Receiving from the store isn't cancel-safe, so it's done by a task of its own that is only stopped once we're done with the session.
+        let mut receiver: Option<tokio::task::JoinHandle<()>> = None;
+        let mut received_rx: Option<tokio::sync::mpsc::Receiver<anyhow::Result<Option<Vec<u8>>>>> =
+            None;
+
+        let r = async {
+            let mut keepalive = tokio::time::interval(self.settings.keepalive_interval);
+            let mut last_seen_at = std::time::Instant::now();
+
+            loop {
+                let msg = tokio::select! {
+                    msg = rx.try_next() => msg?,
+                    _ = keepalive.tick() => {
+                        // If the client has vanished without a close frame, we won't even get pongs back.
+                        if last_seen_at.elapsed() > self.settings.idle_timeout {
+                            anyhow::bail!("client timed out");
+                        }
+                        tx.send(tungstenite::Message::Ping(vec![])).await?;
+                        if let Some(joined) = joined.as_ref() {
+                            self.store.touch(session_id, joined.generation).await?;
                         }
-                        Some(tungstenite::Message::Close(_)) | None => {
-                            break;
+                        continue;
+                    }
+                    msg = async {
+                        match received_rx.as_mut() {
+                            Some(received_rx) => received_rx.recv().await,
+                            None => std::future::pending().await,
+                        }
+                    } => {
+                        match msg.transpose()?.flatten() {
+                            Some(msg) => {
+                                tx.send(tungstenite::Message::Binary(msg)).await?;
+                                continue;
+                            }
+                            None => {
+                                // The session is over, whether the other side left or it was closed from under us.
+                                tx.send(tungstenite::Message::Close(None)).await?;
+                                break;
+                            }
                         }
-                        Some(m) => {
-                            anyhow::bail!("unexpected message: {:?}", m);
+                    }
+                };
+
+                last_seen_at = std::time::Instant::now();
+
+                let msg = match msg {
+                    Some(tungstenite::Message::Binary(d)) => {
+                        if !self.settings.message_limiter.try_acquire(addr) {
+                            return Err(ClientError::new(
+                                tango_protos::signaling::packet::error::Code::RateLimited,
+                                "too many messages".to_string(),
+                            )
+                            .into());
                         }
-                    };
-                    log::debug!("received message: {:?}", msg);
-                    match msg.which {
-                        Some(tango_protos::signaling::packet::Which::Start(start)) => {
-                            let mut sessions = sessions.lock().await;
-                            session = Some(if let Some(session) = sessions.remove(session_id) {
-                                session
-                            } else {
-                                sessions
-                                    .entry(session_id.to_string())
-                                    .or_insert_with(|| {
-                                        std::sync::Arc::new(tokio::sync::Mutex::new(Session {
-                                            offer_sdp: start.offer_sdp.clone(),
-                                            sinks: vec![],
-                                        }))
-                                    })
-                                    .clone()
-                            });
-
-                            let session = if let Some(session) = session.as_ref() {
-                                session
-                            } else {
-                                anyhow::bail!("no such session");
-                            };
-                            let mut session = session.lock().await;
-                            *session_id_for_cleanup.lock().await = Some(session_id);
-                            let offer_sdp = session.offer_sdp.to_string();
-
-                            me = session.sinks.len();
-                            let tx = if let Some(tx) = tx.take() {
-                                tx
-                            } else {
-                                anyhow::bail!("attempted to take tx twice");
-                            };
-                            session.sinks.push(tx);
-
-                            if me == 1 {
-                                session.sinks[me]
-                                    .send(tungstenite::Message::Binary(
-                                        tango_protos::signaling::Packet {
-                                            which: Some(
-                                                tango_protos::signaling::packet::Which::Offer(
-                                                    tango_protos::signaling::packet::Offer {
-                                                        sdp: offer_sdp,
-                                                    },
-                                                ),
-                                            ),
-                                        }
-                                        .encode_to_vec(),
-                                    ))
-                                    .await?;
+                        match tango_protos::signaling::Packet::decode(bytes::Bytes::from(d)) {
+                            Ok(msg) => msg,
+                            Err(e) => {
+                                return Err(ClientError::new(
+                                    tango_protos::signaling::packet::error::Code::ProtocolViolation,
+                                    format!("could not decode packet: {}", e),
+                                )
+                                .into());
                             }
                         }
-                        Some(tango_protos::signaling::packet::Which::Offer(_)) => {
-                            anyhow::bail!(
-                                "received offer from client: only the server may send offers"
-                            );
+                    }
+                    Some(tungstenite::Message::Ping(_)) | Some(tungstenite::Message::Pong(_)) => {
+                        // tungstenite replies to pings by itself: all we care about is that the client is still alive.
+                        continue;
+                    }
+                    Some(tungstenite::Message::Close(_)) | None => {
+                        break;
+                    }
+                    Some(m) => {
+                        return Err(ClientError::new(
+                            tango_protos::signaling::packet::error::Code::ProtocolViolation,
+                            format!("unexpected message: {:?}", m),
+                        )
+                        .into());
+                    }
+                };
+                log::debug!("received message: {:?}", msg);
+                match msg.which {
+                    Some(tango_protos::signaling::packet::Which::Start(start)) => {
+                        if joined.is_some() {
+                            return Err(ClientError::new(
+                                tango_protos::signaling::packet::error::Code::ProtocolViolation,
+                                "attempted to start twice".to_string(),
+                            )
+                            .into());
                         }
-                        Some(tango_protos::signaling::packet::Which::Answer(answer)) => {
-                            let session = match session.as_ref() {
-                                Some(session) => session,
-                                None => {
-                                    anyhow::bail!("no session active");
+
+                        let new_joined = match self
+                            .store
+                            .join(
+                                session_id,
+                                &start.offer_sdp,
+                                store::Participant {
+                                    addr,
+                                    client_version: client_version.to_string(),
+                                    left: false,
+                                },
+                                &store::Limits {
+                                    max_sessions: self.settings.max_sessions,
+                                    max_participants: MAX_PARTICIPANTS,
+                                    session_ttl: self.settings.session_ttl,
+                                },
+                            )
+                            .await?
+                        {
+                            Ok(joined) => joined,
+                            Err(store::JoinError::TooManySessions) => {
+                                return Err(ClientError::new(
+                                    tango_protos::signaling::packet::error::Code::RateLimited,
+                                    "too many sessions".to_string(),
+                                )
+                                .into());
+                            }
+                            Err(store::JoinError::Expired) => {
+                                return Err(ClientError::new(
+                                    tango_protos::signaling::packet::error::Code::SessionExpired,
+                                    "session has expired".to_string(),
+                                )
+                                .into());
+                            }
+                            Err(store::JoinError::Full) => {
+                                return Err(ClientError::new(
+                                    tango_protos::signaling::packet::error::Code::SessionFull,
+                                    "session is full".to_string(),
+                                )
+                                .into());
+                            }
+                        };
+
+                        if new_joined.me == 1 {
+                            self.metrics
+                                .offers_relayed
+                                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                            tx.send(tungstenite::Message::Binary(
+                                tango_protos::signaling::Packet {
+                                    which: Some(tango_protos::signaling::packet::Which::Offer(
+                                        tango_protos::signaling::packet::Offer {
+                                            sdp: new_joined.offer_sdp.clone(),
+                                        },
+                                    )),
                                 }
-                            };
-                            let mut session = session.lock().await;
-                            session.sinks[0]
-                                .send(tungstenite::Message::Binary(
-                                    tango_protos::signaling::Packet {
-                                        which: Some(
-                                            tango_protos::signaling::packet::Which::Answer(
-                                                tango_protos::signaling::packet::Answer {
-                                                    sdp: answer.sdp,
-                                                },
-                                            ),
-                                        ),
-                                    }
-                                    .encode_to_vec(),
-                                ))
-                                .await?;
+                                .encode_to_vec(),
+                            ))
+                            .await?;
                         }
-                        Some(tango_protos::signaling::packet::Which::IceCandidate(
-                            ice_candidate,
-                        )) => {
-                            let session = match session.as_ref() {
-                                Some(session) => session,
-                                None => {
-                                    anyhow::bail!("no session active");
+                        let (received_tx, rx) = tokio::sync::mpsc::channel(1);
+                        receiver = Some(tokio::spawn({
+                            let store = self.store.clone();
+                            let session_id = session_id.to_string();
+                            let generation = new_joined.generation;
+                            let me = new_joined.me;
+                            async move {
+                                loop {
+                                    let r = store.receive(&session_id, generation, me).await;
+                                    let done = !matches!(r, Ok(Some(_)));
+                                    if received_tx.send(r).await.is_err() || done {
+                                        return;
+                                    }
+                                }
+                            }
+                        }));
+                        received_rx = Some(rx);
+                        joined = Some(new_joined);
+                    }
+                    Some(tango_protos::signaling::packet::Which::Answer(answer)) => {
+                        let joined = match joined.as_ref() {
+                            Some(joined) if joined.me == 1 => joined,
+                            _ => {
+                                return Err(ClientError::new(
+                                    tango_protos::signaling::packet::error::Code::ProtocolViolation,
+                                    "only the second participant may answer".to_string(),
+                                )
+                                .into());
+                            }
+                        };
+                        self.metrics
+                            .answers_relayed
+                            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                        self.store
+                            .send(
+                                session_id,
+                                joined.generation,
+                                0,
+                                tango_protos::signaling::Packet {
+                                    which: Some(tango_protos::signaling::packet::Which::Answer(
+                                        tango_protos::signaling::packet::Answer { sdp: answer.sdp },
+                                    )),
                                 }
-                            };
-                            let mut session = session.lock().await;
-                            session.sinks[1 - me]
-                                .send(tungstenite::Message::Binary(
-                                    tango_protos::signaling::Packet {
-                                        which: Some(
-                                            tango_protos::signaling::packet::Which::IceCandidate(
-                                                tango_protos::signaling::packet::IceCandidate {
-                                                    candidate: ice_candidate.candidate,
-                                                    mid: ice_candidate.mid,
-                                                },
-                                            ),
+                                .encode_to_vec(),
+                            )
+                            .await?;
+                    }
+                    Some(tango_protos::signaling::packet::Which::ConnectionResult(
+                        connection_result,
+                    )) => {
+                        let joined = match joined.as_ref() {
+                            Some(joined) => joined,
+                            None => {
+                                return Err(ClientError::new(
+                                    tango_protos::signaling::packet::error::Code::ProtocolViolation,
+                                    "no session active".to_string(),
+                                )
+                                .into());
+                            }
+                        };
+
+                        // Only participants of the session get a ticket, so nobody else can use the relay to talk to them.
+                        let token = match self.relay_tickets.as_ref() {
+                            Some(relay_tickets) => relay_tickets.issue(session_id, joined.me)?,
+                            None => "".to_string(),
+                        };
+                        tx.send(tungstenite::Message::Binary(
+                            tango_protos::signaling::Packet {
+                                which: Some(tango_protos::signaling::packet::Which::RelayTicket(
+                                    tango_protos::signaling::packet::RelayTicket { token },
+                                )),
+                            }
+                            .encode_to_vec(),
+                        ))
+                        .await?;
+
+                        // If the other side has already left, there's nobody left to tell: the client finds out when the session ends.
+                        self.store
+                            .send(
+                                session_id,
+                                joined.generation,
+                                1 - joined.me,
+                                tango_protos::signaling::Packet {
+                                    which: Some(
+                                        tango_protos::signaling::packet::Which::ConnectionResult(
+                                            connection_result,
                                         ),
-                                    }
-                                    .encode_to_vec(),
-                                ))
-                                .await?;
+                                    ),
+                                }
+                                .encode_to_vec(),
+                            )
+                            .await?;
+                    }
+                    Some(tango_protos::signaling::packet::Which::IceCandidate(ice_candidate)) => {
+                        let joined = match joined.as_ref() {
+                            Some(joined) => joined,
+                            None => {
+                                return Err(ClientError::new(
+                                    tango_protos::signaling::packet::error::Code::ProtocolViolation,
+                                    "no session active".to_string(),
+                                )
+                                .into());
+                            }
+                        };
+                        self.metrics
+                            .ice_candidates_relayed
+                            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                        if !self
+                            .store
+                            .send(
+                                session_id,
+                                joined.generation,
+                                1 - joined.me,
+                                tango_protos::signaling::Packet {
+                                    which: Some(
+                                        tango_protos::signaling::packet::Which::IceCandidate(
+                                            tango_protos::signaling::packet::IceCandidate {
+                                                candidate: ice_candidate.candidate,
+                                                mid: ice_candidate.mid,
+                                            },
+                                        ),
+                                    ),
+                                }
+                                .encode_to_vec(),
+                            )
+                            .await?
+                        {
+                            return Err(ClientError::new(
+                                tango_protos::signaling::packet::error::Code::ProtocolViolation,
+                                "no peer to send ice candidate to".to_string(),
+                            )
+                            .into());
                         }
-                        p => anyhow::bail!("unknown packet: {:?}", p),
+                    }
+                    p => {
+                        return Err(ClientError::new(
+                            tango_protos::signaling::packet::error::Code::ProtocolViolation,
+                            format!("unexpected packet: {:?}", p),
+                        )
+                        .into());
                     }
                 }
-                Ok(())
-            })()
-            .await
-        };
-
-        if let Some(session_id) = *session_id_for_cleanup.lock().await {
-            let mut sessions = self.sessions.lock().await;
-            sessions.remove(session_id);
+            }
+            Ok(())
+        }
+        .await;
+
+        if let Some(receiver) = receiver {
+            receiver.abort();
+        }
+
+        // Let the client know why we are hanging up on them, if it was their fault.
+        if let Err(e) = r.as_ref() {
+            if let Some(e) = e.downcast_ref::<ClientError>() {
+                let _ = tx.send(tungstenite::Message::Binary(e.encode())).await;
+                let _ = tx.send(tungstenite::Message::Close(None)).await;
+            }
+        }
+
+        // The session may have been reaped and replaced by a new one with the same ID in the meantime, which we must leave alone.
+        if let Some(joined) = joined {
+            if let Err(e) = self.close(session_id, Some(joined.generation), None).await {
+                log::error!("failed to close session {}: {}", session_id, e);
+            }
+            if let Err(e) = self
+                .store
+                .leave(session_id, joined.generation, joined.me)
+                .await
+            {
+                log::error!("failed to leave session {}: {}", session_id, e);
+            }
         }
 
         r
@@ -0,0 +1,892 @@
+use rand::Rng;
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Participant {
+    pub addr: std::net::IpAddr,
+    pub client_version: String,
+    pub left: bool,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Record {
+    // Distinguishes this session from any earlier one that had the same ID.
+    pub generation: u64,
+    pub offer_sdp: String,
+    pub created_at: std::time::SystemTime,
+    pub last_seen_at: std::time::SystemTime,
+    pub participants: Vec<Participant>,
+    // Messages waiting to be picked up by each participant, in the same order as participants.
+    pub mailboxes: Vec<std::collections::VecDeque<Vec<u8>>>,
+    // A closed session has its remaining messages delivered, but can no longer be joined.
+    pub closed: bool,
+}
+
+impl Record {
+    pub fn age(&self) -> std::time::Duration {
+        self.created_at.elapsed().unwrap_or_default()
+    }
+
+    pub fn idle(&self) -> std::time::Duration {
+        self.last_seen_at.elapsed().unwrap_or_default()
+    }
+}
+
+#[derive(Clone)]
+pub struct Limits {
+    pub max_sessions: usize,
+    pub max_participants: usize,
+    pub session_ttl: std::time::Duration,
+}
+
+#[derive(Debug)]
+pub enum JoinError {
+    TooManySessions,
+    Expired,
+    Full,
+}
+
+pub struct Joined {
+    pub generation: u64,
+    pub me: usize,
+    pub offer_sdp: String,
+}
+
+/// Where sessions and the messages between their participants live.
+///
+/// Each participant's connection only ever writes to its own websocket: messages for the other participant go through the store, so the two may be connected to different instances of the server as long as the store is shared between them.
+#[async_trait::async_trait]
+pub trait SessionStore: Send + Sync {
+    /// Joins the session, creating it with the given offer if there is no open session with this ID yet.
+    async fn join(
+        &self,
+        session_id: &str,
+        offer_sdp: &str,
+        participant: Participant,
+        limits: &Limits,
+    ) -> anyhow::Result<Result<Joined, JoinError>>;
+
+    async fn touch(&self, session_id: &str, generation: u64) -> anyhow::Result<()>;
+
+    /// Leaves a message for a participant, returning false if the session is no longer open.
+    async fn send(
+        &self,
+        session_id: &str,
+        generation: u64,
+        to: usize,
+        msg: Vec<u8>,
+    ) -> anyhow::Result<bool>;
+
+    /// Waits for the next message for a participant, returning None once the session is closed and there is nothing left to deliver.
+    ///
+    /// This is not cancel-safe: a message may be taken out of the mailbox even if the future is dropped before it completes.
+    async fn receive(
+        &self,
+        session_id: &str,
+        generation: u64,
+        me: usize,
+    ) -> anyhow::Result<Option<Vec<u8>>>;
+
+    /// Closes the session, leaving a final message for each participant if given. If a generation is given, only that generation of the session is closed.
+    ///
+    /// Returns the session as it was if it was open.
+    async fn close(
+        &self,
+        session_id: &str,
+        generation: Option<u64>,
+        final_msg: Option<Vec<u8>>,
+    ) -> anyhow::Result<Option<Record>>;
+
+    /// Marks a participant as gone, forgetting the session entirely once it is closed and everyone is gone.
+    async fn leave(&self, session_id: &str, generation: u64, me: usize) -> anyhow::Result<()>;
+
+    /// Forgets a session outright, e.g. when it is closed but its participants vanished without leaving.
+    async fn remove(&self, session_id: &str) -> anyhow::Result<()>;
+
+    /// Gets a session, including a closed one.
+    async fn get(&self, session_id: &str) -> anyhow::Result<Option<Record>>;
+
+    /// Counts the sessions that are still open.
+    async fn num_open(&self) -> anyhow::Result<usize>;
+
+    /// Lists every session, including closed ones.
+    async fn list(&self) -> anyhow::Result<Vec<(String, Record)>>;
+}
+
+fn new_record(offer_sdp: &str, participant: Participant) -> Record {
+    let now = std::time::SystemTime::now();
+    Record {
+        generation: rand::thread_rng().gen(),
+        offer_sdp: offer_sdp.to_string(),
+        created_at: now,
+        last_seen_at: now,
+        participants: vec![participant],
+        mailboxes: vec![std::collections::VecDeque::new()],
+        closed: false,
+    }
+}
+
+fn num_open(sessions: &std::collections::HashMap<String, Record>) -> usize {
+    sessions.values().filter(|record| !record.closed).count()
+}
+
+/// Joins a session in sessions, which only needs to hold the session being joined: the number of open sessions is given separately.
+fn join_sessions(
+    sessions: &mut std::collections::HashMap<String, Record>,
+    num_open: usize,
+    session_id: &str,
+    offer_sdp: &str,
+    participant: Participant,
+    limits: &Limits,
+) -> Result<Joined, JoinError> {
+    match sessions.get_mut(session_id) {
+        Some(record) if !record.closed => {
+            if record.age() > limits.session_ttl {
+                return Err(JoinError::Expired);
+            }
+            if record.participants.len() >= limits.max_participants {
+                return Err(JoinError::Full);
+            }
+            record.participants.push(participant);
+            record.mailboxes.push(std::collections::VecDeque::new());
+            record.last_seen_at = std::time::SystemTime::now();
+            Ok(Joined {
+                generation: record.generation,
+                me: record.participants.len() - 1,
+                offer_sdp: record.offer_sdp.clone(),
+            })
+        }
+        _ => {
+            if num_open >= limits.max_sessions {
+                return Err(JoinError::TooManySessions);
+            }
+            let record = new_record(offer_sdp, participant);
+            let joined = Joined {
+                generation: record.generation,
+                me: 0,
+                offer_sdp: record.offer_sdp.clone(),
+            };
+            sessions.insert(session_id.to_string(), record);
+            Ok(joined)
+        }
+    }
+}
+
+fn get_record<'a>(
+    sessions: &'a mut std::collections::HashMap<String, Record>,
+    session_id: &str,
+    generation: u64,
+) -> Option<&'a mut Record> {
+    sessions
+        .get_mut(session_id)
+        .filter(|record| record.generation == generation)
+}
+
+fn close_session(
+    sessions: &mut std::collections::HashMap<String, Record>,
+    session_id: &str,
+    generation: Option<u64>,
+    final_msg: Option<Vec<u8>>,
+) -> Option<Record> {
+    let record = sessions.get_mut(session_id)?;
+    if record.closed || generation.map(|g| g != record.generation).unwrap_or(false) {
+        return None;
+    }
+    let before = record.clone();
+    record.closed = true;
+    if let Some(final_msg) = final_msg {
+        for mailbox in record.mailboxes.iter_mut() {
+            mailbox.push_back(final_msg.clone());
+        }
+    }
+    if record.participants.iter().all(|p| p.left) {
+        sessions.remove(session_id);
+    }
+    Some(before)
+}
+
+fn leave_session(
+    sessions: &mut std::collections::HashMap<String, Record>,
+    session_id: &str,
+    generation: u64,
+    me: usize,
+) {
+    let record = if let Some(record) = get_record(sessions, session_id, generation) {
+        record
+    } else {
+        return;
+    };
+    if let Some(participant) = record.participants.get_mut(me) {
+        participant.left = true;
+    }
+    if let Some(mailbox) = record.mailboxes.get_mut(me) {
+        mailbox.clear();
+    }
+    if record.closed && record.participants.iter().all(|p| p.left) {
+        sessions.remove(session_id);
+    }
+}
+
+/// Keeps sessions in this process only.
+#[derive(Default)]
+pub struct MemoryStore {
+    sessions: tokio::sync::Mutex<std::collections::HashMap<String, Record>>,
+    changed: tokio::sync::Notify,
+}
+
+#[async_trait::async_trait]
+impl SessionStore for MemoryStore {
+    async fn join(
+        &self,
+        session_id: &str,
+        offer_sdp: &str,
+        participant: Participant,
+        limits: &Limits,
+    ) -> anyhow::Result<Result<Joined, JoinError>> {
+        let mut sessions = self.sessions.lock().await;
+        let n = num_open(&sessions);
+        Ok(join_sessions(
+            &mut sessions,
+            n,
+            session_id,
+            offer_sdp,
+            participant,
+            limits,
+        ))
+    }
+
+    async fn touch(&self, session_id: &str, generation: u64) -> anyhow::Result<()> {
+        let mut sessions = self.sessions.lock().await;
+        if let Some(record) = get_record(&mut sessions, session_id, generation) {
+            record.last_seen_at = std::time::SystemTime::now();
+        }
+        Ok(())
+    }
+
+    async fn send(
+        &self,
+        session_id: &str,
+        generation: u64,
+        to: usize,
+        msg: Vec<u8>,
+    ) -> anyhow::Result<bool> {
+        let mut sessions = self.sessions.lock().await;
+        let mailbox = match get_record(&mut sessions, session_id, generation) {
+            Some(record) if !record.closed => record.mailboxes.get_mut(to),
+            _ => None,
+        };
+        let mailbox = if let Some(mailbox) = mailbox {
+            mailbox
+        } else {
+            return Ok(false);
+        };
+        mailbox.push_back(msg);
+        self.changed.notify_waiters();
+        Ok(true)
+    }
+
+    async fn receive(
+        &self,
+        session_id: &str,
+        generation: u64,
+        me: usize,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        loop {
+            // This must be created before we look, so we can't miss a notification that arrives in between.
+            let changed = self.changed.notified();
+            {
+                let mut sessions = self.sessions.lock().await;
+                let record = if let Some(record) = get_record(&mut sessions, session_id, generation)
+                {
+                    record
+                } else {
+                    return Ok(None);
+                };
+                if let Some(msg) = record.mailboxes.get_mut(me).and_then(|m| m.pop_front()) {
+                    return Ok(Some(msg));
+                }
+                if record.closed {
+                    return Ok(None);
+                }
+            }
+            changed.await;
+        }
+    }
+
+    async fn close(
+        &self,
+        session_id: &str,
+        generation: Option<u64>,
+        final_msg: Option<Vec<u8>>,
+    ) -> anyhow::Result<Option<Record>> {
+        let mut sessions = self.sessions.lock().await;
+        let record = close_session(&mut sessions, session_id, generation, final_msg);
+        self.changed.notify_waiters();
+        Ok(record)
+    }
+
+    async fn leave(&self, session_id: &str, generation: u64, me: usize) -> anyhow::Result<()> {
+        let mut sessions = self.sessions.lock().await;
+        leave_session(&mut sessions, session_id, generation, me);
+        Ok(())
+    }
+
+    async fn remove(&self, session_id: &str) -> anyhow::Result<()> {
+        self.sessions.lock().await.remove(session_id);
+        self.changed.notify_waiters();
+        Ok(())
+    }
+
+    async fn get(&self, session_id: &str) -> anyhow::Result<Option<Record>> {
+        Ok(self.sessions.lock().await.get(session_id).cloned())
+    }
+
+    async fn num_open(&self) -> anyhow::Result<usize> {
+        Ok(num_open(&*self.sessions.lock().await))
+    }
+
+    async fn list(&self) -> anyhow::Result<Vec<(String, Record)>> {
+        Ok(self
+            .sessions
+            .lock()
+            .await
+            .iter()
+            .map(|(session_id, record)| (session_id.clone(), record.clone()))
+            .collect())
+    }
+}
+
+/// Keeps each session in its own JSON file in a directory, so they survive restarts and can be shared by every instance that can see the directory, e.g. on a shared volume.
+///
+/// Instances don't talk to each other directly, so waiting for messages is done by polling the session's file.
+pub struct FileStore {
+    dir: std::path::PathBuf,
+    poll_interval: std::time::Duration,
+}
+
+// Sessions are locked through a fixed set of lock files rather than one per session, as a lock file can't be safely deleted while someone else might be waiting on it.
+const NUM_LOCK_FILES: u64 = 64;
+
+const OPEN_SUFFIX: &str = ".json";
+const CLOSED_SUFFIX: &str = ".closed.json";
+
+fn lock(path: &std::path::Path) -> anyhow::Result<std::fs::File> {
+    let lock_file = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .open(path)?;
+    fs2::FileExt::lock_exclusive(&lock_file)?;
+    Ok(lock_file)
+}
+
+fn read_record(path: &std::path::Path) -> anyhow::Result<Option<Record>> {
+    match std::fs::read(path) {
+        Ok(buf) => Ok(Some(serde_json::from_slice(&buf)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn remove_file(path: &std::path::Path) -> anyhow::Result<()> {
+    match std::fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+impl FileStore {
+    pub fn new(
+        dir: &std::path::Path,
+        poll_interval: std::time::Duration,
+    ) -> anyhow::Result<FileStore> {
+        std::fs::create_dir_all(dir.join(".locks"))?;
+        Ok(FileStore {
+            dir: dir.to_path_buf(),
+            poll_interval,
+        })
+    }
+
+    /// Runs f on a map holding just the given session, if it exists, while holding its lock, writing it back afterwards if f says it was changed.
+    ///
+    /// If join is set, the lock on the whole store is also held and f is given the number of open sessions, so sessions can't be created past the limit.
+    async fn with_session<T, F>(&self, session_id: &str, join: bool, f: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut std::collections::HashMap<String, Record>, usize) -> (T, bool)
+            + Send
+            + 'static,
+    {
+        let dir = self.dir.clone();
+        let session_id = session_id.to_string();
+        tokio::task::spawn_blocking(move || {
+            let store_lock_file = if join {
+                Some(lock(&dir.join(".lock"))?)
+            } else {
+                None
+            };
+            let num_open = if join { count_open(&dir)? } else { 0 };
+
+            // FNV-1a, as it needs to come out the same in every instance.
+            let hash = session_id.bytes().fold(0xcbf29ce484222325u64, |h, b| {
+                (h ^ b as u64).wrapping_mul(0x100000001b3)
+            });
+            let lock_file = lock(&dir.join(".locks").join((hash % NUM_LOCK_FILES).to_string()))?;
+
+            let open_path = dir.join(format!("{}{}", session_id, OPEN_SUFFIX));
+            let closed_path = dir.join(format!("{}{}", session_id, CLOSED_SUFFIX));
+
+            let mut sessions = std::collections::HashMap::new();
+            if let Some(record) = match read_record(&open_path)? {
+                Some(record) => Some(record),
+                None => read_record(&closed_path)?,
+            } {
+                sessions.insert(session_id.clone(), record);
+            }
+            let (r, changed) = f(&mut sessions, num_open);
+            if changed {
+                match sessions.get(&session_id) {
+                    Some(record) => {
+                        let (path, other_path) = if record.closed {
+                            (&closed_path, &open_path)
+                        } else {
+                            (&open_path, &closed_path)
+                        };
+                        // Write to the side and rename over, so nobody ever sees a half-written file.
+                        let mut tmp_path = path.as_os_str().to_owned();
+                        tmp_path.push(".tmp");
+                        std::fs::write(&tmp_path, serde_json::to_vec(record)?)?;
+                        std::fs::rename(&tmp_path, path)?;
+                        remove_file(other_path)?;
+                    }
+                    None => {
+                        remove_file(&open_path)?;
+                        remove_file(&closed_path)?;
+                    }
+                }
+            }
+
+            fs2::FileExt::unlock(&lock_file)?;
+            if let Some(store_lock_file) = store_lock_file {
+                fs2::FileExt::unlock(&store_lock_file)?;
+            }
+            Ok(r)
+        })
+        .await?
+    }
+}
+
+/// Counts open sessions by their file names alone, without reading them.
+fn count_open(dir: &std::path::Path) -> anyhow::Result<usize> {
+    let mut n = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let file_name = entry?.file_name();
+        let file_name = file_name.to_string_lossy();
+        if !file_name.starts_with('.')
+            && file_name.ends_with(OPEN_SUFFIX)
+            && !file_name.ends_with(CLOSED_SUFFIX)
+        {
+            n += 1;
+        }
+    }
+    Ok(n)
+}
+
+#[async_trait::async_trait]
+impl SessionStore for FileStore {
+    async fn join(
+        &self,
+        session_id: &str,
+        offer_sdp: &str,
+        participant: Participant,
+        limits: &Limits,
+    ) -> anyhow::Result<Result<Joined, JoinError>> {
+        let session_id_owned = session_id.to_string();
+        let offer_sdp = offer_sdp.to_string();
+        let limits = limits.clone();
+        self.with_session(session_id, true, move |sessions, num_open| {
+            let r = join_sessions(
+                sessions,
+                num_open,
+                &session_id_owned,
+                &offer_sdp,
+                participant,
+                &limits,
+            );
+            let changed = r.is_ok();
+            (r, changed)
+        })
+        .await
+    }
+
+    async fn touch(&self, session_id: &str, generation: u64) -> anyhow::Result<()> {
+        let session_id_owned = session_id.to_string();
+        self.with_session(session_id, false, move |sessions, _| {
+            match get_record(sessions, &session_id_owned, generation) {
+                Some(record) => {
+                    record.last_seen_at = std::time::SystemTime::now();
+                    ((), true)
+                }
+                None => ((), false),
+            }
+        })
+        .await
+    }
+
+    async fn send(
+        &self,
+        session_id: &str,
+        generation: u64,
+        to: usize,
+        msg: Vec<u8>,
+    ) -> anyhow::Result<bool> {
+        let session_id_owned = session_id.to_string();
+        self.with_session(session_id, false, move |sessions, _| {
+            match get_record(sessions, &session_id_owned, generation) {
+                Some(record) if !record.closed => match record.mailboxes.get_mut(to) {
+                    Some(mailbox) => {
+                        mailbox.push_back(msg);
+                        (true, true)
+                    }
+                    None => (false, false),
+                },
+                _ => (false, false),
+            }
+        })
+        .await
+    }
+
+    async fn receive(
+        &self,
+        session_id: &str,
+        generation: u64,
+        me: usize,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut interval = tokio::time::interval(self.poll_interval);
+        loop {
+            interval.tick().await;
+            let session_id_owned = session_id.to_string();
+            // Outer None means there is nothing yet, inner None means there never will be.
+            let r = self
+                .with_session(session_id, false, move |sessions, _| {
+                    let record =
+                        if let Some(record) = get_record(sessions, &session_id_owned, generation) {
+                            record
+                        } else {
+                            return (Some(None), false);
+                        };
+                    if let Some(msg) = record.mailboxes.get_mut(me).and_then(|m| m.pop_front()) {
+                        return (Some(Some(msg)), true);
+                    }
+                    if record.closed {
+                        return (Some(None), false);
+                    }
+                    (None, false)
+                })
+                .await?;
+            if let Some(r) = r {
+                return Ok(r);
+            }
+        }
+    }
+
+    async fn close(
+        &self,
+        session_id: &str,
+        generation: Option<u64>,
+        final_msg: Option<Vec<u8>>,
+    ) -> anyhow::Result<Option<Record>> {
+        let session_id_owned = session_id.to_string();
+        self.with_session(session_id, false, move |sessions, _| {
+            let record = close_session(sessions, &session_id_owned, generation, final_msg);
+            let changed = record.is_some();
+            (record, changed)
+        })
+        .await
+    }
+
+    async fn leave(&self, session_id: &str, generation: u64, me: usize) -> anyhow::Result<()> {
+        let session_id_owned = session_id.to_string();
+        self.with_session(session_id, false, move |sessions, _| {
+            leave_session(sessions, &session_id_owned, generation, me);
+            ((), true)
+        })
+        .await
+    }
+
+    async fn remove(&self, session_id: &str) -> anyhow::Result<()> {
+        let session_id_owned = session_id.to_string();
+        self.with_session(session_id, false, move |sessions, _| {
+            let changed = sessions.remove(&session_id_owned).is_some();
+            ((), changed)
+        })
+        .await
+    }
+
+    async fn get(&self, session_id: &str) -> anyhow::Result<Option<Record>> {
+        let session_id_owned = session_id.to_string();
+        self.with_session(session_id, false, move |sessions, _| {
+            (sessions.remove(&session_id_owned), false)
+        })
+        .await
+    }
+
+    async fn num_open(&self) -> anyhow::Result<usize> {
+        let dir = self.dir.clone();
+        tokio::task::spawn_blocking(move || count_open(&dir)).await?
+    }
+
+    async fn list(&self) -> anyhow::Result<Vec<(String, Record)>> {
+        let dir = self.dir.clone();
+        tokio::task::spawn_blocking(move || {
+            // Files are only ever replaced whole, so they can be read without taking their locks. A session that is being closed or rejoined may briefly have both files: the newer one wins, which is the closed one if it was just closed.
+            let mut sessions = std::collections::HashMap::new();
+            for entry in std::fs::read_dir(&dir)? {
+                let entry = entry?;
+                let file_name = entry.file_name();
+                let file_name = file_name.to_string_lossy();
+                if file_name.starts_with('.') {
+                    continue;
+                }
+                let session_id = if let Some(session_id) = file_name.strip_suffix(CLOSED_SUFFIX) {
+                    session_id
+                } else if let Some(session_id) = file_name.strip_suffix(OPEN_SUFFIX) {
+                    session_id
+                } else {
+                    continue;
+                };
+                let record = if let Some(record) = read_record(&entry.path())? {
+                    record
+                } else {
+                    continue;
+                };
+                match sessions.entry(session_id.to_string()) {
+                    std::collections::hash_map::Entry::Occupied(mut e) => {
+                        if (record.last_seen_at, record.closed)
+                            > (e.get().last_seen_at, e.get().closed)
+                        {
+                            e.insert(record);
+                        }
+                    }
+                    std::collections::hash_map::Entry::Vacant(e) => {
+                        e.insert(record);
+                    }
+                }
+            }
+            Ok(sessions.into_iter().collect())
+        })
+        .await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TempDir(std::path::PathBuf);
+
+    impl TempDir {
+        fn new() -> TempDir {
+            TempDir(std::env::temp_dir().join(format!(
+                "tango-signaling-server-store-{}",
+                rand::random::<u64>()
+            )))
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn participant() -> Participant {
+        Participant {
+            addr: std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST),
+            client_version: "test".to_string(),
+            left: false,
+        }
+    }
+
+    fn limits() -> Limits {
+        Limits {
+            max_sessions: 2,
+            max_participants: 2,
+            session_ttl: std::time::Duration::from_secs(60),
+        }
+    }
+
+    async fn join(store: &dyn SessionStore, session_id: &str) -> Joined {
+        store
+            .join(session_id, "offer", participant(), &limits())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    async fn check_join_after_close(store: &dyn SessionStore) {
+        let first = join(store, "a").await;
+        join(store, "a").await;
+        assert!(store
+            .close("a", Some(first.generation), Some(b"bye".to_vec()))
+            .await
+            .unwrap()
+            .is_some());
+        assert!(store.get("a").await.unwrap().is_some());
+        assert_eq!(store.num_open().await.unwrap(), 0);
+
+        let second = join(store, "a").await;
+        assert_ne!(second.generation, first.generation);
+        assert_eq!(second.me, 0);
+        assert_eq!(store.num_open().await.unwrap(), 1);
+
+        // The old generation is gone, final message and all.
+        assert_eq!(store.receive("a", first.generation, 1).await.unwrap(), None);
+        assert!(store
+            .close("a", Some(first.generation), None)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(!store.get("a").await.unwrap().unwrap().closed);
+    }
+
+    async fn check_generation_mismatch(store: &dyn SessionStore) {
+        let joined = join(store, "a").await;
+        join(store, "a").await;
+        let other = joined.generation.wrapping_add(1);
+
+        assert!(!store.send("a", other, 1, b"hi".to_vec()).await.unwrap());
+        assert_eq!(store.receive("a", other, 1).await.unwrap(), None);
+        store.leave("a", other, 0).await.unwrap();
+        assert!(store.close("a", Some(other), None).await.unwrap().is_none());
+
+        let record = store.get("a").await.unwrap().unwrap();
+        assert!(!record.closed);
+        assert!(record.participants.iter().all(|p| !p.left));
+
+        assert!(store
+            .send("a", joined.generation, 1, b"hi".to_vec())
+            .await
+            .unwrap());
+        assert_eq!(
+            store.receive("a", joined.generation, 1).await.unwrap(),
+            Some(b"hi".to_vec())
+        );
+    }
+
+    async fn check_leave_then_remove(store: &dyn SessionStore) {
+        let joined = join(store, "a").await;
+        join(store, "a").await;
+
+        // Leaving doesn't forget an open session, even once everyone is gone...
+        store.leave("a", joined.generation, 0).await.unwrap();
+        store.leave("a", joined.generation, 1).await.unwrap();
+        assert!(store.get("a").await.unwrap().is_some());
+
+        // ...but closing it then does.
+        assert!(store
+            .close("a", Some(joined.generation), None)
+            .await
+            .unwrap()
+            .is_some());
+        assert!(store.get("a").await.unwrap().is_none());
+
+        // A closed session whose participants never left sticks around until it is removed.
+        let joined = join(store, "b").await;
+        store
+            .close("b", Some(joined.generation), Some(b"bye".to_vec()))
+            .await
+            .unwrap();
+        assert_eq!(store.list().await.unwrap().len(), 1);
+        store.remove("b").await.unwrap();
+        assert!(store.get("b").await.unwrap().is_none());
+        assert!(store.list().await.unwrap().is_empty());
+    }
+
+    async fn check_too_many_sessions(store: &dyn SessionStore) {
+        join(store, "a").await;
+        join(store, "b").await;
+        assert!(matches!(
+            store
+                .join("c", "offer", participant(), &limits())
+                .await
+                .unwrap(),
+            Err(JoinError::TooManySessions)
+        ));
+
+        // Existing sessions can still be joined, up to their own limit.
+        join(store, "a").await;
+        assert!(matches!(
+            store
+                .join("a", "offer", participant(), &limits())
+                .await
+                .unwrap(),
+            Err(JoinError::Full)
+        ));
+    }
+
+    #[tokio::test]
+    async fn memory_store_join_after_close() {
+        check_join_after_close(&MemoryStore::default()).await;
+    }
+
+    #[tokio::test]
+    async fn memory_store_generation_mismatch() {
+        check_generation_mismatch(&MemoryStore::default()).await;
+    }
+
+    #[tokio::test]
+    async fn memory_store_leave_then_remove() {
+        check_leave_then_remove(&MemoryStore::default()).await;
+    }
+
+    #[tokio::test]
+    async fn memory_store_too_many_sessions() {
+        check_too_many_sessions(&MemoryStore::default()).await;
+    }
+
+    fn file_store(dir: &TempDir) -> FileStore {
+        FileStore::new(&dir.0, std::time::Duration::from_millis(1)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn file_store_join_after_close() {
+        let dir = TempDir::new();
+        check_join_after_close(&file_store(&dir)).await;
+    }
+
+    #[tokio::test]
+    async fn file_store_generation_mismatch() {
+        let dir = TempDir::new();
+        check_generation_mismatch(&file_store(&dir)).await;
+    }
+
+    #[tokio::test]
+    async fn file_store_leave_then_remove() {
+        let dir = TempDir::new();
+        check_leave_then_remove(&file_store(&dir)).await;
+    }
+
+    #[tokio::test]
+    async fn file_store_too_many_sessions() {
+        let dir = TempDir::new();
+        check_too_many_sessions(&file_store(&dir)).await;
+    }
+
+    #[tokio::test]
+    async fn file_store_shared_between_instances() {
+        let dir = TempDir::new();
+        let a = file_store(&dir);
+        let b = file_store(&dir);
+        let joined = join(&a, "a").await;
+        assert_eq!(join(&b, "a").await.generation, joined.generation);
+        assert!(b
+            .send("a", joined.generation, 0, b"hi".to_vec())
+            .await
+            .unwrap());
+        assert_eq!(
+            a.receive("a", joined.generation, 0).await.unwrap(),
+            Some(b"hi".to_vec())
+        );
+        assert_eq!(a.num_open().await.unwrap(), 1);
+    }
+}
@@ -0,0 +1,32 @@
+pub fn load_acceptor(
+    cert_path: &std::path::Path,
+    key_path: &std::path::Path,
+) -> anyhow::Result<tokio_rustls::TlsAcceptor> {
+    let certs = rustls_pemfile::certs(&mut std::io::BufReader::new(std::fs::File::open(
+        cert_path,
+    )?))?
+    .into_iter()
+    .map(tokio_rustls::rustls::Certificate)
+    .collect::<Vec<_>>();
+
+    let key = match rustls_pemfile::read_all(&mut std::io::BufReader::new(std::fs::File::open(
+        key_path,
+    )?))?
+    .into_iter()
+    .find_map(|item| match item {
+        rustls_pemfile::Item::RSAKey(key)
+        | rustls_pemfile::Item::PKCS8Key(key)
+        | rustls_pemfile::Item::ECKey(key) => Some(key),
+        _ => None,
+    }) {
+        Some(key) => tokio_rustls::rustls::PrivateKey(key),
+        None => anyhow::bail!("no private key found in {}", key_path.display()),
+    };
+
+    let mut config = tokio_rustls::rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(tokio_rustls::TlsAcceptor::from(std::sync::Arc::new(config)))
+}